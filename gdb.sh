@@ -1,4 +1,13 @@
 #!/bin/bash
 set -xue
 
-gdb-multiarch ./target/riscv32i-unknown-none-elf/debug/kappa -q -ex 'target remote :1234'
\ No newline at end of file
+ARCH=${ARCH:-riscv32}
+case "$ARCH" in
+  riscv32) TARGET=riscv32imac-unknown-none-elf ;;
+  # base integer ISA without atomics, limited to a single hart
+  riscv32i) TARGET=riscv32i-unknown-none-elf FEATURES=uniprocessor ;;
+  riscv64) TARGET=riscv64gc-unknown-none-elf ;;
+  *) echo "unsupported ARCH=$ARCH" >&2; exit 1 ;;
+esac
+
+gdb-multiarch ./target/$TARGET/debug/kappa -q -ex 'target remote :1234'
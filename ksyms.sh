@@ -0,0 +1,55 @@
+#!/bin/bash
+# Embeds the text symbols of kernel.map into the .ksyms section of the kernel image
+#
+# usage: ./ksyms.sh [--size] <kernel> [map]
+#
+# With --size, prints the size .ksyms needs to hold the symbol table instead, rounded up to
+# 64KiB, which is reserved by building with KSYMS_SIZE set to it.
+set -ue
+
+SIZE_ONLY=
+if [ "$1" = --size ]; then
+    SIZE_ONLY=1
+    shift
+fi
+
+KERNEL=$1
+MAP=${2:-kernel.map}
+OBJCOPY=${OBJCOPY:-llvm-objcopy}
+
+TMP=$(mktemp -d)
+trap 'rm -rf "$TMP"' EXIT
+
+# every line starts with VMA, LMA, size and alignment, followed by the output section (no
+# indentation), the input section (8 spaces) or a symbol (16 spaces)
+awk '
+match($0, /^ *[0-9a-f]+ +[0-9a-f]+ +[0-9a-f]+ +[0-9]+ /) {
+    rest = substr($0, RLENGTH + 1)
+    match(rest, /^ */)
+    name = substr(rest, RLENGTH + 1)
+
+    if (RLENGTH == 0) {
+        section = name
+    } else if (RLENGTH == 16 && section == ".text" && $3 != "0" && name !~ /^(\.L|\$)/) {
+        printf "%s %s %s\n", $1, $3, name
+    }
+}
+' "$MAP" > "$TMP/ksyms"
+printf '\0' >> "$TMP/ksyms"
+
+if [ -n "$SIZE_ONLY" ]; then
+    echo $(( ($(stat -c %s "$TMP/ksyms") + 0xffff) & ~0xffff ))
+    exit 0
+fi
+
+"$OBJCOPY" --dump-section .ksyms="$TMP/reserved" "$KERNEL"
+RESERVED=$(stat -c %s "$TMP/reserved")
+SIZE=$(stat -c %s "$TMP/ksyms")
+if [ "$SIZE" -gt "$RESERVED" ]; then
+    echo "ksyms: symbol table ($SIZE bytes) exceeds reserved .ksyms ($RESERVED bytes)," \
+        "rebuild with KSYMS_SIZE=$(( (SIZE + 0xffff) & ~0xffff ))" >&2
+    exit 1
+fi
+
+truncate -s "$RESERVED" "$TMP/ksyms"
+"$OBJCOPY" --update-section .ksyms="$TMP/ksyms" "$KERNEL"
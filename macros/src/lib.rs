@@ -1,7 +1,7 @@
-use proc_macro2::Ident;
-use quote::quote;
+use proc_macro2::{Ident, Span};
+use quote::{format_ident, quote};
 use syn::parse::{Parse, ParseStream};
-use syn::{Expr, LitInt, Token, parse_macro_input};
+use syn::{Expr, ExprArray, ItemFn, Lit, LitInt, LitStr, Token, parse_macro_input};
 
 struct RepeatInput {
     count: LitInt,
@@ -66,3 +66,105 @@ pub fn repeat(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
     quote! { #(#repeated)* }.into()
 }
+
+/// Init levels in the order of `crate::driver::InitLevel`
+const INIT_LEVELS: [&str; 6] = [
+    "EarlyConsole",
+    "IrqChip",
+    "Timer",
+    "Bus",
+    "Device",
+    "Filesystem",
+];
+
+/// Registers the annotated probe function as a driver
+///
+/// `#[driver(level = Device, compatible = ["virtio,mmio"])]` binds
+/// `fn(&FdtNode) -> Result<(), ProbeError>` to every matching fdt node, without `compatible`
+/// `fn() -> Result<(), ProbeError>` is called once. `name = "..."` overrides the function name
+/// printed when the driver binds.
+///
+/// The driver is placed in the `.drivers.<level>` section, which the linker script sorts so
+/// `crate::driver::initialize` sees the drivers ordered by level.
+#[proc_macro_attribute]
+pub fn driver(
+    args: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let mut level: Option<Ident> = None;
+    let mut name: Option<LitStr> = None;
+    let mut compatible: Option<Vec<LitStr>> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("level") {
+            level = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("compatible") {
+            let array: ExprArray = meta.value()?.parse()?;
+            let mut strings = Vec::new();
+            for elem in array.elems {
+                match elem {
+                    Expr::Lit(syn::ExprLit {
+                        lit: Lit::Str(string),
+                        ..
+                    }) => strings.push(string),
+                    elem => {
+                        return Err(syn::Error::new_spanned(
+                            elem,
+                            "expected a compatible string",
+                        ));
+                    }
+                }
+            }
+            compatible = Some(strings);
+        } else {
+            return Err(meta.error("expected `level`, `name` or `compatible`"));
+        }
+        Ok(())
+    });
+    parse_macro_input!(args with parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    let Some(level) = level else {
+        return syn::Error::new(Span::call_site(), "missing `level = <InitLevel>`")
+            .to_compile_error()
+            .into();
+    };
+    let Some(index) = INIT_LEVELS.iter().position(|name| level == name) else {
+        return syn::Error::new_spanned(&level, "unknown init level")
+            .to_compile_error()
+            .into();
+    };
+
+    let ident = &function.sig.ident;
+    let name = name.unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+    let section = format!(".drivers.{index}");
+    let index = index as u8;
+    let static_ident = format_ident!("__DRIVER_{}", ident.to_string().to_uppercase());
+    let (compatible, probe) = match compatible {
+        Some(compatible) => (
+            quote! { &[#(#compatible),*] },
+            quote! { crate::driver::Probe::Node(#ident) },
+        ),
+        None => (
+            quote! { &[] },
+            quote! { crate::driver::Probe::Once(#ident) },
+        ),
+    };
+
+    quote! {
+        #function
+
+        const _: () = assert!(crate::driver::InitLevel::#level as u8 == #index);
+
+        #[used]
+        #[unsafe(link_section = #section)]
+        static #static_ident: crate::driver::Driver = crate::driver::Driver {
+            name: #name,
+            level: crate::driver::InitLevel::#level,
+            compatible: #compatible,
+            probe: #probe,
+        };
+    }
+    .into()
+}
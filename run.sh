@@ -1,13 +1,37 @@
 #!/bin/bash
 set -xue
 
-QEMU=qemu-system-riscv32
+ARCH=${ARCH:-riscv32}
+case "$ARCH" in
+  riscv32) TARGET=riscv32imac-unknown-none-elf ;;
+  # base integer ISA without atomics, limited to a single hart
+  riscv32i) TARGET=riscv32i-unknown-none-elf FEATURES=uniprocessor ;;
+  riscv64) TARGET=riscv64gc-unknown-none-elf ;;
+  *) echo "unsupported ARCH=$ARCH" >&2; exit 1 ;;
+esac
+QEMU=qemu-system-${ARCH%i}
 
-cargo build
+KERNEL=./target/$TARGET/debug/kappa
+# size of .ksyms needed by the previous build, reserved at build time
+KSYMS_SIZE_FILE=./target/$TARGET/ksyms-size
+
+mkdir -p .disk
+if [ -f "$KSYMS_SIZE_FILE" ]; then
+  export KSYMS_SIZE=$(cat "$KSYMS_SIZE_FILE")
+fi
+cargo build --target $TARGET --features "${FEATURES:-}"
+if ! ./ksyms.sh $KERNEL; then
+  # .ksyms follows .text, so reserving more space moves none of the symbols in the table
+  ./ksyms.sh --size $KERNEL > "$KSYMS_SIZE_FILE"
+  export KSYMS_SIZE=$(cat "$KSYMS_SIZE_FILE")
+  cargo build --target $TARGET --features "${FEATURES:-}"
+  ./ksyms.sh $KERNEL
+fi
 
 $QEMU -machine virt -bios default -nographic -serial mon:stdio --no-reboot "$@" \
   -drive id=drive0,file=virtio-blk-sample,format=raw,if=none \
   -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 \
-  -drive file=fat:rw:.disk/ \
-  -kernel ./target/riscv32i-unknown-none-elf/debug/kappa
+  -drive id=drive1,file=fat:rw:.disk/,format=raw,if=none \
+  -device virtio-blk-device,drive=drive1,bus=virtio-mmio-bus.1 \
+  -kernel $KERNEL
 
@@ -5,8 +5,9 @@ use core::{
 };
 
 use crate::{
+    debug,
     memory::{PAddr, Region},
-    println,
+    sync::SpinLock,
 };
 
 #[derive(Debug)]
@@ -72,9 +73,9 @@ impl Debug for Metadata {
 }
 
 /// Minimum block size of allocation in bytes
-const MINIMUM_BLOCK: usize = 4096;
+pub const MINIMUM_BLOCK: usize = 4096;
 /// Maximum order of buddy
-const MAXIMUM_ORDER: usize = 10;
+pub const MAXIMUM_ORDER: usize = 10;
 /// Maximum block size of allocation in bytes
 const MAXIMUM_BLOCK: usize = MINIMUM_BLOCK * 2usize.pow(MAXIMUM_ORDER as u32);
 
@@ -87,6 +88,9 @@ pub struct BuddyAllocator {
     subranges: usize,
 }
 
+// the metadata pointers are owned by the allocator and only accessed through it
+unsafe impl Send for BuddyAllocator {}
+
 impl Debug for BuddyAllocator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         unsafe {
@@ -225,7 +229,7 @@ impl BuddyAllocator {
 
         *metadata = Metadata::new(true, false, pool as u8);
 
-        println!("block {block}");
+        debug!("block {block}");
         let addr = (block << MINIMUM_BLOCK.ilog2()) as usize;
         unsafe { self.region.addr.as_mut_ptr().add(addr) }
     }
@@ -261,6 +265,23 @@ impl BuddyAllocator {
         self.add_to_free_list(block, *metadata);
     }
 
+    /// returns the number of free blocks of each order
+    pub fn free_blocks(&self) -> [usize; MAXIMUM_ORDER + 1] {
+        let mut counts = [0; MAXIMUM_ORDER + 1];
+        if self.subranges == 0 {
+            return counts;
+        }
+
+        for (order, count) in counts.iter_mut().enumerate() {
+            let mut block = unsafe { (*self.free_lists)[order].head };
+            while block != u16::MAX {
+                *count += 1;
+                block = unsafe { (*self.links)[block as usize].tail };
+            }
+        }
+        counts
+    }
+
     fn add_to_free_list(&self, block: usize, metadata: Metadata) {
         let link = unsafe { &mut (*self.links)[block] };
         let free_list = unsafe { &mut (*self.free_lists)[metadata.pool() as usize] };
@@ -308,7 +329,7 @@ unsafe impl GlobalAlloc for BuddyAllocator {
         } else if size > MAXIMUM_BLOCK {
             panic!("alloc size exceeds MAXIMUM_BLOCK={MAXIMUM_BLOCK}");
         }
-        println!("allocating {size}");
+        debug!("allocating {size}");
         unsafe { self.allocate_unchecked(size) }
     }
 
@@ -319,12 +340,29 @@ unsafe impl GlobalAlloc for BuddyAllocator {
     }
 }
 
+/// Buddy allocator shared by every hart
+pub struct LockedBuddyAllocator(SpinLock<BuddyAllocator>);
+
+unsafe impl GlobalAlloc for LockedBuddyAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.0.lock().alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.0.lock().dealloc(ptr, layout) }
+    }
+}
+
 #[global_allocator]
-pub static mut ALLOCATOR: BuddyAllocator = unsafe { BuddyAllocator::null() };
+pub static ALLOCATOR: LockedBuddyAllocator =
+    LockedBuddyAllocator(SpinLock::new(unsafe { BuddyAllocator::null() }));
+
+/// returns the number of free blocks of each order in the global allocator
+pub fn free_blocks() -> [usize; MAXIMUM_ORDER + 1] {
+    ALLOCATOR.0.lock().free_blocks()
+}
 
 /// Initialize global allocator
 pub unsafe fn initialize_global(region: Region, heap: &mut [u8]) {
-    unsafe {
-        ALLOCATOR = BuddyAllocator::new(region, heap);
-    }
+    *ALLOCATOR.0.lock() = BuddyAllocator::new(region, heap);
 }
@@ -1 +1,55 @@
-pub mod rvc;
\ No newline at end of file
+use crate::memory::PAddr;
+
+pub mod rvc;
+
+#[cfg(target_arch = "riscv32")]
+mod riscv32;
+#[cfg(target_arch = "riscv32")]
+pub use riscv32::*;
+
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::*;
+
+/// Size of a general purpose register in bytes
+pub const REGBYTES: usize = size_of::<usize>();
+/// Width of a register formatted with `{:#0width$x}`
+pub const HEX_WIDTH: usize = 2 + 2 * REGBYTES;
+
+/// Page table format of an address translation mode
+///
+/// Every mode uses 4KiB pages, tables of a single page and entries of native width with the
+/// flags in the lowest 10 bits followed by the physical page number.
+pub trait PagingMode {
+    /// number of page table levels
+    const LEVELS: usize;
+    /// number of virtual page number bits indexing a table of each level
+    const VPN_BITS: usize;
+    /// number of physical page number bits in an entry
+    const PPN_BITS: usize;
+
+    /// returns `satp` translating through the root table at [root] tagged with [asid]
+    fn satp(root: PAddr, asid: usize) -> usize;
+
+    /// returns the index of [vaddr] in the table of [level] (0 is the leaf level)
+    fn vpn(vaddr: usize, level: usize) -> usize {
+        (vaddr >> (12 + level * Self::VPN_BITS)) & ((1 << Self::VPN_BITS) - 1)
+    }
+
+    /// returns the physical page number of [entry]
+    fn ppn(entry: usize) -> usize {
+        (entry >> 10) & ((1 << Self::PPN_BITS) - 1)
+    }
+}
+
+/// Removes the assembler macros defined by [asm_define_macros]
+#[macro_export]
+macro_rules! asm_purge_macros {
+    () => {
+        "
+        .purgem STORE
+        .purgem LOAD
+        "
+    };
+}
@@ -0,0 +1,36 @@
+use super::PagingMode;
+use crate::memory::{PAGE_SIZE, PAddr};
+
+/// Sv32: VPN1(10bits) + VPN0(10bits) + Offset(12bits)
+pub struct Sv32;
+
+impl PagingMode for Sv32 {
+    const LEVELS: usize = 2;
+    const VPN_BITS: usize = 10;
+    const PPN_BITS: usize = 22;
+
+    fn satp(root: PAddr, asid: usize) -> usize {
+        1 << 31 | asid << 22 | (root.addr() / PAGE_SIZE)
+    }
+}
+
+/// Paging mode used by the kernel
+pub type Paging = Sv32;
+
+/// Defines `STORE reg, slot, base=sp` and `LOAD reg, slot, base=sp` moving a register of native
+/// width to and from `slot * REGBYTES(base)`
+///
+/// Blocks using them must end with [asm_purge_macros] as naked functions share a single module.
+#[macro_export]
+macro_rules! asm_define_macros {
+    () => {
+        "
+        .macro STORE reg, slot, base=sp
+            sw \\reg, \\slot * 4(\\base)
+        .endm
+        .macro LOAD reg, slot, base=sp
+            lw \\reg, \\slot * 4(\\base)
+        .endm
+        "
+    };
+}
@@ -0,0 +1,53 @@
+use super::PagingMode;
+use crate::memory::{PAGE_SIZE, PAddr};
+
+/// Sv39: VPN2(9bits) + VPN1(9bits) + VPN0(9bits) + Offset(12bits)
+pub struct Sv39;
+
+impl PagingMode for Sv39 {
+    const LEVELS: usize = 3;
+    const VPN_BITS: usize = 9;
+    const PPN_BITS: usize = 44;
+
+    fn satp(root: PAddr, asid: usize) -> usize {
+        8 << 60 | asid << 44 | (root.addr() / PAGE_SIZE)
+    }
+}
+
+/// Sv48: VPN3(9bits) + VPN2(9bits) + VPN1(9bits) + VPN0(9bits) + Offset(12bits)
+pub struct Sv48;
+
+impl PagingMode for Sv48 {
+    const LEVELS: usize = 4;
+    const VPN_BITS: usize = 9;
+    const PPN_BITS: usize = 44;
+
+    fn satp(root: PAddr, asid: usize) -> usize {
+        9 << 60 | asid << 44 | (root.addr() / PAGE_SIZE)
+    }
+}
+
+/// Paging mode used by the kernel
+#[cfg(not(feature = "sv48"))]
+pub type Paging = Sv39;
+/// Paging mode used by the kernel
+#[cfg(feature = "sv48")]
+pub type Paging = Sv48;
+
+/// Defines `STORE reg, slot, base=sp` and `LOAD reg, slot, base=sp` moving a register of native
+/// width to and from `slot * REGBYTES(base)`
+///
+/// Blocks using them must end with [asm_purge_macros] as naked functions share a single module.
+#[macro_export]
+macro_rules! asm_define_macros {
+    () => {
+        "
+        .macro STORE reg, slot, base=sp
+            sd \\reg, \\slot * 8(\\base)
+        .endm
+        .macro LOAD reg, slot, base=sp
+            ld \\reg, \\slot * 8(\\base)
+        .endm
+        "
+    };
+}
@@ -1,43 +1,49 @@
+use crate::{asm_define_macros, asm_purge_macros};
 use core::arch::global_asm;
 
 // global_asm!(include_str!("./context_switch.S"));
-global_asm!("\
+global_asm!(
+    asm_define_macros!(),
+    "
 .global context_switch
 context_switch:
-  addi sp, sp, -13 * 4
-  sw ra,  0  * 4(sp)
-  sw s0,  1  * 4(sp)
-  sw s1,  2  * 4(sp)
-  sw s2,  3  * 4(sp)
-  sw s3,  4  * 4(sp)
-  sw s4,  5  * 4(sp)
-  sw s5,  6  * 4(sp)
-  sw s6,  7  * 4(sp)
-  sw s7,  8  * 4(sp)
-  sw s8,  9  * 4(sp)
-  sw s9,  10 * 4(sp)
-  sw s10, 11 * 4(sp)
-  sw s11, 12 * 4(sp)
+  addi sp, sp, -13 * {regbytes}
+  STORE ra,  0
+  STORE s0,  1
+  STORE s1,  2
+  STORE s2,  3
+  STORE s3,  4
+  STORE s4,  5
+  STORE s5,  6
+  STORE s6,  7
+  STORE s7,  8
+  STORE s8,  9
+  STORE s9,  10
+  STORE s10, 11
+  STORE s11, 12
 
-  sw sp, (a0)
-  lw sp, (a1)
+  STORE sp, 0, a0
+  LOAD sp, 0, a1
 
-  lw ra,  0  * 4(sp)
-  lw s0,  1  * 4(sp)
-  lw s1,  2  * 4(sp)
-  lw s2,  3  * 4(sp)
-  lw s3,  4  * 4(sp)
-  lw s4,  5  * 4(sp)
-  lw s5,  6  * 4(sp)
-  lw s6,  7  * 4(sp)
-  lw s7,  8  * 4(sp)
-  lw s8,  9  * 4(sp)
-  lw s9,  10 * 4(sp)
-  lw s10, 11 * 4(sp)
-  lw s11, 12 * 4(sp)
-  addi sp, sp, 13 * 4
+  LOAD ra,  0
+  LOAD s0,  1
+  LOAD s1,  2
+  LOAD s2,  3
+  LOAD s3,  4
+  LOAD s4,  5
+  LOAD s5,  6
+  LOAD s6,  7
+  LOAD s7,  8
+  LOAD s8,  9
+  LOAD s9,  10
+  LOAD s10, 11
+  LOAD s11, 12
+  addi sp, sp, 13 * {regbytes}
   ret
-");
+",
+    asm_purge_macros!(),
+    regbytes = const super::REGBYTES,
+);
 
 unsafe extern "C" {
     /// Switches context using given stack pointers
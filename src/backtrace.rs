@@ -0,0 +1,145 @@
+use crate::arch::HEX_WIDTH;
+use crate::{__kernel_base, __kernel_heap_end, ld_variable, memory, println};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Size reserved for the symbol table, `KSYMS_SIZE` at build time when set
+///
+/// `run.sh` rebuilds with the size `ksyms.sh --size` reports when the table does not fit.
+const KSYMS_SIZE: usize = match option_env!("KSYMS_SIZE") {
+    Some(size) => match usize::from_str_radix(size, 10) {
+        Ok(size) => size,
+        Err(_) => panic!("KSYMS_SIZE is not a decimal size"),
+    },
+    None => 256 * 1024,
+};
+/// Maximum number of frames printed
+const MAX_DEPTH: usize = 32;
+
+/// Set once a fatal trap has printed the backtrace of the trapped context
+static TRAP_PRINTED: AtomicBool = AtomicBool::new(false);
+
+/// Reserves the `.ksyms` section, which `ksyms.sh` fills with `<addr> <size> <name>\n` lines
+/// terminated by a NUL after linking
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+unsafe extern "C" {
+    static __ksyms: u8;
+    static __ksyms_end: u8;
+}
+
+/// returns the embedded symbol table, empty if `ksyms.sh` has not been run on the image
+fn ksyms() -> &'static str {
+    // read through the linker symbols as the content of [KSYMS] is unknown to the compiler
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            &__ksyms as *const u8,
+            ld_variable!(__ksyms_end, u8) - ld_variable!(__ksyms, u8),
+        )
+    };
+    let len = table.iter().position(|&b| b == 0).unwrap_or(table.len());
+    core::str::from_utf8(&table[..len]).unwrap_or_default()
+}
+
+/// returns the name of the function containing [addr] and the offset of [addr] in it
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    for line in ksyms().lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (Some(start), Some(size), Some(name)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Ok(start), Ok(size)) = (
+            usize::from_str_radix(start, 16),
+            usize::from_str_radix(size, 16),
+        ) else {
+            continue;
+        };
+
+        if (start..start + size).contains(&addr) {
+            return Some((name, addr - start));
+        }
+    }
+
+    None
+}
+
+/// prints a frame of [pc], symbolized by [lookup] which lies in the same instruction
+fn print_frame(depth: usize, pc: usize, lookup: usize) {
+    match symbolize(lookup) {
+        Some((name, offset)) => {
+            println!(
+                "  #{depth:<2} {pc:#0width$x} {name}+{offset:#x}",
+                width = HEX_WIDTH
+            );
+        }
+        None => {
+            println!("  #{depth:<2} {pc:#0width$x} <unknown>", width = HEX_WIDTH);
+        }
+    }
+}
+
+/// returns whether a frame pointer may point to [addr]
+fn is_stack_address(addr: usize) -> bool {
+    if !addr.is_multiple_of(size_of::<usize>()) {
+        return false;
+    }
+
+    let kernel = unsafe { ld_variable!(__kernel_base, u8)..ld_variable!(__kernel_heap_end, u8) };
+    kernel.contains(&addr)
+        || memory::try_get_region()
+            .is_some_and(|region| (region.addr.addr()..region.end().addr()).contains(&addr))
+}
+
+/// prints the call chain of the caller
+#[inline(never)]
+pub fn print() {
+    let fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+
+    println!("backtrace:");
+    print_from(fp, 0);
+}
+
+/// prints the call chain starting at the frame of [pc] with the frame pointer [fp]
+pub fn print_trap(pc: usize, fp: usize) {
+    TRAP_PRINTED.store(true, Ordering::Relaxed);
+    println!("backtrace:");
+    print_frame(0, pc, pc);
+    print_from(fp, 1);
+}
+
+/// returns whether a fatal trap has printed its backtrace, which the panic following it does not
+/// need to repeat
+pub fn trap_printed() -> bool {
+    TRAP_PRINTED.load(Ordering::Relaxed)
+}
+
+/// walks the frame records, where `fp - 1 * XLEN` holds `ra` and `fp - 2 * XLEN` the previous
+/// frame pointer
+fn print_from(mut fp: usize, first_depth: usize) {
+    for depth in first_depth..MAX_DEPTH {
+        if !is_stack_address(fp) || !is_stack_address(fp - 2 * size_of::<usize>()) {
+            return;
+        }
+
+        let (ra, previous) = unsafe {
+            let record = fp as *const usize;
+            (record.sub(1).read(), record.sub(2).read())
+        };
+        if ra == 0 {
+            return;
+        }
+
+        // `ra` points after the call, look up the call instruction itself
+        print_frame(depth, ra, ra - 1);
+        if previous <= fp {
+            return;
+        }
+        fp = previous;
+    }
+
+    println!("  ...");
+}
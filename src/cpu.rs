@@ -0,0 +1,275 @@
+use crate::{dtb, println};
+use fdt::standard_nodes::Cpu;
+
+/// ISA extension implemented by a hart
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// integer multiplication and division
+    M,
+    /// atomic instructions
+    A,
+    /// single precision floating-point
+    F,
+    /// double precision floating-point
+    D,
+    /// compressed instructions
+    C,
+    /// hypervisor
+    H,
+    /// vector
+    V,
+    Zicsr,
+    Zifencei,
+    /// cache block management (clean, flush, invalidate)
+    Zicbom,
+    /// cache block zero
+    Zicboz,
+    /// cache block prefetch hints
+    Zicbop,
+    Zihintpause,
+    Zba,
+    Zbb,
+    Zbs,
+    /// supervisor timer compare (`stimecmp`)
+    Sstc,
+    /// page based memory types
+    Svpbmt,
+    /// naturally aligned power-of-2 pages
+    Svnapot,
+    /// fine-grained address translation cache invalidation
+    Svinval,
+    /// counter overflow interrupts
+    Sscofpmf,
+}
+
+impl Feature {
+    const ALL: [Feature; 21] = [
+        Feature::M,
+        Feature::A,
+        Feature::F,
+        Feature::D,
+        Feature::C,
+        Feature::H,
+        Feature::V,
+        Feature::Zicsr,
+        Feature::Zifencei,
+        Feature::Zicbom,
+        Feature::Zicboz,
+        Feature::Zicbop,
+        Feature::Zihintpause,
+        Feature::Zba,
+        Feature::Zbb,
+        Feature::Zbs,
+        Feature::Sstc,
+        Feature::Svpbmt,
+        Feature::Svnapot,
+        Feature::Svinval,
+        Feature::Sscofpmf,
+    ];
+
+    /// returns the lower case name used in `riscv,isa` and `riscv,isa-extensions`
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::M => "m",
+            Feature::A => "a",
+            Feature::F => "f",
+            Feature::D => "d",
+            Feature::C => "c",
+            Feature::H => "h",
+            Feature::V => "v",
+            Feature::Zicsr => "zicsr",
+            Feature::Zifencei => "zifencei",
+            Feature::Zicbom => "zicbom",
+            Feature::Zicboz => "zicboz",
+            Feature::Zicbop => "zicbop",
+            Feature::Zihintpause => "zihintpause",
+            Feature::Zba => "zba",
+            Feature::Zbb => "zbb",
+            Feature::Zbs => "zbs",
+            Feature::Sstc => "sstc",
+            Feature::Svpbmt => "svpbmt",
+            Feature::Svnapot => "svnapot",
+            Feature::Svinval => "svinval",
+            Feature::Sscofpmf => "sscofpmf",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Feature> {
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.name().eq_ignore_ascii_case(name))
+    }
+
+    const fn bit(self) -> u64 {
+        1 << self as u64
+    }
+}
+
+/// Set of [Feature]s
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Features(u64);
+
+impl Features {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn contains(self, feature: Feature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+
+    fn insert(&mut self, feature: Feature) {
+        self.0 |= feature.bit();
+    }
+
+    fn insert_name(&mut self, name: &str) {
+        // `g` is shorthand for `imafd_zicsr_zifencei`
+        if name.eq_ignore_ascii_case("g") {
+            for feature in [
+                Feature::M,
+                Feature::A,
+                Feature::F,
+                Feature::D,
+                Feature::Zicsr,
+                Feature::Zifencei,
+            ] {
+                self.insert(feature);
+            }
+        } else if let Some(feature) = Feature::from_name(name) {
+            self.insert(feature);
+        }
+    }
+
+    fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Feature> {
+        Feature::ALL
+            .into_iter()
+            .filter(move |&feature| self.contains(feature))
+    }
+}
+
+/// Features implemented by every hart, so code paths chosen from them are valid on all of them
+static mut FEATURES: Features = Features::empty();
+
+/// detects the features of every enabled hart in `/cpus`
+///
+/// Must run after the fdt is loaded and before subsystems query [has_feature].
+pub fn initialize() {
+    let mut common = None;
+    for cpu in dtb::fdt().cpus() {
+        if cpu
+            .property("status")
+            .and_then(|status| status.as_str())
+            .is_some_and(|status| status != "okay")
+        {
+            continue;
+        }
+
+        let features = parse_cpu(&cpu);
+        common = Some(common.map_or(features, |common: Features| common.intersection(features)));
+    }
+
+    let features = common.unwrap_or_default();
+    unsafe { FEATURES = features };
+
+    println!("cpu: features:");
+    for feature in features.iter() {
+        println!("  {}", feature.name());
+    }
+}
+
+/// returns whether every hart implements [feature]
+pub fn has_feature(feature: Feature) -> bool {
+    features().contains(feature)
+}
+
+pub fn features() -> Features {
+    unsafe { FEATURES }
+}
+
+/// parses `riscv,isa-extensions`, falling back to the deprecated `riscv,isa` string
+fn parse_cpu(cpu: &Cpu) -> Features {
+    let mut features = Features::empty();
+
+    if let Some(extensions) = cpu.property("riscv,isa-extensions") {
+        for name in extensions.value.split(|&b| b == 0) {
+            if let Ok(name) = core::str::from_utf8(name) {
+                features.insert_name(name);
+            }
+        }
+        return features;
+    }
+
+    let isa = cpu
+        .property("riscv,isa")
+        .and_then(|isa| isa.as_str())
+        .unwrap_or_default();
+    parse_isa(isa, &mut features);
+    features
+}
+
+/// parses an ISA string like `rv64imafdc_zicsr_zifencei_sstc`
+///
+/// Single letter extensions follow the base and may carry versions (`rv64i2p1m2p0`),
+/// multi-letter extensions start with `z`, `s` or `x` and are separated by `_`.
+fn parse_isa(isa: &str, features: &mut Features) {
+    let Some(isa) = isa
+        .get(..4)
+        .filter(|base| base.eq_ignore_ascii_case("rv32") || base.eq_ignore_ascii_case("rv64"))
+        .and_then(|_| isa.get(4..))
+    else {
+        return;
+    };
+
+    for (index, segment) in isa.split('_').enumerate() {
+        if index > 0 || segment.starts_with(['z', 's', 'x', 'Z', 'S', 'X']) {
+            features.insert_name(strip_version(segment));
+            continue;
+        }
+
+        let mut rest = segment;
+        while let Some(letter) = rest.chars().next() {
+            if matches!(letter, 'z' | 's' | 'x' | 'Z' | 'S' | 'X') {
+                // multi-letter extension without a preceding `_`
+                features.insert_name(strip_version(rest));
+                break;
+            }
+            let (name, tail) = rest.split_at(letter.len_utf8());
+            features.insert_name(name);
+            rest = skip_version(tail);
+        }
+    }
+}
+
+/// skips a leading `<major>[p<minor>]` version
+fn skip_version(s: &str) -> &str {
+    let rest = s.trim_start_matches(|c: char| c.is_ascii_digit());
+    if rest.len() == s.len() {
+        return s;
+    }
+
+    match rest.strip_prefix(['p', 'P']) {
+        Some(minor) if minor.starts_with(|c: char| c.is_ascii_digit()) => {
+            minor.trim_start_matches(|c: char| c.is_ascii_digit())
+        }
+        _ => rest,
+    }
+}
+
+/// strips a trailing `<major>[p<minor>]` version
+fn strip_version(s: &str) -> &str {
+    let name = s.trim_end_matches(|c: char| c.is_ascii_digit());
+    if name.len() == s.len() {
+        return s;
+    }
+
+    match name.strip_suffix(['p', 'P']) {
+        Some(major) if major.ends_with(|c: char| c.is_ascii_digit()) => {
+            major.trim_end_matches(|c: char| c.is_ascii_digit())
+        }
+        _ => name,
+    }
+}
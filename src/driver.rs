@@ -0,0 +1,115 @@
+use crate::{dtb, println};
+use alloc::vec::Vec;
+use fdt::node::FdtNode;
+
+/// Order in which drivers are initialized, drivers of a level are probed after every driver of
+/// the previous levels
+///
+/// The numeric values name the linker sections (`.drivers.<level>`) and must match the ones of
+/// the `driver` macro.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum InitLevel {
+    EarlyConsole = 0,
+    IrqChip = 1,
+    Timer = 2,
+    Bus = 3,
+    Device = 4,
+    Filesystem = 5,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProbeError {
+    /// the node does not describe a device handled by the driver, e.g. an empty virtio-mmio slot
+    NoDevice,
+    /// the device is recognized but cannot be used
+    Unsupported(&'static str),
+}
+
+/// How a driver is bound
+#[derive(Copy, Clone)]
+pub enum Probe {
+    /// called for every enabled fdt node compatible with one of [Driver::compatible]
+    Node(fn(&FdtNode<'_, 'static>) -> Result<(), ProbeError>),
+    /// called once, for drivers not described by the fdt
+    Once(fn() -> Result<(), ProbeError>),
+}
+
+/// Driver registered with the `driver` attribute macro
+#[repr(C)]
+pub struct Driver {
+    pub name: &'static str,
+    pub level: InitLevel,
+    pub compatible: &'static [&'static str],
+    pub probe: Probe,
+}
+
+// only the addresses of the section bounds are used, [Driver] is not an ffi type
+unsafe extern "C" {
+    static __drivers: u8;
+    static __drivers_end: u8;
+}
+
+/// returns every registered driver, sorted by [InitLevel] by the linker
+fn drivers() -> &'static [Driver] {
+    unsafe {
+        let start = (&raw const __drivers).cast::<Driver>();
+        let end = (&raw const __drivers_end).cast::<Driver>();
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// returns the driver bound to [node], preferring the most specific `compatible` entry
+fn find_driver(node: &FdtNode) -> Option<&'static Driver> {
+    node.compatible()?.all().find_map(|compatible| {
+        drivers().iter().find(|driver| {
+            matches!(driver.probe, Probe::Node(_)) && driver.compatible.contains(&compatible)
+        })
+    })
+}
+
+/// binds drivers to the nodes of the fdt and probes them level by level
+pub fn initialize() {
+    let fdt = dtb::fdt();
+
+    // walk the tree once, probing afterwards keeps the order of nodes within a level
+    let mut bindings = Vec::new();
+    for node in fdt.all_nodes() {
+        if node
+            .property("status")
+            .and_then(|status| status.as_str())
+            .is_some_and(|status| status != "okay" && status != "ok")
+        {
+            continue;
+        }
+
+        if let Some(driver) = find_driver(&node) {
+            bindings.push((driver, Some(node)));
+        }
+    }
+    for driver in drivers() {
+        if matches!(driver.probe, Probe::Once(_)) {
+            bindings.push((driver, None));
+        }
+    }
+    bindings.sort_by_key(|(driver, _)| driver.level);
+
+    for (driver, node) in bindings {
+        let result = match (driver.probe, node) {
+            (Probe::Node(probe), Some(node)) => probe(&node),
+            (Probe::Once(probe), _) => probe(),
+            (Probe::Node(_), None) => unreachable!(),
+        };
+        let name = node.map_or("-", |node| node.name);
+
+        match result {
+            Ok(()) => {
+                println!("driver: {} bound to {name}", driver.name);
+            }
+            Err(ProbeError::NoDevice) => {}
+            Err(err) => {
+                println!("driver: {} failed to probe {name}: {err:?}", driver.name);
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use core::arch::asm;
+use crate::memory::PAddr;
 use fdt::Fdt;
 
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
 #[derive(Debug, Clone)]
 #[repr(C)]
 struct FdtHeader {
@@ -18,20 +20,35 @@ struct FdtHeader {
 
 struct FdtMemoryReservationBlock {}
 
-static mut FDT_ADDRESS: usize = 0;
 static mut FDT: Option<Fdt> = None;
 
-/// updates global fdt address variable
-pub unsafe fn update_fdt_address() {
-    unsafe { asm!("addi t3, a1, 0", out("t3") FDT_ADDRESS) };
-}
-
-/// prints some debug information about global fdt
+/// validates the fdt at [address] and loads it as global fdt
+///
+/// panics with a description of the problem if no valid fdt is found
 #[allow(static_mut_refs)]
-pub fn load_fdt() {
-    unsafe { FDT = Fdt::from_ptr(FDT_ADDRESS as *const u8).ok() };
+pub fn load_fdt(address: PAddr) {
+    if address.addr() == 0 {
+        panic!("no device tree was passed by the firmware (a1 = 0)");
+    }
+
+    let header = unsafe { (address.addr() as *const FdtHeader).read_unaligned() };
+    let magic = u32::from_be(header.magic);
+    if magic != FDT_MAGIC {
+        panic!(
+            "no device tree at {address:#x} (magic {magic:#010x}, expected {FDT_MAGIC:#010x})"
+        );
+    }
+
+    let fdt = unsafe { Fdt::from_ptr(address.addr() as *const u8) }
+        .unwrap_or_else(|err| panic!("invalid device tree at {address:#x}: {err:?}"));
+    unsafe { FDT = Some(fdt) };
 }
 
 pub fn fdt() -> Fdt<'static> {
     unsafe { FDT.unwrap() }
 }
+
+/// returns global fdt or none if it is not loaded yet
+pub fn try_fdt() -> Option<Fdt<'static>> {
+    unsafe { FDT }
+}
@@ -0,0 +1,123 @@
+use crate::arch::REGBYTES;
+use crate::filesystem::vfs::{self, FsError, OpenFile, SeekFrom};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+/// `EI_CLASS` of the native word size
+const CLASS: u8 = if REGBYTES == 8 { 2 } else { 1 };
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+/// Loadable segment of an executable
+pub struct Segment {
+    pub vaddr: usize,
+    /// size in memory, the part past [file_size] is zero
+    pub mem_size: usize,
+    pub offset: u64,
+    pub file_size: usize,
+    /// `PF_*` permissions
+    pub flags: u32,
+}
+
+/// Statically linked executable for the running target
+pub struct Executable {
+    pub entry: usize,
+    pub segments: Vec<Segment>,
+}
+
+/// reads exactly `buf.len()` bytes at [offset] of [file]
+pub fn read_exact(file: &OpenFile, offset: u64, buf: &mut [u8]) -> vfs::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read(&mut buf[done..])? {
+            // the file is shorter than its headers claim
+            0 => return Err(FsError::InvalidArgument),
+            read => done += read,
+        }
+    }
+    Ok(())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// reads a field of the native word size
+fn read_word(bytes: &[u8], offset: usize) -> usize {
+    usize::from_le_bytes(bytes[offset..offset + REGBYTES].try_into().unwrap())
+}
+
+/// parses the header and the loadable segments of the executable [file], failing with
+/// [FsError::InvalidArgument] if it is not an executable of the running target
+pub fn parse(file: &OpenFile) -> vfs::Result<Executable> {
+    // the fields after `e_entry` are laid out in words of the class
+    let header_size = 16 + 8 + 3 * REGBYTES + 12;
+    let mut header = vec![0; header_size];
+    read_exact(file, 0, &mut header)?;
+    if &header[..4] != ELF_MAGIC
+        || header[4] != CLASS
+        || header[5] != DATA_LITTLE_ENDIAN
+        || read_u16(&header, 16) != ET_EXEC
+        || read_u16(&header, 18) != EM_RISCV
+    {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let entry = read_word(&header, 24);
+    let program_headers = read_word(&header, 24 + REGBYTES) as u64;
+    let flags = 24 + 3 * REGBYTES;
+    let entry_size = read_u16(&header, flags + 6) as usize;
+    let count = read_u16(&header, flags + 8) as usize;
+    let program_header_size = 8 + 6 * REGBYTES;
+    if entry_size < program_header_size {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let mut table = vec![0; entry_size * count];
+    read_exact(file, program_headers, &mut table)?;
+    let segments = table
+        .chunks_exact(entry_size)
+        .filter(|header| read_u32(header, 0) == PT_LOAD)
+        .map(|header| {
+            // `p_flags` follows `p_type` on 64-bit and `p_memsz` on 32-bit
+            let word = |index| read_word(header, 8 + index * REGBYTES);
+            if REGBYTES == 8 {
+                Segment {
+                    offset: word(0) as u64,
+                    vaddr: word(1),
+                    file_size: word(3),
+                    mem_size: word(4),
+                    flags: read_u32(header, 4),
+                }
+            } else {
+                Segment {
+                    offset: read_word(header, 4) as u64,
+                    vaddr: read_word(header, 8),
+                    file_size: read_word(header, 16),
+                    mem_size: read_word(header, 20),
+                    flags: read_u32(header, 24),
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    if segments
+        .iter()
+        .any(|segment| segment.file_size > segment.mem_size)
+    {
+        return Err(FsError::InvalidArgument);
+    }
+
+    Ok(Executable { entry, segments })
+}
@@ -1,6 +1,14 @@
+use crate::arch::HEX_WIDTH;
+use crate::smp::MAX_HARTS;
+use crate::{asm_define_macros, asm_purge_macros, println};
 use core::arch::naked_asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::interrupt::Trap;
+use riscv::interrupt::supervisor::{Exception, Interrupt};
+use riscv::register::stvec::Stvec;
 
-#[repr(packed)]
+#[repr(C)]
+#[derive(Default)]
 pub struct TrapFrame {
     pub ra: usize,
     pub gp: usize,
@@ -33,99 +41,309 @@ pub struct TrapFrame {
     pub s10: usize,
     pub s11: usize,
     pub sp: usize,
+    pub sepc: usize,
+    pub sstatus: usize,
 }
 
+/// Size reserved on the stack for a [TrapFrame], keeping `sp` 16-byte aligned
+pub const FRAME_SIZE: usize = size_of::<TrapFrame>().next_multiple_of(16);
+
+/// Previous privilege bit of `sstatus`, set if the trap was taken from S-mode
+const SSTATUS_SPP: usize = 1 << 8;
+const SSTATUS_SIE: usize = 1 << 1;
+/// Previous interrupt enable bit of `sstatus`, loaded into `SIE` by `sret`
+const SSTATUS_SPIE: usize = 1 << 5;
+/// Floating-point state field of `sstatus`, off while zero
+const SSTATUS_FS: usize = 0b11 << 13;
+
+impl TrapFrame {
+    const REGISTER_NAMES: [&str; 33] = [
+        "ra", "gp", "tp", "t0", "t1", "t2", "t3", "t4", "t5", "t6", "a0", "a1", "a2", "a3", "a4",
+        "a5", "a6", "a7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+        "sp", "sepc", "sstatus",
+    ];
+
+    /// returns a frame entering U-mode at [entry] with the stack pointer [sp], interrupts
+    /// enabled and the floating-point unit off until its first use
+    pub fn user(entry: usize, sp: usize) -> Self {
+        let sstatus = riscv::register::sstatus::read().bits();
+        Self {
+            sp,
+            sepc: entry,
+            sstatus: (sstatus & !(SSTATUS_SPP | SSTATUS_SIE | SSTATUS_FS)) | SSTATUS_SPIE,
+            ..Self::default()
+        }
+    }
+
+    /// returns whether the trap was taken from U-mode
+    pub fn is_from_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
+
+    /// prints every saved register
+    pub fn dump(&self) {
+        let registers = unsafe { (self as *const TrapFrame as *const [usize; 33]).read() };
+        for (i, (name, value)) in Self::REGISTER_NAMES.iter().zip(registers).enumerate() {
+            crate::print!("{name:>7}={value:#0width$x}", width = HEX_WIDTH);
+            if i % 4 == 3 {
+                println!();
+            } else {
+                crate::print!(" ");
+            }
+        }
+        println!();
+    }
+}
+
+/// sets up trap handling on the calling hart
+///
+/// `sscratch` holds the [Hart](crate::smp::Hart) while running in U-mode and zero while running
+/// in S-mode, which tells `exception_entrypoint` whether it has to switch to a kernel stack.
+pub fn initialize_hart() {
+    unsafe {
+        riscv::register::sscratch::write(0);
+        riscv::register::stvec::write(Stvec::from_bits(exception_entrypoint as *const () as usize));
+    }
+}
+
+/// Entrypoint of every trap
+///
+/// Traps from U-mode are saved on the kernel stack of the running process
+/// ([Hart::kernel_sp](crate::smp::Hart)), traps from S-mode on the current stack, so traps nest
+/// as long as the kernel stack does not overflow.
 #[unsafe(no_mangle)]
 #[unsafe(naked)]
 pub fn exception_entrypoint() {
     naked_asm!(
+        asm_define_macros!(),
         r#"
     .align 4
 
-    csrrw sp, sscratch, sp
-
-    addi sp, sp, -4 * 31
-    sw ra,  4 * 0(sp)
-    sw gp,  4 * 1(sp)
-    sw tp,  4 * 2(sp)
-    sw t0,  4 * 3(sp)
-    sw t1,  4 * 4(sp)
-    sw t2,  4 * 5(sp)
-    sw t3,  4 * 6(sp)
-    sw t4,  4 * 7(sp)
-    sw t5,  4 * 8(sp)
-    sw t6,  4 * 9(sp)
-    sw a0,  4 * 10(sp)
-    sw a1,  4 * 11(sp)
-    sw a2,  4 * 12(sp)
-    sw a3,  4 * 13(sp)
-    sw a4,  4 * 14(sp)
-    sw a5,  4 * 15(sp)
-    sw a6,  4 * 16(sp)
-    sw a7,  4 * 17(sp)
-    sw s0,  4 * 18(sp)
-    sw s1,  4 * 19(sp)
-    sw s2,  4 * 20(sp)
-    sw s3,  4 * 21(sp)
-    sw s4,  4 * 22(sp)
-    sw s5,  4 * 23(sp)
-    sw s6,  4 * 24(sp)
-    sw s7,  4 * 25(sp)
-    sw s8,  4 * 26(sp)
-    sw s9,  4 * 27(sp)
-    sw s10, 4 * 28(sp)
-    sw s11, 4 * 29(sp)
-
-    csrr a0, sscratch
-    sw a0, 4 * 30(sp)
-
-    addi a0, sp, 4 * 31
-    csrw sscratch, a0
+    csrrw tp, sscratch, tp
+    bnez tp, 1f
+
+    // trapped from S-mode: restore tp and stay on the current stack
+    csrrw tp, sscratch, tp
+    addi sp, sp, -{frame_size}
+    STORE t0,  3
+    addi t0, sp, {frame_size}
+    STORE t0,  30
+    STORE tp,  2
+    j 2f
+
+1:
+    // trapped from U-mode: tp holds the hart, sscratch the user tp
+    STORE sp, {scratch_sp}, tp
+    LOAD sp, {kernel_sp}, tp
+    addi sp, sp, -{frame_size}
+    STORE t0,  3
+    LOAD t0, {scratch_sp}, tp
+    STORE t0,  30
+    // mark the hart as running in S-mode for nested traps
+    csrrw t0, sscratch, zero
+    STORE t0,  2
+
+2:
+    STORE ra,  0
+    STORE gp,  1
+    STORE t1,  4
+    STORE t2,  5
+    STORE t3,  6
+    STORE t4,  7
+    STORE t5,  8
+    STORE t6,  9
+    STORE a0,  10
+    STORE a1,  11
+    STORE a2,  12
+    STORE a3,  13
+    STORE a4,  14
+    STORE a5,  15
+    STORE a6,  16
+    STORE a7,  17
+    STORE s0,  18
+    STORE s1,  19
+    STORE s2,  20
+    STORE s3,  21
+    STORE s4,  22
+    STORE s5,  23
+    STORE s6,  24
+    STORE s7,  25
+    STORE s8,  26
+    STORE s9,  27
+    STORE s10, 28
+    STORE s11, 29
+
+    csrr t0, sepc
+    STORE t0,  31
+    csrr t0, sstatus
+    STORE t0,  32
 
     mv a0, sp
     call handle_trap
+    j {trap_return}
+    "#,
+        asm_purge_macros!(),
+        frame_size = const FRAME_SIZE,
+        kernel_sp = const crate::smp::KERNEL_SP_SLOT,
+        scratch_sp = const crate::smp::SCRATCH_SP_SLOT,
+        trap_return = sym trap_return,
+    )
+}
 
-    lw ra,  4 * 0(sp)
-    lw gp,  4 * 1(sp)
-    lw tp,  4 * 2(sp)
-    lw t0,  4 * 3(sp)
-    lw t1,  4 * 4(sp)
-    lw t2,  4 * 5(sp)
-    lw t3,  4 * 6(sp)
-    lw t4,  4 * 7(sp)
-    lw t5,  4 * 8(sp)
-    lw t6,  4 * 9(sp)
-    lw a0,  4 * 10(sp)
-    lw a1,  4 * 11(sp)
-    lw a2,  4 * 12(sp)
-    lw a3,  4 * 13(sp)
-    lw a4,  4 * 14(sp)
-    lw a5,  4 * 15(sp)
-    lw a6,  4 * 16(sp)
-    lw a7,  4 * 17(sp)
-    lw s0,  4 * 18(sp)
-    lw s1,  4 * 19(sp)
-    lw s2,  4 * 20(sp)
-    lw s3,  4 * 21(sp)
-    lw s4,  4 * 22(sp)
-    lw s5,  4 * 23(sp)
-    lw s6,  4 * 24(sp)
-    lw s7,  4 * 25(sp)
-    lw s8,  4 * 26(sp)
-    lw s9,  4 * 27(sp)
-    lw s10, 4 * 28(sp)
-    lw s11, 4 * 29(sp)
-    lw sp,  4 * 30(sp)
+/// Returns from the trap whose [TrapFrame] is at `sp`
+///
+/// Processes also enter U-mode for the first time through it, with a frame built by
+/// [TrapFrame::user].
+#[unsafe(naked)]
+pub unsafe extern "C" fn trap_return() -> ! {
+    naked_asm!(
+        asm_define_macros!(),
+        r#"
+    // the saved sstatus has SIE cleared, so nothing can trap until sret
+    LOAD t0,  32
+    csrw sstatus, t0
+    LOAD t1,  31
+    csrw sepc, t1
+
+    andi t0, t0, {sstatus_spp}
+    bnez t0, 3f
+    // returning to U-mode: the next trap starts at the top of this kernel stack
+    addi t0, sp, {frame_size}
+    STORE t0, {kernel_sp}, tp
+    csrw sscratch, tp
+
+3:
+    LOAD ra,  0
+    LOAD gp,  1
+    LOAD tp,  2
+    LOAD t0,  3
+    LOAD t1,  4
+    LOAD t2,  5
+    LOAD t3,  6
+    LOAD t4,  7
+    LOAD t5,  8
+    LOAD t6,  9
+    LOAD a0,  10
+    LOAD a1,  11
+    LOAD a2,  12
+    LOAD a3,  13
+    LOAD a4,  14
+    LOAD a5,  15
+    LOAD a6,  16
+    LOAD a7,  17
+    LOAD s0,  18
+    LOAD s1,  19
+    LOAD s2,  20
+    LOAD s3,  21
+    LOAD s4,  22
+    LOAD s5,  23
+    LOAD s6,  24
+    LOAD s7,  25
+    LOAD s8,  26
+    LOAD s9,  27
+    LOAD s10, 28
+    LOAD s11, 29
+    LOAD sp,  30
     sret
-    "#
+    "#,
+        asm_purge_macros!(),
+        frame_size = const FRAME_SIZE,
+        sstatus_spp = const SSTATUS_SPP,
+        kernel_sp = const crate::smp::KERNEL_SP_SLOT,
     )
 }
 
+/// Interrupt causes counted by [INTERRUPT_COUNTS], the standard ones of `scause`
+pub const INTERRUPT_CAUSES: usize = 16;
+
+/// Interrupts taken indexed by `[cause][hart index]`
+///
+/// Each hart only updates its own counters with interrupts disabled, so no read-modify-write
+/// atomics are required.
+static INTERRUPT_COUNTS: [[AtomicUsize; MAX_HARTS]; INTERRUPT_CAUSES] =
+    [COUNT_ROW_INIT; INTERRUPT_CAUSES];
+
+/// workaround for [https://github.com/rust-lang/rust/issues/44796]
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNT_INIT: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNT_ROW_INIT: [AtomicUsize; MAX_HARTS] = [COUNT_INIT; MAX_HARTS];
+
+/// returns the number of interrupts of [cause] taken by the hart of logical index [hart]
+pub fn interrupt_count(cause: usize, hart: usize) -> usize {
+    INTERRUPT_COUNTS[cause][hart].load(Ordering::Relaxed)
+}
+
 #[unsafe(no_mangle)]
-fn handle_trap(frame: TrapFrame) {
+fn handle_trap(frame: &mut TrapFrame) {
+    if frame.is_from_user()
+        && let Some(proc) = crate::proc::current()
+    {
+        frame.sstatus = crate::fpu::trap_entry(&mut proc.fp, frame.sstatus);
+    }
+
+    dispatch(frame);
+
+    if frame.is_from_user() {
+        // the work deferred by interrupts runs before returning to the process
+        unsafe { riscv::register::sstatus::set_sie() };
+        crate::timer::run_deferred();
+        unsafe { riscv::register::sstatus::clear_sie() };
+    }
+
+    // the process returned to may not be the one that trapped
+    if frame.is_from_user()
+        && let Some(proc) = crate::proc::current()
+    {
+        frame.sstatus = crate::fpu::trap_return(&proc.fp, frame.sstatus);
+    }
+}
+
+fn dispatch(frame: &mut TrapFrame) {
     let scause = riscv::register::scause::read();
     let stval = riscv::register::stval::read();
-    let sepc = riscv::register::sepc::read();
+    let sepc = frame.sepc;
+
+    if scause.is_interrupt() && scause.code() < INTERRUPT_CAUSES {
+        let count = &INTERRUPT_COUNTS[scause.code()][crate::smp::current().index];
+        count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    match scause.cause().try_into::<Interrupt, Exception>() {
+        Ok(Trap::Exception(Exception::UserEnvCall)) => {
+            crate::syscall::handle(frame);
+            return;
+        }
+        Ok(Trap::Interrupt(Interrupt::SupervisorSoft)) => {
+            crate::ipi::handle();
+            return;
+        }
+        Ok(Trap::Interrupt(Interrupt::SupervisorTimer)) => {
+            crate::timer::handle();
+            return;
+        }
+        _ => {}
+    }
+
+    if let Ok(Trap::Exception(Exception::IllegalInstruction)) =
+        scause.cause().try_into::<Interrupt, Exception>()
+        && frame.is_from_user()
+        && let Some(proc) = crate::proc::current()
+        && let Some(sstatus) =
+            crate::fpu::handle_illegal_instruction(&mut proc.fp, frame.sstatus, stval)
+    {
+        frame.sstatus = sstatus;
+        return;
+    }
+
+    frame.dump();
+    println!(
+        "scause={:#0width$x} stval={stval:#0width$x} satp={:#0width$x}",
+        scause.bits(),
+        riscv::register::satp::read().bits(),
+        width = HEX_WIDTH,
+    );
+    crate::backtrace::print_trap(sepc, frame.s0);
 
     panic!(
         "unexpected trap (scause={scause:?}, stval={stval}, sepc={sepc:#x})",
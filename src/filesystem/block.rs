@@ -0,0 +1,235 @@
+use crate::filesystem::cache::CachedDevice;
+use crate::filesystem::partition;
+use crate::filesystem::vfs::{self, FsError};
+use crate::println;
+use crate::sync::{Arc, SpinLock};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Device addressed in fixed-size blocks, e.g. a disk
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// returns the size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// reads the blocks starting at [block] into [buf], whose length is a multiple of the block size
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> vfs::Result<()>;
+
+    /// writes [buf], whose length is a multiple of the block size, to the blocks starting at [block]
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> vfs::Result<()>;
+
+    /// waits until completed writes reach stable storage
+    fn flush(&self) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    /// returns whether writes are rejected with [FsError::ReadOnly]
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// tells the device the [count] blocks from [block] are unused, leaving their content
+    /// undefined
+    fn discard(&self, _block: u64, _count: u64) -> vfs::Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    /// fills the [count] blocks from [block] with zeros, by writing them unless the device can
+    /// do it by itself
+    fn write_zeroes(&self, block: u64, count: u64) -> vfs::Result<()> {
+        const CHUNK_BLOCKS: u64 = 32;
+        let zeros = vec![0; CHUNK_BLOCKS as usize * self.block_size()];
+        let mut done = 0;
+        while done < count {
+            let blocks = (count - done).min(CHUNK_BLOCKS);
+            self.write_blocks(block + done, &zeros[..blocks as usize * self.block_size()])?;
+            done += blocks;
+        }
+        Ok(())
+    }
+}
+
+/// Block devices in the order they were probed
+static DEVICES: SpinLock<Vec<Arc<dyn BlockDevice>>> = SpinLock::new(Vec::new());
+
+/// registers the disk [device] behind the buffer cache, followed by its partitions
+pub fn register(device: Arc<dyn BlockDevice>) {
+    let device: Arc<dyn BlockDevice> = Arc::new(CachedDevice::new(device));
+    add(device.clone());
+    match partition::scan(&device) {
+        Ok(partitions) => {
+            for partition in partitions {
+                add(Arc::new(partition));
+            }
+        }
+        Err(err) => {
+            println!(
+                "block: invalid partition table on {} ({err:?})",
+                device.name()
+            );
+        }
+    }
+}
+
+fn add(device: Arc<dyn BlockDevice>) {
+    println!(
+        "block: {} with {} blocks of {} bytes",
+        device.name(),
+        device.block_count(),
+        device.block_size()
+    );
+    DEVICES.lock().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// returns the device named [name], with or without a `/dev/` prefix
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// returns the size of [device] in bytes
+pub fn size(device: &dyn BlockDevice) -> u64 {
+    device.block_count() * device.block_size() as u64
+}
+
+/// reads [buf] from the byte [offset] of [device], which need not be block aligned
+pub fn read_at(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> vfs::Result<()> {
+    check_range(device, offset, buf.len())?;
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let block_size = device.block_size();
+    let mut block = offset / block_size as u64;
+    let mut done = 0;
+
+    // partial first block
+    let skip = (offset % block_size as u64) as usize;
+    if skip != 0 || buf.len() < block_size {
+        let mut bounce = vec![0; block_size];
+        device.read_blocks(block, &mut bounce)?;
+        let len = buf.len().min(block_size - skip);
+        buf[..len].copy_from_slice(&bounce[skip..skip + len]);
+        done = len;
+        block += 1;
+    }
+
+    let whole = (buf.len() - done) / block_size * block_size;
+    if whole != 0 {
+        device.read_blocks(block, &mut buf[done..done + whole])?;
+        done += whole;
+        block += (whole / block_size) as u64;
+    }
+
+    // partial last block
+    if done < buf.len() {
+        let mut bounce = vec![0; block_size];
+        device.read_blocks(block, &mut bounce)?;
+        let len = buf.len() - done;
+        buf[done..].copy_from_slice(&bounce[..len]);
+    }
+    Ok(())
+}
+
+/// writes [buf] to the byte [offset] of [device], reading back partially written blocks
+pub fn write_at(device: &dyn BlockDevice, offset: u64, buf: &[u8]) -> vfs::Result<()> {
+    check_range(device, offset, buf.len())?;
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let block_size = device.block_size();
+    let mut block = offset / block_size as u64;
+    let mut done = 0;
+
+    let skip = (offset % block_size as u64) as usize;
+    if skip != 0 || buf.len() < block_size {
+        let mut bounce = vec![0; block_size];
+        device.read_blocks(block, &mut bounce)?;
+        let len = buf.len().min(block_size - skip);
+        bounce[skip..skip + len].copy_from_slice(&buf[..len]);
+        device.write_blocks(block, &bounce)?;
+        done = len;
+        block += 1;
+    }
+
+    let whole = (buf.len() - done) / block_size * block_size;
+    if whole != 0 {
+        device.write_blocks(block, &buf[done..done + whole])?;
+        done += whole;
+        block += (whole / block_size) as u64;
+    }
+
+    if done < buf.len() {
+        let mut bounce = vec![0; block_size];
+        device.read_blocks(block, &mut bounce)?;
+        let len = buf.len() - done;
+        bounce[..len].copy_from_slice(&buf[done..]);
+        device.write_blocks(block, &bounce)?;
+    }
+    Ok(())
+}
+
+/// fills the bytes `offset..offset + len` of [device] with zeros, the whole blocks through
+/// [BlockDevice::write_zeroes]
+pub fn zero_at(device: &dyn BlockDevice, offset: u64, len: u64) -> vfs::Result<()> {
+    let blocks = whole_blocks(device, offset, len)?;
+    let block_size = device.block_size() as u64;
+    if blocks.is_empty() {
+        return write_at(device, offset, &vec![0; len as usize]);
+    }
+
+    let end = offset + len;
+    write_at(
+        device,
+        offset,
+        &vec![0; (blocks.start * block_size - offset) as usize],
+    )?;
+    device.write_zeroes(blocks.start, blocks.end - blocks.start)?;
+    write_at(
+        device,
+        blocks.end * block_size,
+        &vec![0; (end - blocks.end * block_size) as usize],
+    )
+}
+
+/// tells [device] the whole blocks of the bytes `offset..offset + len` are unused, doing nothing
+/// if it does not support discarding them
+pub fn discard_at(device: &dyn BlockDevice, offset: u64, len: u64) -> vfs::Result<()> {
+    let blocks = whole_blocks(device, offset, len)?;
+    if blocks.is_empty() {
+        return Ok(());
+    }
+    match device.discard(blocks.start, blocks.end - blocks.start) {
+        Err(FsError::Unsupported) => Ok(()),
+        result => result,
+    }
+}
+
+/// returns the blocks of [device] lying entirely inside the bytes `offset..offset + len`
+fn whole_blocks(device: &dyn BlockDevice, offset: u64, len: u64) -> vfs::Result<Range<u64>> {
+    let end = offset
+        .checked_add(len)
+        .filter(|&end| end <= size(device))
+        .ok_or(FsError::InvalidArgument)?;
+    let block_size = device.block_size() as u64;
+    let start = offset.div_ceil(block_size);
+    Ok(start..(end / block_size).max(start))
+}
+
+fn check_range(device: &dyn BlockDevice, offset: u64, len: usize) -> vfs::Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size(device) => Ok(()),
+        _ => Err(FsError::InvalidArgument),
+    }
+}
@@ -0,0 +1,378 @@
+use crate::filesystem::block::BlockDevice;
+use crate::filesystem::vfs::{self, FsError};
+use crate::sync::{Arc, SpinLock};
+use crate::{println, timer};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+crate::kernel_param!(
+    /// number of blocks kept by the buffer cache (`cache.blocks=`)
+    pub static CAPACITY: usize = "cache.blocks", 256
+);
+crate::kernel_param!(
+    /// blocks read past the end of a sequential read (`cache.readahead=`)
+    pub static READAHEAD: usize = "cache.readahead", 16
+);
+crate::kernel_param!(
+    /// milliseconds a dirty block may stay in the cache before being written back
+    /// (`cache.writeback_ms=`)
+    pub static WRITEBACK_MS: usize = "cache.writeback_ms", 5000
+);
+
+/// Block of a device held in memory
+struct Buffer {
+    device: Arc<dyn BlockDevice>,
+    block: u64,
+    state: SpinLock<BufferState>,
+}
+
+struct BufferState {
+    data: Vec<u8>,
+    /// whether [data] holds the content of the block, false until it is read or overwritten
+    valid: bool,
+    /// time counter value at which the buffer became dirty, none if it is clean
+    dirty_since: Option<u64>,
+}
+
+impl Buffer {
+    /// writes the buffer to the device if it is dirty
+    fn write_back(&self) -> vfs::Result<()> {
+        let mut state = self.state.lock();
+        if state.dirty_since.is_some() {
+            self.device.write_blocks(self.block, &state.data)?;
+            state.dirty_since = None;
+        }
+        Ok(())
+    }
+}
+
+/// Device and block of a buffer, devices are identified by the address of their object
+type Key = (usize, u64);
+
+fn device_id(device: &Arc<dyn BlockDevice>) -> usize {
+    &**device as *const dyn BlockDevice as *const () as usize
+}
+
+struct Entry {
+    buffer: Arc<Buffer>,
+    /// position in [Cache::lru]
+    stamp: u64,
+}
+
+struct Cache {
+    entries: BTreeMap<Key, Entry>,
+    /// keys by last use, the least recently used first
+    lru: BTreeMap<u64, Key>,
+    next_stamp: u64,
+    /// buffers evicted while they are written back, taken back by a lookup in the meantime so
+    /// the block is not read from the device before the write completes
+    evicting: BTreeMap<Key, Arc<Buffer>>,
+}
+
+/// Buffers of every device, shared so the capacity bounds the memory of all of them
+static CACHE: SpinLock<Cache> = SpinLock::new(Cache {
+    entries: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    next_stamp: 0,
+    evicting: BTreeMap::new(),
+});
+
+impl Cache {
+    /// returns the buffer of [block], inserting one that is not valid yet if it is not cached
+    ///
+    /// The cache may exceed its capacity until [Cache::evict] is called.
+    fn lookup(&mut self, device: &Arc<dyn BlockDevice>, block: u64) -> Arc<Buffer> {
+        let key = (device_id(device), block);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            let stamp = self.next_stamp;
+            self.next_stamp += 1;
+            self.lru.remove(&entry.stamp);
+            entry.stamp = stamp;
+            self.lru.insert(stamp, key);
+            return entry.buffer.clone();
+        }
+
+        let buffer = self.evicting.remove(&key).unwrap_or_else(|| {
+            Arc::new(Buffer {
+                device: device.clone(),
+                block,
+                state: SpinLock::new(BufferState {
+                    data: vec![0; device.block_size()],
+                    valid: false,
+                    dirty_since: None,
+                }),
+            })
+        });
+        self.insert(key, buffer.clone());
+        buffer
+    }
+
+    fn insert(&mut self, key: Key, buffer: Arc<Buffer>) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.entries.insert(key, Entry { buffer, stamp });
+        self.lru.insert(stamp, key);
+    }
+
+    /// moves the least recently used buffer that is not referenced outside of the cache to
+    /// [Cache::evicting] if the cache is over its capacity, returning it to be written back
+    fn evict(&mut self) -> Option<(Key, Arc<Buffer>)> {
+        if self.entries.len() <= CAPACITY.get() {
+            return None;
+        }
+        let (stamp, key) = self
+            .lru
+            .iter()
+            .map(|(&stamp, &key)| (stamp, key))
+            .find(|(_, key)| Arc::strong_count(&self.entries[key].buffer) == 1)?;
+
+        self.lru.remove(&stamp);
+        let buffer = self.entries.remove(&key)?.buffer;
+        self.evicting.insert(key, buffer.clone());
+        Some((key, buffer))
+    }
+
+    fn contains(&self, device: &Arc<dyn BlockDevice>, block: u64) -> bool {
+        self.entries.contains_key(&(device_id(device), block))
+    }
+
+    /// returns the cached buffers of the [count] blocks of [device] from [block]
+    fn range(&self, device: &Arc<dyn BlockDevice>, block: u64, count: u64) -> Vec<Arc<Buffer>> {
+        let id = device_id(device);
+        self.entries
+            .range((id, block)..(id, block + count))
+            .map(|(_, entry)| entry.buffer.clone())
+            .chain(
+                self.evicting
+                    .range((id, block)..(id, block + count))
+                    .map(|(_, buffer)| buffer.clone()),
+            )
+            .collect()
+    }
+}
+
+/// returns the buffer of [block] of [device], writing back the buffer it evicts outside of the
+/// cache lock
+fn lookup(device: &Arc<dyn BlockDevice>, block: u64) -> Arc<Buffer> {
+    let (buffer, victim) = {
+        let mut cache = CACHE.lock();
+        let buffer = cache.lookup(device, block);
+        (buffer, cache.evict())
+    };
+    let Some((key, victim)) = victim else {
+        return buffer;
+    };
+
+    let result = victim.write_back();
+    let mut cache = CACHE.lock();
+    // a lookup may have taken it back while it was written
+    if cache
+        .evicting
+        .get(&key)
+        .is_some_and(|evicting| Arc::ptr_eq(evicting, &victim))
+    {
+        cache.evicting.remove(&key);
+        // the cache grows past its capacity rather than losing the data
+        if let Err(err) = result {
+            println!(
+                "cache: failed to write back block {} of {} ({err:?})",
+                victim.block,
+                victim.device.name()
+            );
+            cache.insert(key, victim);
+        }
+    }
+    buffer
+}
+
+/// writes back the dirty buffers of the device [id], or of every device if none
+fn write_back(id: Option<usize>) -> vfs::Result<()> {
+    let buffers: Vec<_> = {
+        let cache = CACHE.lock();
+        cache
+            .entries
+            .iter()
+            .map(|(key, entry)| (key, &entry.buffer))
+            .chain(cache.evicting.iter())
+            .filter(|((device, _), _)| id.is_none_or(|id| id == *device))
+            .map(|(_, buffer)| buffer.clone())
+            .collect()
+    };
+    buffers.iter().try_for_each(|buffer| buffer.write_back())
+}
+
+/// writes back every dirty buffer
+pub fn sync() -> vfs::Result<()> {
+    write_back(None)
+}
+
+/// runs [write_back_expired] periodically, outside of the timer interrupt as it waits for the
+/// devices
+pub fn initialize() {
+    timer::deferred(WRITEBACK_MS.get(), write_back_expired);
+}
+
+/// writes back the buffers dirty for longer than [WRITEBACK_MS]
+fn write_back_expired() {
+    let now = timer::now();
+    let interval = timer::ms_to_ticks(WRITEBACK_MS.get());
+    let buffers: Vec<_> = CACHE
+        .lock()
+        .entries
+        .values()
+        .map(|entry| entry.buffer.clone())
+        .collect();
+
+    for buffer in buffers {
+        let dirty_since = buffer.state.lock().dirty_since;
+        if dirty_since.is_some_and(|since| now.wrapping_sub(since) >= interval)
+            && let Err(err) = buffer.write_back()
+        {
+            println!(
+                "cache: failed to write back block {} of {} ({err:?})",
+                buffer.block,
+                buffer.device.name()
+            );
+        }
+    }
+}
+
+/// Block device accessed through the buffer cache
+///
+/// Writes only reach the device when their buffers are evicted, expire or are flushed.
+pub struct CachedDevice {
+    device: Arc<dyn BlockDevice>,
+    /// block following the last read, a read starting there is sequential
+    next_read: SpinLock<u64>,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            device,
+            next_read: SpinLock::new(u64::MAX),
+        }
+    }
+
+    fn check_range(&self, block: u64, count: u64) -> vfs::Result<()> {
+        match block.checked_add(count) {
+            Some(end) if end <= self.device.block_count() => Ok(()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> vfs::Result<()> {
+        let block_size = self.block_size();
+        let count = buf.len() / block_size;
+        self.check_range(block, count as u64)?;
+        let sequential =
+            core::mem::replace(&mut *self.next_read.lock(), block + count as u64) == block;
+
+        let mut index = 0;
+        while index < count {
+            let current = block + index as u64;
+            let buffer = lookup(&self.device, current);
+            let state = buffer.state.lock();
+            if state.valid {
+                buf[index * block_size..][..block_size].copy_from_slice(&state.data);
+                index += 1;
+                continue;
+            }
+            drop(state);
+
+            // read the missing blocks at once, extending sequential reads by the read-ahead
+            let wanted = count - index + if sequential { READAHEAD.get() } else { 0 };
+            let limit = (wanted as u64).min(self.block_count() - current);
+            let mut run = 1;
+            while run < limit && !CACHE.lock().contains(&self.device, current + run) {
+                run += 1;
+            }
+            let mut data = vec![0; run as usize * block_size];
+            self.device.read_blocks(current, &mut data)?;
+
+            for (offset, chunk) in data.chunks(block_size).enumerate() {
+                let buffer = match offset {
+                    0 => buffer.clone(),
+                    _ => lookup(&self.device, current + offset as u64),
+                };
+                let mut state = buffer.state.lock();
+                if !state.valid {
+                    state.data.copy_from_slice(chunk);
+                    state.valid = true;
+                }
+                if index + offset < count {
+                    buf[(index + offset) * block_size..][..block_size].copy_from_slice(&state.data);
+                }
+            }
+            index += (run as usize).min(count - index);
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> vfs::Result<()> {
+        let block_size = self.block_size();
+        self.check_range(block, (buf.len() / block_size) as u64)?;
+        // rejected now, it would only fail when written back
+        if self.read_only() {
+            return Err(FsError::ReadOnly);
+        }
+
+        for (index, chunk) in buf.chunks(block_size).enumerate() {
+            let buffer = lookup(&self.device, block + index as u64);
+            let mut state = buffer.state.lock();
+            state.data.copy_from_slice(chunk);
+            state.valid = true;
+            state.dirty_since.get_or_insert_with(timer::now);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> vfs::Result<()> {
+        write_back(Some(device_id(&self.device)))?;
+        self.device.flush()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    /// drops pending writes of the blocks, the cached content stays as it is undefined anyway
+    fn discard(&self, block: u64, count: u64) -> vfs::Result<()> {
+        self.check_range(block, count)?;
+        for buffer in CACHE.lock().range(&self.device, block, count) {
+            buffer.state.lock().dirty_since = None;
+        }
+        self.device.discard(block, count)
+    }
+
+    fn write_zeroes(&self, block: u64, count: u64) -> vfs::Result<()> {
+        self.check_range(block, count)?;
+        if self.read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        for buffer in CACHE.lock().range(&self.device, block, count) {
+            let mut state = buffer.state.lock();
+            state.data.fill(0);
+            state.valid = true;
+            state.dirty_since = None;
+        }
+        self.device.write_zeroes(block, count)
+    }
+}
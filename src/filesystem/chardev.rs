@@ -0,0 +1,159 @@
+use crate::driver::ProbeError;
+use crate::filesystem::vfs::{self, FsError};
+use crate::sync::{Arc, SpinLock};
+use crate::{println, random, sbi};
+use alloc::vec::Vec;
+use macros::driver;
+
+/// Device transferring a stream of bytes, e.g. a terminal
+pub trait CharDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// reads at most `buf.len()` bytes, returning 0 at the end of the stream
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize>;
+
+    /// writes a prefix of [buf], returning its length
+    fn write(&self, buf: &[u8]) -> vfs::Result<usize>;
+}
+
+/// Character devices in the order they were registered
+static DEVICES: SpinLock<Vec<Arc<dyn CharDevice>>> = SpinLock::new(Vec::new());
+
+pub fn register(device: Arc<dyn CharDevice>) {
+    println!("chardev: {}", device.name());
+    DEVICES.lock().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn CharDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// returns the device named [name], with or without a `/dev/` prefix
+pub fn find(name: &str) -> Option<Arc<dyn CharDevice>> {
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Discards writes and reads nothing
+struct Null;
+
+impl CharDevice for Null {
+    fn name(&self) -> &str {
+        "null"
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> vfs::Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> vfs::Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// Discards writes and reads zeros
+struct Zero;
+
+impl CharDevice for Zero {
+    fn name(&self) -> &str {
+        "zero"
+    }
+
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> vfs::Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads from [random::fill_bytes], writes are discarded
+///
+/// Reads fail while no entropy source (`/chosen/rng-seed` or a virtio-rng device) seeded the
+/// generator, rather than returning predictable bytes.
+struct Random(&'static str);
+
+impl CharDevice for Random {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize> {
+        if !random::fill_bytes(buf) {
+            return Err(FsError::Io);
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> vfs::Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// SBI console
+///
+/// Reads wait for a first byte by polling, then return the bytes already received.
+struct Console;
+
+impl CharDevice for Console {
+    fn name(&self) -> &str {
+        "console"
+    }
+
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = loop {
+            match sbi::get_char() {
+                Some(byte) => break byte,
+                None => core::hint::spin_loop(),
+            }
+        };
+
+        let mut len = 1;
+        while len < buf.len()
+            && let Some(byte) = sbi::get_char()
+        {
+            buf[len] = byte;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> vfs::Result<usize> {
+        for byte in buf {
+            sbi::put_char(*byte as char);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// registers the SBI console ahead of the other devices
+#[driver(name = "sbi-console", level = EarlyConsole)]
+fn probe_console() -> Result<(), ProbeError> {
+    if sbi::CONSOLE.get() != "sbi" {
+        println!(
+            "console: {} is not supported, using sbi",
+            sbi::CONSOLE.get()
+        );
+    }
+    register(Arc::new(Console));
+    Ok(())
+}
+
+/// registers the devices implemented without hardware
+#[driver(name = "chardev", level = Device)]
+fn probe() -> Result<(), ProbeError> {
+    register(Arc::new(Null));
+    register(Arc::new(Zero));
+    register(Arc::new(Random("random")));
+    register(Arc::new(Random("urandom")));
+    Ok(())
+}
@@ -0,0 +1,123 @@
+/// Magic of the `newc` format (without checksums, `070702` adds them)
+const MAGIC_NEWC: &[u8; 6] = b"070701";
+const MAGIC_NEWC_CRC: &[u8; 6] = b"070702";
+/// Size of the header, 6 bytes of magic and 13 fields of 8 hexadecimal digits
+const HEADER_SIZE: usize = 110;
+/// Name of the entry terminating the archive
+const TRAILER: &str = "TRAILER!!!";
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CpioError {
+    /// the archive ends inside of an entry
+    Truncated,
+    /// an entry does not start with the newc magic
+    BadMagic,
+    /// a header field is not hexadecimal
+    BadHeader,
+    /// a name is not NUL terminated UTF-8
+    BadName,
+}
+
+/// Entry of a newc archive
+///
+/// Hard links are not resolved, their data is only attached to the last entry of the inode.
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn file_type(&self) -> u32 {
+        self.mode & S_IFMT
+    }
+}
+
+/// Iterator over the entries of a newc archive, ending at the trailer
+pub struct Reader<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(archive: &'a [u8]) -> Self {
+        Self {
+            archive,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    fn read(&mut self, len: usize) -> Result<&'a [u8], CpioError> {
+        let bytes = self
+            .archive
+            .get(self.offset..self.offset + len)
+            .ok_or(CpioError::Truncated)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn align(&mut self) {
+        self.offset = self.offset.next_multiple_of(4);
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let header = self.read(HEADER_SIZE)?;
+        let magic = &header[..6];
+        if magic != MAGIC_NEWC && magic != MAGIC_NEWC_CRC {
+            return Err(CpioError::BadMagic);
+        }
+
+        let field = |index: usize| {
+            let digits = &header[6 + index * 8..6 + (index + 1) * 8];
+            core::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .ok_or(CpioError::BadHeader)
+        };
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor,
+        // rdevminor, namesize, check
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name = self.read(name_size)?;
+        let name = name
+            .strip_suffix(&[0])
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or(CpioError::BadName)?;
+        self.align();
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+
+        let data = self.read(file_size)?;
+        self.align();
+
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = self.next_entry().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
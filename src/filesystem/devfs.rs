@@ -0,0 +1,178 @@
+use crate::filesystem::block::{self, BlockDevice};
+use crate::filesystem::chardev::{self, CharDevice};
+use crate::filesystem::vfs::{
+    self, DirEntry, Directory, File, FileType, Filesystem, FsError, Inode, Stat,
+};
+use crate::sync::Arc;
+use alloc::string::{String, ToString};
+
+/// Inode number of the root, devices are numbered after it by kind and registration order
+const ROOT_INO: u64 = 1;
+
+/// Filesystem listing the registered character and block devices, usually mounted at `/dev`
+///
+/// The directory is generated from the registries on every lookup, so devices registered after
+/// mounting appear as well. Nodes cannot be created, removed or renamed.
+pub struct DevFs {
+    root: Arc<DevInode>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(DevInode::Root),
+        }
+    }
+}
+
+impl Filesystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum DevInode {
+    Root,
+    /// character device with its index in [chardev::devices]
+    Char(usize, Arc<dyn CharDevice>),
+    /// block device with its index in [block::devices]
+    Block(usize, Arc<dyn BlockDevice>),
+}
+
+impl DevInode {
+    fn ino(&self) -> u64 {
+        // registries only grow, so indices are stable
+        match self {
+            DevInode::Root => ROOT_INO,
+            DevInode::Char(index, _) => ROOT_INO + 1 + 2 * *index as u64,
+            DevInode::Block(index, _) => ROOT_INO + 2 + 2 * *index as u64,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self {
+            DevInode::Root => FileType::Directory,
+            DevInode::Char(..) => FileType::CharDevice,
+            DevInode::Block(..) => FileType::BlockDevice,
+        }
+    }
+
+    /// returns every device node, character devices first
+    fn entries() -> impl Iterator<Item = (String, DevInode)> {
+        let chars = chardev::devices()
+            .into_iter()
+            .enumerate()
+            .map(|(index, device)| (device.name().to_string(), DevInode::Char(index, device)));
+        let blocks = block::devices()
+            .into_iter()
+            .enumerate()
+            .map(|(index, device)| (device.name().to_string(), DevInode::Block(index, device)));
+        chars.chain(blocks)
+    }
+}
+
+impl Inode for DevInode {
+    fn stat(&self) -> vfs::Result<Stat> {
+        let (mode, size) = match self {
+            DevInode::Root => (0o755, 0),
+            DevInode::Char(..) => (0o666, 0),
+            DevInode::Block(_, device) if device.read_only() => (0o440, block::size(&**device)),
+            DevInode::Block(_, device) => (0o660, block::size(&**device)),
+        };
+        Ok(Stat {
+            file_type: self.file_type(),
+            mode,
+            ino: self.ino(),
+            nlink: if let DevInode::Root = self { 2 } else { 1 },
+            size,
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        (!matches!(self, DevInode::Root)).then_some(self)
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory> {
+        matches!(self, DevInode::Root).then_some(self)
+    }
+}
+
+impl File for DevInode {
+    /// reads from the stream of a character device, ignoring [offset]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        match self {
+            DevInode::Root => Err(FsError::IsDirectory),
+            DevInode::Char(_, device) => device.read(buf),
+            DevInode::Block(_, device) => {
+                let size = block::size(&**device);
+                let len = (buf.len() as u64).min(size.saturating_sub(offset)) as usize;
+                block::read_at(&**device, offset, &mut buf[..len])?;
+                Ok(len)
+            }
+        }
+    }
+
+    /// writes to the stream of a character device, ignoring [offset]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        match self {
+            DevInode::Root => Err(FsError::IsDirectory),
+            DevInode::Char(_, device) => device.write(buf),
+            DevInode::Block(_, device) => {
+                let size = block::size(&**device);
+                if offset >= size && !buf.is_empty() {
+                    return Err(FsError::NoSpace);
+                }
+                let len = (buf.len() as u64).min(size - offset.min(size)) as usize;
+                block::write_at(&**device, offset, &buf[..len])?;
+                Ok(len)
+            }
+        }
+    }
+
+    /// ignored, devices have a fixed size
+    fn truncate(&self, _size: u64) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        match self {
+            DevInode::Block(_, device) => device.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Directory for DevInode {
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        DevInode::entries()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, inode)| Arc::new(inode) as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u32) -> vfs::Result<Arc<dyn Inode>> {
+        Err(FsError::Unsupported)
+    }
+
+    fn unlink(&self, _name: &str) -> vfs::Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    fn rename(&self, _old_name: &str, _new_parent: &dyn Inode, _new_name: &str) -> vfs::Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    fn readdir(&self, index: usize) -> vfs::Result<Option<DirEntry>> {
+        Ok(DevInode::entries()
+            .nth(index)
+            .map(|(name, inode)| DirEntry {
+                name,
+                ino: inode.ino(),
+                file_type: inode.file_type(),
+            }))
+    }
+}
@@ -0,0 +1,1225 @@
+use crate::filesystem::block::{self, BlockDevice};
+use crate::filesystem::vfs::{
+    self, DirEntry, Directory, File, FileType, Filesystem, FsError, Inode, Stat,
+};
+use crate::println;
+use crate::sync::{Arc, SpinLock};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INO: u32 = 2;
+/// First inode number for files in revision 0, later ones record it in the superblock
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+/// Part of the on-disk inode read and written, the rest of larger inodes is left untouched
+const INODE_SIZE: usize = 128;
+const GROUP_DESC_SIZE: u64 = 32;
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+const TRIPLE_INDIRECT_BLOCK: usize = 14;
+/// Symlink targets shorter than this are stored in the block pointers
+const FAST_SYMLINK_MAX: usize = 60;
+const MAX_NAME: usize = 255;
+
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+/// Directory indexed by a hash tree, the index is dropped when the directory is modified
+const INDEX_FL: u32 = 0x1000;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFLNK: u16 = 0o120000;
+const S_IFREG: u16 = 0o100000;
+const S_IFBLK: u16 = 0o060000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFCHR: u16 = 0o020000;
+
+/// File type of a directory entry with the `filetype` feature
+const FT_UNKNOWN: u8 = 0;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_SYMLINK: u8 = 7;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn file_type_of(mode: u16) -> Option<FileType> {
+    match mode & S_IFMT {
+        S_IFREG => Some(FileType::Regular),
+        S_IFDIR => Some(FileType::Directory),
+        S_IFLNK => Some(FileType::Symlink),
+        S_IFCHR => Some(FileType::CharDevice),
+        S_IFBLK => Some(FileType::BlockDevice),
+        _ => None,
+    }
+}
+
+fn dirent_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Regular => FT_REG_FILE,
+        FileType::Directory => FT_DIR,
+        FileType::Symlink => FT_SYMLINK,
+        FileType::CharDevice => FT_CHRDEV,
+        FileType::BlockDevice => FT_BLKDEV,
+    }
+}
+
+/// returns the length of a directory entry holding a name of [name_len] bytes
+fn dirent_len(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+
+/// On-disk inode, accessed through its fields
+#[derive(Clone)]
+struct RawInode([u8; INODE_SIZE]);
+
+impl RawInode {
+    fn mode(&self) -> u16 {
+        read_u16(&self.0, 0)
+    }
+
+    fn file_type(&self) -> Option<FileType> {
+        file_type_of(self.mode())
+    }
+
+    fn size(&self) -> u64 {
+        let high = if self.mode() & S_IFMT == S_IFREG {
+            read_u32(&self.0, 108) as u64
+        } else {
+            0
+        };
+        (high << 32) | read_u32(&self.0, 4) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        write_u32(&mut self.0, 4, size as u32);
+        if self.mode() & S_IFMT == S_IFREG {
+            write_u32(&mut self.0, 108, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        read_u16(&self.0, 26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        write_u16(&mut self.0, 26, links);
+    }
+
+    /// returns the number of 512-byte sectors allocated, including indirect blocks
+    fn sectors(&self) -> u32 {
+        read_u32(&self.0, 28)
+    }
+
+    fn set_sectors(&mut self, sectors: u32) {
+        write_u32(&mut self.0, 28, sectors);
+    }
+
+    fn flags(&self) -> u32 {
+        read_u32(&self.0, 32)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        write_u32(&mut self.0, 32, flags);
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        read_u32(&self.0, 40 + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        write_u32(&mut self.0, 40 + index * 4, block);
+    }
+
+    fn set_times(&mut self, time: u32) {
+        for offset in [8, 12, 16] {
+            write_u32(&mut self.0, offset, time);
+        }
+    }
+
+    fn set_dtime(&mut self, time: u32) {
+        write_u32(&mut self.0, 20, time);
+    }
+
+    /// returns whether the symlink target is stored in the block pointers
+    fn is_fast_symlink(&self) -> bool {
+        self.mode() & S_IFMT == S_IFLNK && (self.size() as usize) < FAST_SYMLINK_MAX
+    }
+}
+
+/// Directory entry as stored on disk
+struct RawDirEntry {
+    /// byte offset in the directory
+    offset: u64,
+    ino: u32,
+    file_type: u8,
+    name: String,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    group_count: u32,
+    inode_size: u64,
+    first_ino: u32,
+    /// byte offset of the group descriptor table
+    group_table: u64,
+    filetype: bool,
+    /// whether files may be 2 GiB or larger, set on the first such file
+    large_file: SpinLock<bool>,
+    read_only: bool,
+    /// timestamp for changes, the last write time of the volume as there is no real-time clock
+    time: u32,
+    /// serializes every operation on the volume
+    lock: SpinLock<()>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> vfs::Result<Self> {
+        let mut superblock = [0; 1024];
+        block::read_at(&*device, SUPERBLOCK_OFFSET, &mut superblock)?;
+        if read_u16(&superblock, 56) != MAGIC {
+            return Err(FsError::Unsupported);
+        }
+
+        let revision = read_u32(&superblock, 76);
+        let (first_ino, inode_size, incompat, ro_compat) = if revision == 0 {
+            (GOOD_OLD_FIRST_INO, GOOD_OLD_INODE_SIZE, 0, 0)
+        } else {
+            (
+                read_u32(&superblock, 84),
+                read_u16(&superblock, 88) as u64,
+                read_u32(&superblock, 96),
+                read_u32(&superblock, 100),
+            )
+        };
+        if incompat & !FEATURE_INCOMPAT_FILETYPE != 0 {
+            println!("ext2: unsupported incompatible features {incompat:#x}");
+            return Err(FsError::Unsupported);
+        }
+        let supported = FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE;
+        let read_only = ro_compat & !supported != 0;
+        if read_only {
+            println!("ext2: read-only compatible features {ro_compat:#x}, mounting read-only");
+        }
+
+        let log_block_size = read_u32(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(FsError::Corrupted);
+        }
+        let block_size = 1024 << log_block_size;
+        let blocks_count = read_u32(&superblock, 4);
+        let first_data_block = read_u32(&superblock, 20);
+        let blocks_per_group = read_u32(&superblock, 32);
+        let inodes_per_group = read_u32(&superblock, 40);
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || blocks_per_group as u64 > block_size * 8
+            || inodes_per_group as u64 > block_size * 8
+            || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+            || !inode_size.is_power_of_two()
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let group_count = blocks_count
+            .checked_sub(first_data_block)
+            .ok_or(FsError::Corrupted)?
+            .div_ceil(blocks_per_group);
+        Ok(Self {
+            device,
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inodes_count: read_u32(&superblock, 0),
+            group_count,
+            inode_size,
+            first_ino,
+            // the descriptors follow the block of the superblock
+            group_table: (first_data_block as u64 + 1) * block_size,
+            filetype: incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+            large_file: SpinLock::new(ro_compat & FEATURE_RO_COMPAT_LARGE_FILE != 0),
+            read_only,
+            time: read_u32(&superblock, 44).max(read_u32(&superblock, 48)),
+            lock: SpinLock::new(()),
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<()> {
+        block::read_at(&*self.device, offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> vfs::Result<()> {
+        block::write_at(&*self.device, offset, buf)
+    }
+
+    fn read_block(&self, block: u32) -> vfs::Result<Vec<u8>> {
+        if block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        let mut data = vec![0; self.block_size as usize];
+        self.read(block as u64 * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u32, data: &[u8]) -> vfs::Result<()> {
+        if block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        self.write(block as u64 * self.block_size, data)
+    }
+
+    fn check_writable(&self) -> vfs::Result<()> {
+        match self.read_only {
+            true => Err(FsError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// sets the `large_file` feature before a file grows to [size]
+    fn require_large_file(&self, size: u64) -> vfs::Result<()> {
+        let mut large_file = self.large_file.lock();
+        if size <= i32::MAX as u64 || *large_file {
+            return Ok(());
+        }
+        let mut features = [0; 4];
+        self.read(SUPERBLOCK_OFFSET + 100, &mut features)?;
+        let features = u32::from_le_bytes(features) | FEATURE_RO_COMPAT_LARGE_FILE;
+        self.write(SUPERBLOCK_OFFSET + 100, &features.to_le_bytes())?;
+        *large_file = true;
+        Ok(())
+    }
+
+    fn group_desc(&self, group: u32) -> vfs::Result<[u8; GROUP_DESC_SIZE as usize]> {
+        let mut desc = [0; GROUP_DESC_SIZE as usize];
+        self.read(self.group_table + group as u64 * GROUP_DESC_SIZE, &mut desc)?;
+        Ok(desc)
+    }
+
+    /// adjusts the free block, free inode and directory counts of [group] and the superblock
+    fn adjust_counts(&self, group: u32, blocks: i32, inodes: i32, dirs: i32) -> vfs::Result<()> {
+        let offset = self.group_table + group as u64 * GROUP_DESC_SIZE;
+        let mut desc = self.group_desc(group)?;
+        for (field, delta) in [(12, blocks), (14, inodes), (16, dirs)] {
+            let value = read_u16(&desc, field).wrapping_add_signed(delta as i16);
+            write_u16(&mut desc, field, value);
+        }
+        self.write(offset, &desc)?;
+
+        let mut counts = [0; 8];
+        self.read(SUPERBLOCK_OFFSET + 12, &mut counts)?;
+        for (field, delta) in [(0, blocks), (4, inodes)] {
+            let value = read_u32(&counts, field).wrapping_add_signed(delta);
+            write_u32(&mut counts, field, value);
+        }
+        self.write(SUPERBLOCK_OFFSET + 12, &counts)
+    }
+
+    /// sets the first clear bit of a bitmap block below [limit] and returns its index
+    fn allocate_bit(&self, bitmap: u32, limit: u32) -> vfs::Result<Option<u32>> {
+        let mut data = self.read_block(bitmap)?;
+        for index in 0..limit {
+            let (byte, bit) = ((index / 8) as usize, index % 8);
+            if data[byte] & (1 << bit) == 0 {
+                data[byte] |= 1 << bit;
+                self.write(
+                    bitmap as u64 * self.block_size + byte as u64,
+                    &data[byte..=byte],
+                )?;
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    fn free_bit(&self, bitmap: u32, index: u32) -> vfs::Result<()> {
+        let offset = bitmap as u64 * self.block_size + (index / 8) as u64;
+        let mut byte = [0];
+        self.read(offset, &mut byte)?;
+        if byte[0] & (1 << (index % 8)) == 0 {
+            return Err(FsError::Corrupted);
+        }
+        byte[0] &= !(1 << (index % 8));
+        self.write(offset, &byte)
+    }
+
+    /// returns the groups in the order to search them, starting at [preferred]
+    fn groups_from(&self, preferred: u32) -> impl Iterator<Item = u32> {
+        let count = self.group_count;
+        (0..count).map(move |i| (preferred + i) % count)
+    }
+
+    /// allocates a zeroed block, preferably in [group]
+    fn allocate_block(&self, group: u32) -> vfs::Result<u32> {
+        for group in self.groups_from(group) {
+            let desc = self.group_desc(group)?;
+            if read_u16(&desc, 12) == 0 {
+                continue;
+            }
+            // the last group may be shorter
+            let start = self.first_data_block + group * self.blocks_per_group;
+            let limit = (self.blocks_count - start).min(self.blocks_per_group);
+            if let Some(index) = self.allocate_bit(read_u32(&desc, 0), limit)? {
+                self.adjust_counts(group, -1, 0, 0)?;
+                let block = start + index;
+                block::zero_at(
+                    &*self.device,
+                    block as u64 * self.block_size,
+                    self.block_size,
+                )?;
+                return Ok(block);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> vfs::Result<()> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        let group = (block - self.first_data_block) / self.blocks_per_group;
+        let index = (block - self.first_data_block) % self.blocks_per_group;
+        self.free_bit(read_u32(&self.group_desc(group)?, 0), index)?;
+        self.adjust_counts(group, 1, 0, 0)?;
+        block::discard_at(
+            &*self.device,
+            block as u64 * self.block_size,
+            self.block_size,
+        )
+    }
+
+    /// allocates an inode, preferably in [group]
+    fn allocate_inode(&self, group: u32, directory: bool) -> vfs::Result<u32> {
+        for group in self.groups_from(group) {
+            let desc = self.group_desc(group)?;
+            if read_u16(&desc, 14) == 0 {
+                continue;
+            }
+            let limit = self
+                .inodes_per_group
+                .min(self.inodes_count - group * self.inodes_per_group);
+            let Some(index) = self.allocate_bit(read_u32(&desc, 4), limit)? else {
+                continue;
+            };
+
+            let ino = group * self.inodes_per_group + index + 1;
+            if ino < self.first_ino {
+                // reserved inodes are marked in use by mke2fs, a clear bit is corruption
+                return Err(FsError::Corrupted);
+            }
+            self.adjust_counts(group, 0, -1, directory as i32)?;
+            // clear the whole inode, including the fields past the ones handled here
+            self.write(self.inode_offset(ino)?, &vec![0; self.inode_size as usize])?;
+            return Ok(ino);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, ino: u32, directory: bool) -> vfs::Result<()> {
+        let group = (ino - 1) / self.inodes_per_group;
+        let index = (ino - 1) % self.inodes_per_group;
+        self.free_bit(read_u32(&self.group_desc(group)?, 4), index)?;
+        self.adjust_counts(group, 0, 1, -(directory as i32))
+    }
+
+    fn group_of(&self, ino: u32) -> u32 {
+        (ino - 1) / self.inodes_per_group
+    }
+
+    fn inode_offset(&self, ino: u32) -> vfs::Result<u64> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Corrupted);
+        }
+        let index = (ino - 1) % self.inodes_per_group;
+        let table = read_u32(&self.group_desc(self.group_of(ino))?, 8);
+        Ok(table as u64 * self.block_size + index as u64 * self.inode_size)
+    }
+
+    fn read_inode(&self, ino: u32) -> vfs::Result<RawInode> {
+        let mut inode = RawInode([0; INODE_SIZE]);
+        self.read(self.inode_offset(ino)?, &mut inode.0)?;
+        Ok(inode)
+    }
+
+    fn write_inode(&self, ino: u32, inode: &RawInode) -> vfs::Result<()> {
+        self.write(self.inode_offset(ino)?, &inode.0)
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    /// returns the block holding the data block [index] of [inode], allocating missing blocks
+    /// when [allocate] is set, or 0 for a hole
+    fn map_block(
+        &self,
+        ino: u32,
+        inode: &mut RawInode,
+        index: u64,
+        allocate: bool,
+    ) -> vfs::Result<u32> {
+        let per_block = self.pointers_per_block();
+        // the path of pointer indices from the slot in the inode down to the data block
+        let (slot, path): (usize, Vec<u64>) = if index < DIRECT_BLOCKS as u64 {
+            (index as usize, vec![])
+        } else if index - (DIRECT_BLOCKS as u64) < per_block {
+            (INDIRECT_BLOCK, vec![index - DIRECT_BLOCKS as u64])
+        } else if index - (DIRECT_BLOCKS as u64) - per_block < per_block * per_block {
+            let index = index - DIRECT_BLOCKS as u64 - per_block;
+            (
+                DOUBLE_INDIRECT_BLOCK,
+                vec![index / per_block, index % per_block],
+            )
+        } else {
+            let index = index - DIRECT_BLOCKS as u64 - per_block - per_block * per_block;
+            if index >= per_block * per_block * per_block {
+                return Err(FsError::NoSpace);
+            }
+            (
+                TRIPLE_INDIRECT_BLOCK,
+                vec![
+                    index / (per_block * per_block),
+                    index / per_block % per_block,
+                    index % per_block,
+                ],
+            )
+        };
+
+        let group = self.group_of(ino);
+        let sectors_per_block = (self.block_size / 512) as u32;
+        let mut block = inode.block(slot);
+        if block == 0 {
+            if !allocate {
+                return Ok(0);
+            }
+            block = self.allocate_block(group)?;
+            inode.set_block(slot, block);
+            inode.set_sectors(inode.sectors() + sectors_per_block);
+        }
+
+        for index in path {
+            let offset = block as u64 * self.block_size + index * 4;
+            let mut pointer = [0; 4];
+            self.read(offset, &mut pointer)?;
+            let mut next = u32::from_le_bytes(pointer);
+            if next == 0 {
+                if !allocate {
+                    return Ok(0);
+                }
+                next = self.allocate_block(group)?;
+                self.write(offset, &next.to_le_bytes())?;
+                inode.set_sectors(inode.sectors() + sectors_per_block);
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    fn read_data(
+        &self,
+        ino: u32,
+        inode: &RawInode,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> vfs::Result<()> {
+        let mut inode = inode.clone();
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let skip = position % self.block_size;
+            let len = ((self.block_size - skip) as usize).min(buf.len() - done);
+            match self.map_block(ino, &mut inode, position / self.block_size, false)? {
+                0 => buf[done..done + len].fill(0),
+                block => self.read(
+                    block as u64 * self.block_size + skip,
+                    &mut buf[done..done + len],
+                )?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// writes [buf] at [offset] of [inode], allocating blocks, without updating the size
+    fn write_data(
+        &self,
+        ino: u32,
+        inode: &mut RawInode,
+        offset: u64,
+        buf: &[u8],
+    ) -> vfs::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let skip = position % self.block_size;
+            let len = ((self.block_size - skip) as usize).min(buf.len() - done);
+            let block = self.map_block(ino, inode, position / self.block_size, true)?;
+            self.write(
+                block as u64 * self.block_size + skip,
+                &buf[done..done + len],
+            )?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// frees the data blocks from index [keep] on, and the indirect blocks no longer needed
+    fn free_blocks_from(&self, inode: &mut RawInode, keep: u64) -> vfs::Result<()> {
+        let per_block = self.pointers_per_block();
+        let mut freed = 0;
+        for slot in 0..DIRECT_BLOCKS {
+            let block = inode.block(slot);
+            if slot as u64 >= keep && block != 0 {
+                self.free_block(block)?;
+                inode.set_block(slot, 0);
+                freed += 1;
+            }
+        }
+
+        let mut first = DIRECT_BLOCKS as u64;
+        for (slot, depth) in [
+            (INDIRECT_BLOCK, 1),
+            (DOUBLE_INDIRECT_BLOCK, 2),
+            (TRIPLE_INDIRECT_BLOCK, 3),
+        ] {
+            let span = per_block.pow(depth);
+            let block = inode.block(slot);
+            if block != 0 && keep < first + span {
+                let start = keep.saturating_sub(first);
+                if self.free_tree(block, depth, start, &mut freed)? {
+                    self.free_block(block)?;
+                    inode.set_block(slot, 0);
+                    freed += 1;
+                }
+            }
+            first += span;
+        }
+
+        let sectors = freed * (self.block_size / 512) as u32;
+        inode.set_sectors(inode.sectors().saturating_sub(sectors));
+        Ok(())
+    }
+
+    /// frees the blocks from index [start] below the indirect block [block] of [depth] levels,
+    /// returning whether it is now empty
+    fn free_tree(&self, block: u32, depth: u32, start: u64, freed: &mut u32) -> vfs::Result<bool> {
+        let per_block = self.pointers_per_block();
+        let span = per_block.pow(depth - 1);
+        let mut data = self.read_block(block)?;
+        let mut changed = false;
+
+        for index in 0..per_block {
+            let offset = index as usize * 4;
+            let child = read_u32(&data, offset);
+            // the subtree lies entirely before [start]
+            if child == 0 || (index + 1) * span <= start {
+                continue;
+            }
+
+            let child_start = start.saturating_sub(index * span);
+            let empty = depth == 1 || self.free_tree(child, depth - 1, child_start, freed)?;
+            if empty {
+                self.free_block(child)?;
+                *freed += 1;
+                write_u32(&mut data, offset, 0);
+                changed = true;
+            }
+        }
+
+        let empty = data.iter().all(|&byte| byte == 0);
+        if changed && !empty {
+            self.write_block(block, &data)?;
+        }
+        Ok(empty)
+    }
+
+    /// changes the size of a regular file, freeing or zeroing the blocks beyond the old size
+    fn resize(&self, ino: u32, inode: &mut RawInode, size: u64) -> vfs::Result<()> {
+        let old_size = inode.size();
+        if size < old_size {
+            self.free_blocks_from(inode, size.div_ceil(self.block_size))?;
+            // the rest of the last block is expected to be zero if the file grows again
+            let tail = size % self.block_size;
+            if tail != 0 {
+                let block = self.map_block(ino, inode, size / self.block_size, false)?;
+                if block != 0 {
+                    let zeros = vec![0; (self.block_size - tail) as usize];
+                    self.write(block as u64 * self.block_size + tail, &zeros)?;
+                }
+            }
+        }
+        inode.set_size(size);
+        Ok(())
+    }
+
+    fn read_dir(&self, ino: u32, inode: &RawInode) -> vfs::Result<Vec<RawDirEntry>> {
+        let mut entries = Vec::new();
+        let size = inode.size();
+        let mut data = vec![0; self.block_size as usize];
+        let mut block_start = 0;
+        while block_start < size {
+            self.read_data(ino, inode, block_start, &mut data)?;
+            let mut offset = 0;
+            while offset + 8 <= data.len() {
+                let entry_ino = read_u32(&data, offset);
+                let rec_len = read_u16(&data, offset + 4);
+                let name_len = data[offset + 6] as usize;
+                if rec_len < 8
+                    || offset + rec_len as usize > data.len()
+                    || name_len + 8 > rec_len as usize
+                {
+                    return Err(FsError::Corrupted);
+                }
+                if entry_ino != 0 {
+                    let name = &data[offset + 8..offset + 8 + name_len];
+                    entries.push(RawDirEntry {
+                        offset: block_start + offset as u64,
+                        ino: entry_ino,
+                        file_type: if self.filetype {
+                            data[offset + 7]
+                        } else {
+                            FT_UNKNOWN
+                        },
+                        name: String::from_utf8_lossy(name).into_owned(),
+                    });
+                }
+                offset += rec_len as usize;
+            }
+            block_start += self.block_size;
+        }
+        Ok(entries)
+    }
+
+    fn find(&self, ino: u32, inode: &RawInode, name: &str) -> vfs::Result<RawDirEntry> {
+        self.read_dir(ino, inode)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(FsError::NotFound)
+    }
+
+    /// adds the entry [name] for [child] to the directory [ino]
+    fn add_entry(
+        &self,
+        ino: u32,
+        inode: &mut RawInode,
+        name: &str,
+        child: u32,
+        file_type: FileType,
+    ) -> vfs::Result<()> {
+        let needed = dirent_len(name.len());
+        let size = inode.size();
+        let mut data = vec![0; self.block_size as usize];
+        let mut block_start = 0;
+
+        let (block_start, offset, rec_len) = 'found: {
+            while block_start < size {
+                self.read_data(ino, inode, block_start, &mut data)?;
+                let mut offset = 0;
+                while offset + 8 <= data.len() {
+                    let entry_ino = read_u32(&data, offset);
+                    let rec_len = read_u16(&data, offset + 4) as usize;
+                    let used = if entry_ino == 0 {
+                        0
+                    } else {
+                        dirent_len(data[offset + 6] as usize)
+                    };
+                    if rec_len < 8.max(used) || offset + rec_len > data.len() {
+                        return Err(FsError::Corrupted);
+                    }
+                    if rec_len - used >= needed {
+                        // shrink the existing entry to make room after it
+                        if used != 0 {
+                            write_u16(&mut data, offset + 4, used as u16);
+                        }
+                        break 'found (block_start, offset + used, rec_len - used);
+                    }
+                    offset += rec_len;
+                }
+                block_start += self.block_size;
+            }
+
+            // append a block holding only the new entry
+            data.fill(0);
+            inode.set_size(size + self.block_size);
+            (size, 0, self.block_size as usize)
+        };
+
+        write_u32(&mut data, offset, child);
+        write_u16(&mut data, offset + 4, rec_len as u16);
+        data[offset + 6] = name.len() as u8;
+        data[offset + 7] = if self.filetype {
+            dirent_type(file_type)
+        } else {
+            FT_UNKNOWN
+        };
+        data[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+        self.write_data(ino, inode, block_start, &data)?;
+        // a hash tree index would miss the new entry
+        inode.set_flags(inode.flags() & !INDEX_FL);
+        self.write_inode(ino, inode)
+    }
+
+    /// removes the entry at [entry_offset] from the directory [ino]
+    fn remove_entry(&self, ino: u32, inode: &mut RawInode, entry_offset: u64) -> vfs::Result<()> {
+        let block_start = entry_offset / self.block_size * self.block_size;
+        let target = (entry_offset - block_start) as usize;
+        let mut data = vec![0; self.block_size as usize];
+        self.read_data(ino, inode, block_start, &mut data)?;
+
+        // merge the entry into the previous one, or mark the first entry of a block unused
+        let mut previous = None;
+        let mut offset = 0;
+        while offset < target {
+            previous = Some(offset);
+            offset += read_u16(&data, offset + 4) as usize;
+        }
+        if offset != target {
+            return Err(FsError::Corrupted);
+        }
+        match previous {
+            Some(previous) => {
+                let merged = read_u16(&data, previous + 4) + read_u16(&data, target + 4);
+                write_u16(&mut data, previous + 4, merged);
+            }
+            None => write_u32(&mut data, target, 0),
+        }
+        self.write_data(ino, inode, block_start, &data)?;
+        inode.set_flags(inode.flags() & !INDEX_FL);
+        self.write_inode(ino, inode)
+    }
+
+    /// drops a link to [ino], freeing it with its blocks after the last one
+    fn unlink_inode(&self, ino: u32) -> vfs::Result<()> {
+        let mut inode = self.read_inode(ino)?;
+        let directory = inode.file_type() == Some(FileType::Directory);
+        // a directory is also linked from its own `.`
+        let links = if directory {
+            0
+        } else {
+            inode.links().saturating_sub(1)
+        };
+        inode.set_links(links);
+        if links != 0 {
+            return self.write_inode(ino, &inode);
+        }
+
+        if !inode.is_fast_symlink() {
+            self.free_blocks_from(&mut inode, 0)?;
+        }
+        inode.set_size(0);
+        inode.set_dtime(self.time.max(1));
+        self.write_inode(ino, &inode)?;
+        self.free_inode(ino, directory)
+    }
+
+    /// returns whether the directory holds nothing but `.` and `..`
+    fn is_empty_dir(&self, ino: u32, inode: &RawInode) -> vfs::Result<bool> {
+        Ok(self
+            .read_dir(ino, inode)?
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == ".."))
+    }
+
+    /// points `..` of the directory [ino] to [parent]
+    fn set_dot_dot(&self, ino: u32, parent: u32) -> vfs::Result<()> {
+        let mut inode = self.read_inode(ino)?;
+        let entry = self.find(ino, &inode, "..")?;
+        self.write_data(ino, &mut inode, entry.offset, &parent.to_le_bytes())
+    }
+}
+
+fn validate_name(name: &str) -> vfs::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME || name.contains('/')
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// Second extended filesystem, without the journal of its successors
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+}
+
+impl Ext2Fs {
+    pub fn new(device: Arc<dyn BlockDevice>) -> vfs::Result<Self> {
+        Ok(Self {
+            volume: Arc::new(Volume::new(device)?),
+        })
+    }
+}
+
+impl Filesystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Ext2Inode {
+            volume: self.volume.clone(),
+            ino: ROOT_INO,
+            file_type: FileType::Directory,
+        })
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        self.volume.device.flush()
+    }
+}
+
+pub struct Ext2Inode {
+    volume: Arc<Volume>,
+    ino: u32,
+    file_type: FileType,
+}
+
+impl Ext2Inode {
+    fn open(volume: &Arc<Volume>, ino: u32) -> vfs::Result<Arc<Self>> {
+        let inode = volume.read_inode(ino)?;
+        Ok(Arc::new(Self {
+            volume: volume.clone(),
+            ino,
+            file_type: inode.file_type().ok_or(FsError::Unsupported)?,
+        }))
+    }
+
+    /// creates an inode of [file_type] linked as [name], initialized by [initialize]
+    fn create_inode(
+        &self,
+        name: &str,
+        file_type: FileType,
+        mode: u32,
+        initialize: impl FnOnce(&Volume, u32, &mut RawInode) -> vfs::Result<()>,
+    ) -> vfs::Result<Arc<dyn Inode>> {
+        validate_name(name)?;
+        let volume = &self.volume;
+        volume.check_writable()?;
+        let _guard = volume.lock.lock();
+        let mut parent = volume.read_inode(self.ino)?;
+        match volume.find(self.ino, &parent, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        let directory = file_type == FileType::Directory;
+        let ino = volume.allocate_inode(volume.group_of(self.ino), directory)?;
+        let type_bits = match file_type {
+            FileType::Regular => S_IFREG,
+            FileType::Directory => S_IFDIR,
+            FileType::Symlink => S_IFLNK,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+        };
+        let mut inode = RawInode([0; INODE_SIZE]);
+        write_u16(&mut inode.0, 0, type_bits | (mode & 0o7777) as u16);
+        inode.set_times(volume.time);
+        inode.set_links(if directory { 2 } else { 1 });
+
+        let result = initialize(volume, ino, &mut inode)
+            .and_then(|()| volume.write_inode(ino, &inode))
+            .and_then(|()| volume.add_entry(self.ino, &mut parent, name, ino, file_type));
+        if let Err(err) = result {
+            // release what was allocated for the inode
+            if !inode.is_fast_symlink() {
+                volume.free_blocks_from(&mut inode, 0)?;
+            }
+            volume.free_inode(ino, directory)?;
+            return Err(err);
+        }
+
+        if directory {
+            // `..` of the new directory links the parent
+            parent.set_links(parent.links() + 1);
+            volume.write_inode(self.ino, &parent)?;
+        }
+        Ok(Arc::new(Ext2Inode {
+            volume: volume.clone(),
+            ino,
+            file_type,
+        }))
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn stat(&self) -> vfs::Result<Stat> {
+        let _guard = self.volume.lock.lock();
+        let inode = self.volume.read_inode(self.ino)?;
+        Ok(Stat {
+            file_type: self.file_type,
+            mode: (inode.mode() & 0o7777) as u32,
+            ino: self.ino as u64,
+            nlink: inode.links() as u32,
+            size: inode.size(),
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        (self.file_type == FileType::Regular).then_some(self)
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory> {
+        (self.file_type == FileType::Directory).then_some(self)
+    }
+
+    fn readlink(&self) -> vfs::Result<String> {
+        if self.file_type != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        let _guard = self.volume.lock.lock();
+        let inode = self.volume.read_inode(self.ino)?;
+        let size = inode.size() as usize;
+        let target = if inode.is_fast_symlink() {
+            inode.0[40..40 + size].to_vec()
+        } else {
+            let mut target = vec![0; size];
+            self.volume.read_data(self.ino, &inode, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| FsError::Corrupted)
+    }
+}
+
+impl File for Ext2Inode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let volume = &self.volume;
+        let _guard = volume.lock.lock();
+        let inode = volume.read_inode(self.ino)?;
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        volume.read_data(self.ino, &inode, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+        let _guard = volume.lock.lock();
+        let mut inode = volume.read_inode(self.ino)?;
+        let end = offset + buf.len() as u64;
+        volume.require_large_file(end)?;
+        volume.write_data(self.ino, &mut inode, offset, buf)?;
+        if end > inode.size() {
+            inode.set_size(end);
+        }
+        write_u32(&mut inode.0, 16, volume.time);
+        volume.write_inode(self.ino, &inode)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> vfs::Result<()> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+        let _guard = volume.lock.lock();
+        let mut inode = volume.read_inode(self.ino)?;
+        volume.require_large_file(size)?;
+        volume.resize(self.ino, &mut inode, size)?;
+        volume.write_inode(self.ino, &inode)
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        self.volume.device.flush()
+    }
+}
+
+impl Directory for Ext2Inode {
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let volume = &self.volume;
+        let _guard = volume.lock.lock();
+        let inode = volume.read_inode(self.ino)?;
+        let entry = volume.find(self.ino, &inode, name)?;
+        Ok(Ext2Inode::open(volume, entry.ino)?)
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> vfs::Result<Arc<dyn Inode>> {
+        let parent = self.ino;
+        match file_type {
+            FileType::Directory => {
+                self.create_inode(name, file_type, mode, |volume, ino, inode| {
+                    // a single block with `.` and `..`
+                    let block_size = volume.block_size as usize;
+                    let mut data = vec![0; block_size];
+                    let dot_len = dirent_len(1);
+                    let dir_type = if volume.filetype { FT_DIR } else { FT_UNKNOWN };
+                    write_u32(&mut data, 0, ino);
+                    write_u16(&mut data, 4, dot_len as u16);
+                    data[6] = 1;
+                    data[7] = dir_type;
+                    data[8] = b'.';
+                    write_u32(&mut data, dot_len, parent);
+                    write_u16(&mut data, dot_len + 4, (block_size - dot_len) as u16);
+                    data[dot_len + 6] = 2;
+                    data[dot_len + 7] = dir_type;
+                    data[dot_len + 8..dot_len + 10].copy_from_slice(b"..");
+                    volume.write_data(ino, inode, 0, &data)?;
+                    inode.set_size(volume.block_size);
+                    Ok(())
+                })
+            }
+            FileType::Regular => self.create_inode(name, file_type, mode, |_, _, _| Ok(())),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn Inode>> {
+        if target.is_empty() || target.len() >= self.volume.block_size as usize {
+            return Err(FsError::InvalidArgument);
+        }
+        self.create_inode(name, FileType::Symlink, 0o777, |volume, ino, inode| {
+            if target.len() < FAST_SYMLINK_MAX {
+                inode.0[40..40 + target.len()].copy_from_slice(target.as_bytes());
+            } else {
+                volume.write_data(ino, inode, 0, target.as_bytes())?;
+            }
+            inode.set_size(target.len() as u64);
+            Ok(())
+        })
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        validate_name(name)?;
+        let volume = &self.volume;
+        volume.check_writable()?;
+        let _guard = volume.lock.lock();
+        let mut parent = volume.read_inode(self.ino)?;
+        let entry = volume.find(self.ino, &parent, name)?;
+        let inode = volume.read_inode(entry.ino)?;
+        let directory = inode.file_type() == Some(FileType::Directory);
+        if directory && !volume.is_empty_dir(entry.ino, &inode)? {
+            return Err(FsError::NotEmpty);
+        }
+
+        volume.remove_entry(self.ino, &mut parent, entry.offset)?;
+        if directory {
+            // the `..` of the removed directory no longer links the parent
+            parent.set_links(parent.links().checked_sub(1).ok_or(FsError::Corrupted)?);
+            volume.write_inode(self.ino, &parent)?;
+        }
+        volume.unlink_inode(entry.ino)
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &dyn Inode, new_name: &str) -> vfs::Result<()> {
+        validate_name(old_name)?;
+        validate_name(new_name)?;
+        let new_parent = (new_parent as &dyn core::any::Any)
+            .downcast_ref::<Ext2Inode>()
+            .filter(|new_parent| Arc::ptr_eq(&new_parent.volume, &self.volume))
+            .ok_or(FsError::CrossDevice)?;
+        let volume = &self.volume;
+        volume.check_writable()?;
+        let _guard = volume.lock.lock();
+
+        let old_dir = volume.read_inode(self.ino)?;
+        let source = volume.find(self.ino, &old_dir, old_name)?;
+        let source_inode = volume.read_inode(source.ino)?;
+        let source_type = source_inode.file_type().ok_or(FsError::Unsupported)?;
+        let directory = source_type == FileType::Directory;
+
+        let mut new_dir = volume.read_inode(new_parent.ino)?;
+        match volume.find(new_parent.ino, &new_dir, new_name) {
+            Ok(target) if target.ino == source.ino => return Ok(()),
+            Ok(target) => {
+                let target_inode = volume.read_inode(target.ino)?;
+                let target_directory = target_inode.file_type() == Some(FileType::Directory);
+                match (directory, target_directory) {
+                    (true, true) if !volume.is_empty_dir(target.ino, &target_inode)? => {
+                        return Err(FsError::NotEmpty);
+                    }
+                    (true, false) => return Err(FsError::NotDirectory),
+                    (false, true) => return Err(FsError::IsDirectory),
+                    _ => {}
+                }
+                volume.remove_entry(new_parent.ino, &mut new_dir, target.offset)?;
+                if target_directory {
+                    new_dir.set_links(new_dir.links().checked_sub(1).ok_or(FsError::Corrupted)?);
+                    volume.write_inode(new_parent.ino, &new_dir)?;
+                }
+                volume.unlink_inode(target.ino)?;
+            }
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        volume.add_entry(
+            new_parent.ino,
+            &mut new_dir,
+            new_name,
+            source.ino,
+            source_type,
+        )?;
+        // the old directory may be the new one, whose inode just changed
+        let mut old_dir = volume.read_inode(self.ino)?;
+        let source = volume.find(self.ino, &old_dir, old_name)?;
+        volume.remove_entry(self.ino, &mut old_dir, source.offset)?;
+
+        if directory && self.ino != new_parent.ino {
+            volume.set_dot_dot(source.ino, new_parent.ino)?;
+            old_dir.set_links(old_dir.links().checked_sub(1).ok_or(FsError::Corrupted)?);
+            volume.write_inode(self.ino, &old_dir)?;
+            let mut new_dir = volume.read_inode(new_parent.ino)?;
+            new_dir.set_links(new_dir.links() + 1);
+            volume.write_inode(new_parent.ino, &new_dir)?;
+        }
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> vfs::Result<Option<DirEntry>> {
+        let volume = &self.volume;
+        let _guard = volume.lock.lock();
+        let inode = volume.read_inode(self.ino)?;
+        let Some(entry) = volume
+            .read_dir(self.ino, &inode)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .nth(index)
+        else {
+            return Ok(None);
+        };
+
+        let file_type = match entry.file_type {
+            FT_REG_FILE => FileType::Regular,
+            FT_DIR => FileType::Directory,
+            FT_SYMLINK => FileType::Symlink,
+            FT_CHRDEV => FileType::CharDevice,
+            FT_BLKDEV => FileType::BlockDevice,
+            _ => volume
+                .read_inode(entry.ino)?
+                .file_type()
+                .ok_or(FsError::Unsupported)?,
+        };
+        Ok(Some(DirEntry {
+            name: entry.name,
+            ino: entry.ino as u64,
+            file_type,
+        }))
+    }
+}
@@ -0,0 +1,1171 @@
+use crate::filesystem::block::{self, BlockDevice};
+use crate::filesystem::vfs::{
+    self, DirEntry, Directory, File, FileType, Filesystem, FsError, Inode, Stat,
+};
+use crate::sync::{Arc, SpinLock};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+const DIRENT_SIZE: usize = 32;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// First name byte of a deleted entry
+const DELETED: u8 = 0xe5;
+/// Sequence number flag of the last (first stored) long name entry
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Offsets of the UTF-16 name characters in a long name entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME: usize = 255;
+/// Case flags of the short entry for a lowercase base name and extension
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+/// FAT date of 1980-01-01, as there is no real-time clock to take timestamps from
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+/// Free cluster count of the FAT32 FSInfo sector meaning unknown
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+const FSINFO_FREE_COUNT: u64 = 488;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Storage of a directory
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum DirLocation {
+    /// fixed root directory region of FAT12/16, as a byte range on the device
+    Root(u64, u64),
+    /// cluster chain starting at the cluster
+    Chain(u32),
+}
+
+/// Allocation state, its lock serializes every operation on the volume
+struct State {
+    /// cluster to start searching for a free one
+    next_free: u32,
+    /// whether the FSInfo free count was already invalidated
+    fsinfo_invalidated: bool,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    cluster_size: u64,
+    /// byte offset of the first FAT
+    fat_offset: u64,
+    /// size of a FAT in bytes
+    fat_size: u64,
+    /// FAT read from
+    active_fat: u32,
+    /// FATs updated on writes, all of them unless mirroring is disabled on FAT32
+    mirrors: Range<u32>,
+    root: DirLocation,
+    /// byte offset of cluster 2
+    data_offset: u64,
+    cluster_count: u32,
+    /// byte offset of the FAT32 FSInfo sector
+    fsinfo: Option<u64>,
+    state: SpinLock<State>,
+}
+
+/// Directory entry as stored on disk, with its long name resolved
+struct RawEntry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    /// byte offset of the short entry
+    offset: u64,
+    /// byte offsets of the long name entries preceding the short entry
+    lfn_offsets: Vec<u64>,
+}
+
+impl RawEntry {
+    fn is_directory(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.short_name[0] == b'.'
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// returns the checksum of a short name stored in the long name entries
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| {
+        (sum >> 1).wrapping_add(sum << 7).wrapping_add(byte)
+    })
+}
+
+/// returns the printable form of a short name, e.g. `README.TXT`
+fn display_short_name(short_name: &[u8; 11], case: u8) -> String {
+    let mut base = short_name[..8].to_vec();
+    if base[0] == 0x05 {
+        base[0] = DELETED;
+    }
+    let lower = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .iter()
+            .take_while(|&&byte| byte != b' ')
+            .map(|&byte| {
+                let c = byte as char;
+                if lower { c.to_ascii_lowercase() } else { c }
+            })
+            .collect()
+    };
+
+    let mut name = lower(&base, case & CASE_LOWER_BASE != 0);
+    let extension = lower(&short_name[8..], case & CASE_LOWER_EXT != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// returns the short name for [name] if it fits 8.3, with case flags for lowercase parts
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) => (base, Some(extension)),
+        None => (name, None),
+    };
+    let extension = extension.unwrap_or("");
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    // a part may be all lowercase or all uppercase, recorded by the case flags
+    let mut case = 0;
+    for (part, flag) in [(base, CASE_LOWER_BASE), (extension, CASE_LOWER_EXT)] {
+        let upper = part.to_ascii_uppercase();
+        if !upper.chars().all(is_short_char) {
+            return None;
+        }
+        if part != upper {
+            if part != part.to_ascii_lowercase() {
+                return None;
+            }
+            case |= flag;
+        }
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+    Some((short_name, case))
+}
+
+/// returns a unique `BASE~N.EXT` short name for a name only representable as a long name
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> vfs::Result<[u8; 11]> {
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if is_short_char(c) { c as u8 } else { b'_' }
+            })
+            .take(len)
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) => (convert(base, 8), convert(extension, 3)),
+        None => (convert(name, 8), Vec::new()),
+    };
+
+    let mut short_name = [b' '; 11];
+    short_name[8..8 + extension.len()].copy_from_slice(&extension);
+    for n in 1..1_000_000 {
+        let tail = alloc::format!("~{n}");
+        let len = base.len().min(8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..len].copy_from_slice(&base[..len]);
+        short_name[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+fn validate_name(name: &str) -> vfs::Result<()> {
+    let invalid = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME
+        || name.chars().any(invalid)
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// returns the long name entries storing [name], in the order they are stored on disk
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; DIRENT_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_CHARS);
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut entry = [0; DIRENT_SIZE];
+            entry[0] = (index + 1) as u8 | if index == count - 1 { LFN_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+                // the name is NUL terminated if it does not fill the entry, then padded
+                let unit = match units.get(index * LFN_CHARS + i) {
+                    Some(&unit) => unit,
+                    None if index * LFN_CHARS + i == units.len() => 0,
+                    None => 0xffff,
+                };
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+fn short_entry(short_name: &[u8; 11], case: u8, attr: u8, first_cluster: u32) -> [u8; DIRENT_SIZE] {
+    let mut entry = [0; DIRENT_SIZE];
+    entry[..11].copy_from_slice(short_name);
+    entry[11] = attr;
+    entry[12] = case;
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    entry
+}
+
+impl Volume {
+    /// parses the boot sector of the volume at the start of [device]
+    fn new(device: Arc<dyn BlockDevice>) -> vfs::Result<Self> {
+        let mut sector = [0; 512];
+        block::read_at(&*device, 0, &mut sector)?;
+
+        let bytes_per_sector = read_u16(&sector, 11) as u64;
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved_sectors = read_u16(&sector, 14) as u64;
+        let fat_count = sector[16] as u32;
+        let root_entries = read_u16(&sector, 17) as u64;
+        let total_sectors = match read_u16(&sector, 19) {
+            0 => read_u32(&sector, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match read_u16(&sector, 22) {
+            0 => read_u32(&sector, 36) as u64,
+            sectors => sectors as u64,
+        };
+
+        if !matches!(sector[0], 0xeb | 0xe9)
+            || sector[510..512] != [0x55, 0xaa]
+            || !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+            || total_sectors == 0
+        {
+            return Err(FsError::Unsupported);
+        }
+
+        let root_sectors = (root_entries * DIRENT_SIZE as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved_sectors + fat_count as u64 * fat_sectors + root_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_sector)
+            .ok_or(FsError::Corrupted)?
+            / sectors_per_cluster;
+        // the type is defined by the number of clusters alone
+        let fat_type = match cluster_count {
+            ..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        if (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return Err(FsError::Corrupted);
+        }
+
+        let fat_offset = reserved_sectors * bytes_per_sector;
+        let fat_size = fat_sectors * bytes_per_sector;
+        let (root, active_fat, mirrors, fsinfo) = if fat_type == FatType::Fat32 {
+            let flags = read_u16(&sector, 40);
+            // bit 7 disables mirroring, only the FAT in the low bits is used then
+            let (active_fat, mirrors) = if flags & 0x80 != 0 {
+                let active = (flags & 0xf) as u32;
+                (active, active..active + 1)
+            } else {
+                (0, 0..fat_count)
+            };
+            let fsinfo = match read_u16(&sector, 48) {
+                0 | 0xffff => None,
+                sector => Some(sector as u64 * bytes_per_sector),
+            };
+            let root = DirLocation::Chain(read_u32(&sector, 44) & 0x0fff_ffff);
+            (root, active_fat, mirrors, fsinfo)
+        } else {
+            let root_offset = fat_offset + fat_count as u64 * fat_size;
+            let root =
+                DirLocation::Root(root_offset, root_offset + root_entries * DIRENT_SIZE as u64);
+            (root, 0, 0..fat_count, None)
+        };
+        if active_fat >= fat_count {
+            return Err(FsError::Corrupted);
+        }
+
+        let volume = Self {
+            device,
+            fat_type,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_offset,
+            fat_size,
+            active_fat,
+            mirrors,
+            root,
+            data_offset: data_sector * bytes_per_sector,
+            cluster_count: cluster_count as u32,
+            fsinfo,
+            state: SpinLock::new(State {
+                next_free: 2,
+                fsinfo_invalidated: false,
+            }),
+        };
+        if block::size(&*volume.device) < volume.data_offset + cluster_count * volume.cluster_size {
+            return Err(FsError::Corrupted);
+        }
+        Ok(volume)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<()> {
+        block::read_at(&*self.device, offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> vfs::Result<()> {
+        block::write_at(&*self.device, offset, buf)
+    }
+
+    fn zero(&self, offset: u64, len: u64) -> vfs::Result<()> {
+        block::zero_at(&*self.device, offset, len)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.cluster_size
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    fn fat_entry_offset(&self, fat: u32, cluster: u32) -> u64 {
+        let index = match self.fat_type {
+            FatType::Fat12 => cluster as u64 * 3 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        };
+        self.fat_offset + fat as u64 * self.fat_size + index
+    }
+
+    fn read_fat(&self, cluster: u32) -> vfs::Result<u32> {
+        let offset = self.fat_entry_offset(self.active_fat, cluster);
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read(offset, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes) as u32;
+                if cluster % 2 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read(offset, &mut bytes)?;
+                u16::from_le_bytes(bytes) as u32
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read(offset, &mut bytes)?;
+                u32::from_le_bytes(bytes) & 0x0fff_ffff
+            }
+        })
+    }
+
+    /// sets the FAT entry of [cluster] in every mirrored FAT
+    fn write_fat(&self, cluster: u32, value: u32) -> vfs::Result<()> {
+        for fat in self.mirrors.clone() {
+            let offset = self.fat_entry_offset(fat, cluster);
+            match self.fat_type {
+                FatType::Fat12 => {
+                    // entries share the byte in the middle of their 3 bytes
+                    let mut bytes = [0; 2];
+                    self.read(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let new = if cluster % 2 == 1 {
+                        (old & 0x000f) | ((value as u16) << 4)
+                    } else {
+                        (old & 0xf000) | (value as u16 & 0x0fff)
+                    };
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write(offset, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // the upper 4 bits are reserved and preserved
+                    let mut bytes = [0; 4];
+                    self.read(offset, &mut bytes)?;
+                    let new = (u32::from_le_bytes(bytes) & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// returns the cluster following [cluster] in its chain
+    fn next_cluster(&self, cluster: u32) -> vfs::Result<Option<u32>> {
+        let next = self.read_fat(cluster)?;
+        if next >= self.end_of_chain() - 7 {
+            Ok(None)
+        } else if next < 2 || next >= self.cluster_count + 2 {
+            // free, reserved or bad clusters are never part of a chain
+            Err(FsError::Corrupted)
+        } else {
+            Ok(Some(next))
+        }
+    }
+
+    /// returns the clusters of the chain starting at [first], empty for cluster 0
+    fn chain(&self, first: u32) -> vfs::Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut next = (first != 0).then_some(first);
+        while let Some(cluster) = next {
+            if cluster < 2 || cluster >= self.cluster_count + 2 {
+                return Err(FsError::Corrupted);
+            }
+            clusters.push(cluster);
+            // a chain longer than the volume contains a cycle
+            if clusters.len() > self.cluster_count as usize {
+                return Err(FsError::Corrupted);
+            }
+            next = self.next_cluster(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// allocates a cluster, appending it to the chain ending at [previous]
+    fn allocate_cluster(&self, state: &mut State, previous: Option<u32>) -> vfs::Result<u32> {
+        let start = state.next_free.clamp(2, self.cluster_count + 1);
+        let candidates = (start..self.cluster_count + 2).chain(2..start);
+        for cluster in candidates {
+            if self.read_fat(cluster)? != 0 {
+                continue;
+            }
+
+            self.write_fat(cluster, self.end_of_chain())?;
+            if let Some(previous) = previous {
+                self.write_fat(previous, cluster)?;
+            }
+            state.next_free = cluster + 1;
+            self.invalidate_fsinfo(state)?;
+            return Ok(cluster);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// frees the chain starting at [first]
+    fn free_chain(&self, state: &mut State, first: u32) -> vfs::Result<()> {
+        for cluster in self.chain(first)? {
+            self.write_fat(cluster, 0)?;
+            block::discard_at(
+                &*self.device,
+                self.cluster_offset(cluster),
+                self.cluster_size,
+            )?;
+        }
+        self.invalidate_fsinfo(state)
+    }
+
+    /// marks the FSInfo free count as unknown instead of keeping it up to date
+    fn invalidate_fsinfo(&self, state: &mut State) -> vfs::Result<()> {
+        if let Some(fsinfo) = self.fsinfo
+            && !state.fsinfo_invalidated
+        {
+            self.write(fsinfo + FSINFO_FREE_COUNT, &FSINFO_UNKNOWN.to_le_bytes())?;
+            state.fsinfo_invalidated = true;
+        }
+        Ok(())
+    }
+
+    /// returns the byte ranges of [dir] on the device, in order
+    fn dir_regions(&self, dir: DirLocation) -> vfs::Result<Vec<Range<u64>>> {
+        Ok(match dir {
+            DirLocation::Root(start, end) => core::iter::once(start..end).collect(),
+            DirLocation::Chain(first) => self
+                .chain(first)?
+                .into_iter()
+                .map(|cluster| {
+                    let offset = self.cluster_offset(cluster);
+                    offset..offset + self.cluster_size
+                })
+                .collect(),
+        })
+    }
+
+    /// returns the entries of [dir], including `.` and `..`
+    fn read_dir(&self, dir: DirLocation) -> vfs::Result<Vec<RawEntry>> {
+        let mut entries = Vec::new();
+        let mut units = [0u16; MAX_NAME.next_multiple_of(LFN_CHARS)];
+        let mut lfn_offsets = Vec::new();
+        let mut lfn_checksum = 0;
+        // sequence number expected next, 0 when no long name is pending
+        let mut lfn_next = 0;
+
+        for region in self.dir_regions(dir)? {
+            let mut data = vec![0; (region.end - region.start) as usize];
+            self.read(region.start, &mut data)?;
+
+            for (index, entry) in data.chunks_exact(DIRENT_SIZE).enumerate() {
+                let offset = region.start + (index * DIRENT_SIZE) as u64;
+                match entry[0] {
+                    0 => return Ok(entries),
+                    DELETED => {
+                        lfn_next = 0;
+                        lfn_offsets.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if entry[11] & 0x3f == ATTR_LONG_NAME {
+                    let sequence = entry[0] & !LFN_LAST;
+                    if entry[0] & LFN_LAST != 0 {
+                        lfn_offsets.clear();
+                        lfn_checksum = entry[13];
+                        units.fill(0);
+                    } else if sequence != lfn_next || entry[13] != lfn_checksum {
+                        lfn_next = 0;
+                        lfn_offsets.clear();
+                        continue;
+                    }
+                    if sequence == 0 || sequence as usize * LFN_CHARS > units.len() {
+                        lfn_next = 0;
+                        lfn_offsets.clear();
+                        continue;
+                    }
+
+                    let start = (sequence as usize - 1) * LFN_CHARS;
+                    for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+                        units[start + i] = read_u16(entry, offset);
+                    }
+                    lfn_offsets.push(offset);
+                    lfn_next = sequence - 1;
+                    continue;
+                }
+
+                let short_name: [u8; 11] = entry[..11].try_into().unwrap();
+                let attr = entry[11];
+                let long_name = (lfn_next == 0
+                    && !lfn_offsets.is_empty()
+                    && lfn_checksum == checksum(&short_name))
+                .then(|| {
+                    let len = units
+                        .iter()
+                        .position(|&unit| unit == 0)
+                        .unwrap_or(units.len());
+                    char::decode_utf16(units[..len].iter().copied())
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect::<String>()
+                });
+                // an incomplete run or one for another short name is an orphan
+                let lfn = if long_name.is_some() {
+                    core::mem::take(&mut lfn_offsets)
+                } else {
+                    lfn_offsets.clear();
+                    Vec::new()
+                };
+                lfn_next = 0;
+                if attr & ATTR_VOLUME_ID != 0 {
+                    continue;
+                }
+
+                let high = if self.fat_type == FatType::Fat32 {
+                    read_u16(entry, 20) as u32
+                } else {
+                    0
+                };
+                entries.push(RawEntry {
+                    name: long_name.unwrap_or_else(|| display_short_name(&short_name, entry[12])),
+                    short_name,
+                    attr,
+                    first_cluster: (high << 16) | read_u16(entry, 26) as u32,
+                    offset,
+                    lfn_offsets: lfn,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// returns the entry named [name] in [dir], matching case-insensitively
+    fn find(&self, dir: DirLocation, name: &str) -> vfs::Result<RawEntry> {
+        self.read_dir(dir)?
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .find(|entry| {
+                entry.name.eq_ignore_ascii_case(name)
+                    || display_short_name(&entry.short_name, 0).eq_ignore_ascii_case(name)
+            })
+            .ok_or(FsError::NotFound)
+    }
+
+    /// stores [entries] in consecutive free slots of [dir], extending it if needed, and returns
+    /// the offset of the last one
+    fn insert_entries(
+        &self,
+        state: &mut State,
+        dir: DirLocation,
+        entries: &[[u8; DIRENT_SIZE]],
+    ) -> vfs::Result<u64> {
+        let mut run = Vec::new();
+        for region in self.dir_regions(dir)? {
+            let mut data = vec![0; (region.end - region.start) as usize];
+            self.read(region.start, &mut data)?;
+            for (index, entry) in data.chunks_exact(DIRENT_SIZE).enumerate() {
+                if entry[0] == 0 || entry[0] == DELETED {
+                    run.push(region.start + (index * DIRENT_SIZE) as u64);
+                    if run.len() == entries.len() {
+                        return self.write_entries(&run, entries);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+        }
+
+        let DirLocation::Chain(first) = dir else {
+            return Err(FsError::NoSpace);
+        };
+        // append zeroed clusters, continuing the run of free slots at the end
+        let mut last = *self.chain(first)?.last().ok_or(FsError::Corrupted)?;
+        while run.len() < entries.len() {
+            last = self.allocate_cluster(state, Some(last))?;
+            let offset = self.cluster_offset(last);
+            self.zero(offset, self.cluster_size)?;
+            let slots = self.cluster_size / DIRENT_SIZE as u64;
+            run.extend((0..slots).map(|i| offset + i * DIRENT_SIZE as u64));
+        }
+        self.write_entries(&run[..entries.len()], entries)
+    }
+
+    fn write_entries(&self, offsets: &[u64], entries: &[[u8; DIRENT_SIZE]]) -> vfs::Result<u64> {
+        for (&offset, entry) in offsets.iter().zip(entries) {
+            self.write(offset, entry)?;
+        }
+        Ok(*offsets.last().unwrap())
+    }
+
+    /// marks the slots of [entry] as deleted, keeping its clusters
+    fn remove_entry(&self, entry: &RawEntry) -> vfs::Result<()> {
+        for &offset in entry.lfn_offsets.iter().chain([&entry.offset]) {
+            self.write(offset, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// returns whether [dir] contains nothing but `.` and `..`
+    fn is_empty(&self, first_cluster: u32) -> vfs::Result<bool> {
+        Ok(self
+            .read_dir(DirLocation::Chain(first_cluster))?
+            .iter()
+            .all(RawEntry::is_dot))
+    }
+}
+
+/// FAT12, FAT16 or FAT32 volume, including VFAT long names
+pub struct FatFs {
+    volume: Arc<Volume>,
+}
+
+impl FatFs {
+    /// mounts the FAT volume at the start of [device], usually a partition
+    pub fn new(device: Arc<dyn BlockDevice>) -> vfs::Result<Self> {
+        Ok(Self {
+            volume: Arc::new(Volume::new(device)?),
+        })
+    }
+}
+
+impl Filesystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.volume.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            entry: None,
+            directory: true,
+        })
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        self.volume.device.flush()
+    }
+}
+
+/// File or directory of a [FatFs]
+///
+/// FAT has no inodes, so the state lives in the directory entry, which is read again on every
+/// access. An inode does not follow its entry when it is renamed.
+pub struct FatInode {
+    volume: Arc<Volume>,
+    /// byte offset of the short directory entry, none for the root directory
+    entry: Option<u64>,
+    directory: bool,
+}
+
+impl FatInode {
+    fn new(volume: &Arc<Volume>, entry: &RawEntry) -> Arc<Self> {
+        Arc::new(Self {
+            volume: volume.clone(),
+            entry: Some(entry.offset),
+            directory: entry.is_directory(),
+        })
+    }
+
+    /// returns the attributes, first cluster and size from the directory entry
+    fn read_entry(&self) -> vfs::Result<(u8, u32, u32)> {
+        let Some(offset) = self.entry else {
+            return Ok((ATTR_DIRECTORY, 0, 0));
+        };
+        let mut entry = [0; DIRENT_SIZE];
+        self.volume.read(offset, &mut entry)?;
+        if entry[0] == DELETED || entry[0] == 0 {
+            return Err(FsError::NotFound);
+        }
+
+        let high = if self.volume.fat_type == FatType::Fat32 {
+            read_u16(&entry, 20) as u32
+        } else {
+            0
+        };
+        Ok((
+            entry[11],
+            (high << 16) | read_u16(&entry, 26) as u32,
+            read_u32(&entry, 28),
+        ))
+    }
+
+    fn write_entry(&self, first_cluster: u32, size: u32) -> vfs::Result<()> {
+        let offset = self.entry.ok_or(FsError::InvalidArgument)?;
+        if self.volume.fat_type == FatType::Fat32 {
+            self.volume
+                .write(offset + 20, &((first_cluster >> 16) as u16).to_le_bytes())?;
+        }
+        self.volume
+            .write(offset + 26, &(first_cluster as u16).to_le_bytes())?;
+        self.volume.write(offset + 28, &size.to_le_bytes())
+    }
+
+    fn location(&self) -> vfs::Result<DirLocation> {
+        match self.entry {
+            None => Ok(self.volume.root),
+            Some(_) => Ok(DirLocation::Chain(self.read_entry()?.1)),
+        }
+    }
+
+    /// returns the cluster recorded in `..` of the subdirectories, 0 for the root even on FAT32
+    fn dot_dot_cluster(&self) -> vfs::Result<u32> {
+        match self.entry {
+            None => Ok(0),
+            Some(_) => self.read_entry().map(|(_, cluster, _)| cluster),
+        }
+    }
+
+    /// extends or shrinks the chain of the file to [size] bytes, zeroing new data up to
+    /// [zero_until] as the rest is about to be written
+    fn resize(
+        &self,
+        state: &mut State,
+        size: u64,
+        zero_until: u64,
+    ) -> vfs::Result<(u32, Vec<u32>)> {
+        let volume = &self.volume;
+        let (_, mut first, old_size) = self.read_entry()?;
+        let mut clusters = volume.chain(first)?;
+        let needed = size.div_ceil(volume.cluster_size) as usize;
+
+        if needed < clusters.len() {
+            match needed {
+                0 => {
+                    volume.free_chain(state, first)?;
+                    first = 0;
+                }
+                _ => {
+                    volume.free_chain(state, clusters[needed])?;
+                    volume.write_fat(clusters[needed - 1], volume.end_of_chain())?;
+                }
+            }
+            clusters.truncate(needed);
+        }
+        while clusters.len() < needed {
+            let cluster = volume.allocate_cluster(state, clusters.last().copied())?;
+            if first == 0 {
+                first = cluster;
+            }
+            clusters.push(cluster);
+        }
+
+        // clusters keep stale data past the end of the file
+        let zero_until = zero_until.min(size);
+        if zero_until > old_size as u64 {
+            self.zero_range(&clusters, old_size as u64, zero_until)?;
+        }
+        Ok((first, clusters))
+    }
+
+    fn zero_range(&self, clusters: &[u32], start: u64, end: u64) -> vfs::Result<()> {
+        let volume = &self.volume;
+        let mut position = start;
+        while position < end {
+            let cluster = clusters[(position / volume.cluster_size) as usize];
+            let offset = position % volume.cluster_size;
+            let len = (volume.cluster_size - offset).min(end - position);
+            volume.zero(volume.cluster_offset(cluster) + offset, len)?;
+            position += len;
+        }
+        Ok(())
+    }
+}
+
+impl Inode for FatInode {
+    fn stat(&self) -> vfs::Result<Stat> {
+        let _state = self.volume.state.lock();
+        let (attr, _, size) = self.read_entry()?;
+        let mode = if self.directory { 0o755 } else { 0o644 };
+        Ok(Stat {
+            file_type: if self.directory {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            mode: if attr & ATTR_READ_ONLY != 0 {
+                mode & !0o222
+            } else {
+                mode
+            },
+            ino: self.entry.map_or(1, |offset| offset / DIRENT_SIZE as u64),
+            nlink: if self.directory { 2 } else { 1 },
+            size: if self.directory { 0 } else { size as u64 },
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        (!self.directory).then_some(self)
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory> {
+        self.directory.then_some(self)
+    }
+}
+
+impl File for FatInode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let volume = &self.volume;
+        let _state = volume.state.lock();
+        let (_, first, size) = self.read_entry()?;
+        if offset >= size as u64 {
+            return Ok(0);
+        }
+
+        let len = buf.len().min((size as u64 - offset) as usize);
+        let clusters = volume.chain(first)?;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / volume.cluster_size) as usize)
+                .ok_or(FsError::Corrupted)?;
+            let skip = position % volume.cluster_size;
+            let chunk = ((volume.cluster_size - skip) as usize).min(len - done);
+            volume.read(
+                volume.cluster_offset(cluster) + skip,
+                &mut buf[done..done + chunk],
+            )?;
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let (attr, _, size) = self.read_entry()?;
+        if attr & ATTR_READ_ONLY != 0 {
+            return Err(FsError::ReadOnly);
+        }
+        let end = offset + buf.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        let (first, clusters) = self.resize(&mut state, end.max(size as u64), offset)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let cluster = clusters[(position / volume.cluster_size) as usize];
+            let skip = position % volume.cluster_size;
+            let chunk = ((volume.cluster_size - skip) as usize).min(buf.len() - done);
+            volume.write(
+                volume.cluster_offset(cluster) + skip,
+                &buf[done..done + chunk],
+            )?;
+            done += chunk;
+        }
+        self.write_entry(first, end.max(size as u64) as u32)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> vfs::Result<()> {
+        let mut state = self.volume.state.lock();
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let (first, _) = self.resize(&mut state, size, size)?;
+        self.write_entry(first, size as u32)
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        self.volume.device.flush()
+    }
+}
+
+impl Directory for FatInode {
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let _state = self.volume.state.lock();
+        let entry = self.volume.find(self.location()?, name)?;
+        Ok(FatInode::new(&self.volume, &entry))
+    }
+
+    /// FAT has no permissions, so [_mode] is ignored
+    fn create(&self, name: &str, file_type: FileType, _mode: u32) -> vfs::Result<Arc<dyn Inode>> {
+        validate_name(name)?;
+        let attr = match file_type {
+            FileType::Regular => ATTR_ARCHIVE,
+            FileType::Directory => ATTR_DIRECTORY,
+            _ => return Err(FsError::Unsupported),
+        };
+
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let location = self.location()?;
+        let existing = volume.read_dir(location)?;
+        if existing
+            .iter()
+            .filter(|entry| !entry.is_dot())
+            .any(|entry| {
+                entry.name.eq_ignore_ascii_case(name)
+                    || display_short_name(&entry.short_name, 0).eq_ignore_ascii_case(name)
+            })
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let first_cluster = if file_type == FileType::Directory {
+            let cluster = volume.allocate_cluster(&mut state, None)?;
+            volume.zero(volume.cluster_offset(cluster), volume.cluster_size)?;
+            let parent = self.dot_dot_cluster()?;
+            let dot = short_entry(b".          ", 0, ATTR_DIRECTORY, cluster);
+            let dot_dot = short_entry(b"..         ", 0, ATTR_DIRECTORY, parent);
+            volume.write_entries(
+                &[
+                    volume.cluster_offset(cluster),
+                    volume.cluster_offset(cluster) + DIRENT_SIZE as u64,
+                ],
+                &[dot, dot_dot],
+            )?;
+            cluster
+        } else {
+            0
+        };
+
+        let mut entries = Vec::new();
+        let (short_name, case) = match exact_short_name(name) {
+            Some(exact) => exact,
+            None => {
+                let taken: Vec<_> = existing.iter().map(|entry| entry.short_name).collect();
+                let short_name = generate_short_name(name, &taken)?;
+                entries = long_name_entries(name, checksum(&short_name));
+                (short_name, 0)
+            }
+        };
+        entries.push(short_entry(&short_name, case, attr, first_cluster));
+
+        let offset = match volume.insert_entries(&mut state, location, &entries) {
+            Ok(offset) => offset,
+            Err(err) => {
+                if first_cluster != 0 {
+                    volume.free_chain(&mut state, first_cluster)?;
+                }
+                return Err(err);
+            }
+        };
+        Ok(Arc::new(FatInode {
+            volume: volume.clone(),
+            entry: Some(offset),
+            directory: file_type == FileType::Directory,
+        }))
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let entry = volume.find(self.location()?, name)?;
+        if entry.is_directory() && !volume.is_empty(entry.first_cluster)? {
+            return Err(FsError::NotEmpty);
+        }
+
+        volume.remove_entry(&entry)?;
+        if entry.first_cluster != 0 {
+            volume.free_chain(&mut state, entry.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &dyn Inode, new_name: &str) -> vfs::Result<()> {
+        validate_name(new_name)?;
+        let new_parent = (new_parent as &dyn core::any::Any)
+            .downcast_ref::<FatInode>()
+            .filter(|new_parent| Arc::ptr_eq(&new_parent.volume, &self.volume))
+            .ok_or(FsError::CrossDevice)?;
+
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let old_location = self.location()?;
+        let new_location = new_parent.location()?;
+        let source = volume.find(old_location, old_name)?;
+
+        let existing = volume.read_dir(new_location)?;
+        if let Some(target) = existing
+            .iter()
+            .filter(|entry| !entry.is_dot())
+            .find(|entry| {
+                entry.name.eq_ignore_ascii_case(new_name)
+                    || display_short_name(&entry.short_name, 0).eq_ignore_ascii_case(new_name)
+            })
+        {
+            if target.offset == source.offset {
+                // only the case of the name changes, which keeps the same slots
+                if target.name == new_name {
+                    return Ok(());
+                }
+            } else {
+                match (source.is_directory(), target.is_directory()) {
+                    (true, true) if !volume.is_empty(target.first_cluster)? => {
+                        return Err(FsError::NotEmpty);
+                    }
+                    (true, false) => return Err(FsError::NotDirectory),
+                    (false, true) => return Err(FsError::IsDirectory),
+                    _ => {}
+                }
+                volume.remove_entry(target)?;
+                if target.first_cluster != 0 {
+                    volume.free_chain(&mut state, target.first_cluster)?;
+                }
+            }
+        }
+
+        // the new short entry keeps the attributes, timestamps, cluster and size
+        let mut short = [0; DIRENT_SIZE];
+        volume.read(source.offset, &mut short)?;
+        let mut entries = Vec::new();
+        let (short_name, case) = match exact_short_name(new_name) {
+            Some(exact) => exact,
+            None => {
+                let taken: Vec<_> = existing
+                    .iter()
+                    .filter(|entry| entry.offset != source.offset)
+                    .map(|entry| entry.short_name)
+                    .collect();
+                let short_name = generate_short_name(new_name, &taken)?;
+                entries = long_name_entries(new_name, checksum(&short_name));
+                (short_name, 0)
+            }
+        };
+        short[..11].copy_from_slice(&short_name);
+        short[12] = case;
+        entries.push(short);
+
+        // the old slots are removed first so a rename within a directory may reuse them, and
+        // restored if the new ones do not fit
+        let mut old_slots = Vec::new();
+        for &offset in source.lfn_offsets.iter().chain([&source.offset]) {
+            let mut slot = [0; DIRENT_SIZE];
+            volume.read(offset, &mut slot)?;
+            old_slots.push((offset, slot));
+        }
+        volume.remove_entry(&source)?;
+        if let Err(err) = volume.insert_entries(&mut state, new_location, &entries) {
+            for (offset, slot) in old_slots {
+                volume.write(offset, &slot)?;
+            }
+            return Err(err);
+        }
+
+        if source.is_directory() && old_location != new_location {
+            // `..` of the moved directory refers to its new parent
+            let parent = new_parent.dot_dot_cluster()?;
+            let dot_dot = volume.cluster_offset(source.first_cluster) + DIRENT_SIZE as u64;
+            volume.write(dot_dot + 20, &((parent >> 16) as u16).to_le_bytes())?;
+            volume.write(dot_dot + 26, &(parent as u16).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> vfs::Result<Option<DirEntry>> {
+        let _state = self.volume.state.lock();
+        let entries = self.volume.read_dir(self.location()?)?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .nth(index)
+            .map(|entry| DirEntry {
+                ino: entry.offset / DIRENT_SIZE as u64,
+                file_type: if entry.is_directory() {
+                    FileType::Directory
+                } else {
+                    FileType::Regular
+                },
+                name: entry.name,
+            }))
+    }
+}
@@ -0,0 +1,102 @@
+use crate::driver::ProbeError;
+use crate::filesystem::cpio;
+use crate::filesystem::ramfs::{RamFs, RamInode};
+use crate::filesystem::vfs::{self, Directory, FileType, FsError};
+use crate::memory::PAGE_SIZE;
+use crate::sync::{Arc, SpinLock};
+use crate::{dtb, println};
+use core::ops::Range;
+use macros::driver;
+
+/// Mode of directories missing from the archive
+const DEFAULT_DIRECTORY_MODE: u32 = 0o755;
+
+/// Unpacked initramfs, none if no initrd was passed
+pub static INITRAMFS: SpinLock<Option<Arc<RamFs>>> = SpinLock::new(None);
+
+/// returns the physical range of the initrd passed in `/chosen`
+pub fn initrd_range() -> Option<Range<usize>> {
+    let fdt = dtb::try_fdt()?;
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+    (start < end).then_some(start..end)
+}
+
+/// returns [initrd_range] extended to whole pages, for reserving it from the page allocator
+pub fn reserved_range() -> Option<Range<usize>> {
+    initrd_range()
+        .map(|range| (range.start & !(PAGE_SIZE - 1))..range.end.next_multiple_of(PAGE_SIZE))
+}
+
+/// unpacks the newc cpio archive passed as initrd into [INITRAMFS]
+#[driver(name = "initramfs", level = Filesystem)]
+fn unpack() -> Result<(), ProbeError> {
+    let Some(range) = initrd_range() else {
+        return Err(ProbeError::NoDevice);
+    };
+
+    // the range is reserved in `memory::set_region_from_fdt` and never reused
+    let archive: &'static [u8] =
+        unsafe { core::slice::from_raw_parts(range.start as *const u8, range.end - range.start) };
+
+    let fs = RamFs::new("initramfs");
+    let mut files = 0;
+    for entry in cpio::Reader::new(archive) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                println!("initramfs: invalid archive at entry {files}: {err:?}");
+                return Err(ProbeError::Unsupported("invalid cpio archive"));
+            }
+        };
+
+        // the archive usually starts with `.` for the root itself
+        let path = entry.name.trim_start_matches("./").trim_matches('/');
+        if path.is_empty() || path == "." {
+            continue;
+        }
+        if let Err(err) = insert(fs.root_inode(), path, &entry) {
+            println!("initramfs: failed to add {}: {err:?}", entry.name);
+            continue;
+        }
+        files += 1;
+    }
+
+    println!(
+        "initramfs: unpacked {files} entries from {:#x}..{:#x}",
+        range.start, range.end
+    );
+    *INITRAMFS.lock() = Some(Arc::new(fs));
+    Ok(())
+}
+
+/// adds [entry] at [path] below [root], creating missing parent directories
+fn insert(root: &Arc<RamInode>, path: &str, entry: &cpio::Entry<'static>) -> vfs::Result<()> {
+    let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut parent = root.clone();
+    for component in parents.split('/').filter(|component| !component.is_empty()) {
+        parent = match parent.directory(component) {
+            Err(FsError::NotFound) => {
+                parent.create(component, FileType::Directory, DEFAULT_DIRECTORY_MODE)?;
+                parent.directory(component)?
+            }
+            result => result?,
+        };
+    }
+
+    let mode = entry.mode & 0o7777;
+    match entry.file_type() {
+        cpio::S_IFDIR => match parent.create(name, FileType::Directory, mode) {
+            // created earlier as the parent of another entry
+            Err(FsError::AlreadyExists) => Ok(()),
+            result => result.map(drop),
+        },
+        cpio::S_IFREG => parent.insert_file(name, mode, entry.data).map(drop),
+        cpio::S_IFLNK => {
+            let target = core::str::from_utf8(entry.data).map_err(|_| FsError::InvalidArgument)?;
+            parent.symlink(name, target).map(drop)
+        }
+        _ => Err(FsError::Unsupported),
+    }
+}
@@ -1 +1,116 @@
-pub mod virtio;
+use crate::filesystem::block::BlockDevice;
+use crate::filesystem::ramfs::RamFs;
+use crate::println;
+use crate::sync::Arc;
+use alloc::format;
+
+pub mod block;
+pub mod cache;
+pub mod chardev;
+pub mod cpio;
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initramfs;
+pub mod partition;
+pub mod procfs;
+pub mod ramfs;
+pub mod tmpfs;
+pub mod vfs;
+
+crate::kernel_param!(
+    /// device of the root filesystem (`root=`)
+    pub static ROOT: Option<&str> = "root", None
+);
+
+/// returns the filesystem on [device], trying every block filesystem driver
+pub fn open_device(device: Arc<dyn BlockDevice>) -> vfs::Result<Arc<dyn vfs::Filesystem>> {
+    match ext2::Ext2Fs::new(device.clone()) {
+        Err(vfs::FsError::Unsupported) => Ok(Arc::new(fat::FatFs::new(device)?)),
+        result => Ok(Arc::new(result?)),
+    }
+}
+
+/// returns a new filesystem of [fstype] (`proc`, `devfs` or `tmpfs`) or, for any other type, the
+/// filesystem on the block device [source], named with or without `/dev/`
+pub fn create(fstype: &str, source: &str) -> vfs::Result<Arc<dyn vfs::Filesystem>> {
+    Ok(match fstype {
+        "proc" => Arc::new(procfs::ProcFs::new()),
+        "devfs" | "devtmpfs" => Arc::new(devfs::DevFs::new()),
+        "tmpfs" => Arc::new(tmpfs::new()),
+        _ => {
+            let name = source.strip_prefix("/dev/").unwrap_or(source);
+            open_device(block::find(name).ok_or(vfs::FsError::NotFound)?)?
+        }
+    })
+}
+
+/// creates the directory [path] if needed and mounts [fs] on it
+fn mount_on_directory(path: &str, fs: Arc<dyn vfs::Filesystem>) -> vfs::Result<()> {
+    match vfs::mkdir(path, 0o755) {
+        Err(vfs::FsError::AlreadyExists) | Ok(()) => vfs::mount(path, fs),
+        Err(err) => Err(err),
+    }
+}
+
+/// mounts the root filesystem from `root=` or the initramfs, devfs at `/dev`, procfs at `/proc`,
+/// a tmpfs at `/tmp`, then the other block devices below `/mnt`
+pub fn mount_root() {
+    let root = ROOT.get().and_then(|root| {
+        let Some(device) = block::find(root) else {
+            println!("vfs: root device {root} not found, using the initramfs");
+            return None;
+        };
+        match open_device(device.clone()) {
+            Ok(fs) => Some((device, fs)),
+            Err(err) => {
+                println!("vfs: no filesystem on {root} ({err:?}), using the initramfs");
+                None
+            }
+        }
+    });
+
+    let (root_device, fs): (_, Arc<dyn vfs::Filesystem>) = match root {
+        Some((device, fs)) => (Some(device), fs),
+        None => match initramfs::INITRAMFS.lock().clone() {
+            Some(initramfs) => (None, initramfs),
+            None => (None, Arc::new(RamFs::new("rootfs"))),
+        },
+    };
+    vfs::mount("/", fs).expect("failed to mount the root filesystem");
+
+    let special: [(&str, Arc<dyn vfs::Filesystem>); 3] = [
+        ("/dev", Arc::new(devfs::DevFs::new())),
+        ("/proc", Arc::new(procfs::ProcFs::new())),
+        ("/tmp", Arc::new(tmpfs::new())),
+    ];
+    for (path, fs) in special {
+        if let Err(err) = mount_on_directory(path, fs) {
+            println!("vfs: failed to mount {path}: {err:?}");
+        }
+    }
+
+    for device in block::devices() {
+        if root_device
+            .as_ref()
+            .is_some_and(|root| Arc::ptr_eq(root, &device))
+        {
+            continue;
+        }
+        let Ok(fs) = open_device(device.clone()) else {
+            continue;
+        };
+
+        let path = format!("/mnt/{}", device.name());
+        let result = ["/mnt", path.as_str()]
+            .into_iter()
+            .try_for_each(|path| match vfs::mkdir(path, 0o755) {
+                Err(vfs::FsError::AlreadyExists) => Ok(()),
+                result => result,
+            })
+            .and_then(|()| vfs::mount(&path, fs));
+        if let Err(err) = result {
+            println!("vfs: failed to mount {} at {path}: {err:?}", device.name());
+        }
+    }
+}
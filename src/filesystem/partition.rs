@@ -0,0 +1,296 @@
+use crate::filesystem::block::{self, BlockDevice};
+use crate::filesystem::vfs::{self, FsError};
+use crate::println;
+use crate::sync::Arc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// MBR partition types of extended partitions, holding a chain of EBRs
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+/// MBR partition type covering the disk in front of a GPT
+const MBR_PROTECTIVE_TYPE: u8 = 0xee;
+/// Number of the first logical partition, primary partitions are numbered 1 to 4
+const FIRST_LOGICAL: usize = 5;
+/// Bound on the EBR chain, which may loop on a corrupted disk
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Bound on the size of the partition entry array, 128 entries are usual
+const GPT_MAX_ENTRIES_SIZE: usize = 1 << 20;
+
+/// Range of blocks of a disk exposed as a block device, e.g. `vda1`
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    block_count: u64,
+}
+
+impl Partition {
+    fn new(disk: &Arc<dyn BlockDevice>, number: usize, start: u64, block_count: u64) -> Self {
+        // like Linux, names ending in a digit are separated from the number, e.g. `mmcblk0p1`
+        let separator = if disk.name().ends_with(|c: char| c.is_ascii_digit()) {
+            "p"
+        } else {
+            ""
+        };
+        Self {
+            name: format!("{}{separator}{number}", disk.name()),
+            disk: disk.clone(),
+            start,
+            block_count,
+        }
+    }
+
+    /// returns the block on the disk of [block] of the partition, checking the [count] blocks
+    /// from it lie in the partition
+    fn translate(&self, block: u64, count: u64) -> vfs::Result<u64> {
+        match block.checked_add(count) {
+            Some(end) if end <= self.block_count => Ok(self.start + block),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> vfs::Result<()> {
+        let count = (buf.len() / self.block_size()) as u64;
+        self.disk.read_blocks(self.translate(block, count)?, buf)
+    }
+
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> vfs::Result<()> {
+        let count = (buf.len() / self.block_size()) as u64;
+        self.disk.write_blocks(self.translate(block, count)?, buf)
+    }
+
+    fn flush(&self) -> vfs::Result<()> {
+        self.disk.flush()
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    fn discard(&self, block: u64, count: u64) -> vfs::Result<()> {
+        self.disk.discard(self.translate(block, count)?, count)
+    }
+
+    fn write_zeroes(&self, block: u64, count: u64) -> vfs::Result<()> {
+        self.disk.write_zeroes(self.translate(block, count)?, count)
+    }
+}
+
+/// returns the partitions of [disk] from its GPT or MBR, none if it has no partition table
+pub fn scan(disk: &Arc<dyn BlockDevice>) -> vfs::Result<Vec<Partition>> {
+    let mut sector = vec![0; disk.block_size()];
+    disk.read_blocks(0, &mut sector)?;
+    let Some(entries) = mbr_entries(disk, &sector) else {
+        return Ok(Vec::new());
+    };
+
+    if entries
+        .iter()
+        .any(|entry| entry.kind == MBR_PROTECTIVE_TYPE)
+    {
+        return gpt(disk);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.kind == 0 {
+            continue;
+        }
+        if MBR_EXTENDED_TYPES.contains(&entry.kind) {
+            logical(disk, entry.start, &mut partitions)?;
+        } else {
+            partitions.push(Partition::new(disk, index + 1, entry.start, entry.count));
+        }
+    }
+    Ok(partitions)
+}
+
+/// Partition entry of an MBR or EBR, in blocks of the disk
+struct MbrEntry {
+    kind: u8,
+    start: u64,
+    count: u64,
+}
+
+/// returns the four entries of the MBR or EBR [sector], none if it is not a valid one
+///
+/// The boot sector of an unpartitioned FAT volume also ends with the signature, so every entry
+/// must have a valid boot indicator and lie on the disk.
+fn mbr_entries(disk: &Arc<dyn BlockDevice>, sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+
+    let entries = core::array::from_fn(|index| {
+        let entry = &sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        (
+            entry[0],
+            MbrEntry {
+                kind: entry[4],
+                start: read_u32(entry, 8) as u64,
+                count: read_u32(entry, 12) as u64,
+            },
+        )
+    });
+    let valid = entries.iter().all(|(status, entry)| {
+        matches!(status, 0x00 | 0x80)
+            && (entry.kind == 0
+                || entry.kind == MBR_PROTECTIVE_TYPE
+                || (entry.start != 0 && entry.start + entry.count <= disk.block_count()))
+    });
+    valid.then(|| entries.map(|(_, entry)| entry))
+}
+
+/// adds the logical partitions of the extended partition at [extended] to [partitions]
+fn logical(
+    disk: &Arc<dyn BlockDevice>,
+    extended: u64,
+    partitions: &mut Vec<Partition>,
+) -> vfs::Result<()> {
+    let mut sector = vec![0; disk.block_size()];
+    let mut ebr = extended;
+    for number in FIRST_LOGICAL..FIRST_LOGICAL + MAX_LOGICAL {
+        disk.read_blocks(ebr, &mut sector)?;
+        let Some([partition, next, ..]) = mbr_entries(disk, &sector) else {
+            return Err(FsError::Corrupted);
+        };
+
+        // the partition is relative to its EBR, the next EBR to the extended partition
+        if partition.kind != 0 {
+            let start = ebr + partition.start;
+            if start + partition.count > disk.block_count() {
+                return Err(FsError::Corrupted);
+            }
+            partitions.push(Partition::new(disk, number, start, partition.count));
+        }
+        if next.kind == 0 {
+            return Ok(());
+        }
+        ebr = extended + next.start;
+    }
+    Err(FsError::Corrupted)
+}
+
+/// returns the partitions of the GPT of [disk], using the backup header if the primary one is
+/// invalid
+fn gpt(disk: &Arc<dyn BlockDevice>) -> vfs::Result<Vec<Partition>> {
+    let (array, entry_size) = match gpt_entries(disk, 1) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!(
+                "partition: invalid primary GPT on {} ({err:?})",
+                disk.name()
+            );
+            // the backup header is in the last block
+            let backup = disk.block_count().checked_sub(1).ok_or(err)?;
+            gpt_entries(disk, backup)?
+        }
+    };
+
+    let mut partitions = Vec::new();
+    for (index, entry) in array.chunks(entry_size).enumerate() {
+        // unused entries have a zero type GUID
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let first = read_u64(entry, 32);
+        let last = read_u64(entry, 40);
+        if first > last || last >= disk.block_count() {
+            return Err(FsError::Corrupted);
+        }
+        partitions.push(Partition::new(disk, index + 1, first, last - first + 1));
+    }
+    Ok(partitions)
+}
+
+/// returns the partition entry array of the GPT header at [lba] and the size of an entry,
+/// validating the CRCs of both
+fn gpt_entries(disk: &Arc<dyn BlockDevice>, lba: u64) -> vfs::Result<(Vec<u8>, usize)> {
+    let mut header = vec![0; disk.block_size()];
+    disk.read_blocks(lba, &mut header)?;
+
+    let header_size = read_u32(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE
+        || !(GPT_MIN_HEADER_SIZE..=header.len()).contains(&header_size)
+        || read_u64(&header, 24) != lba
+    {
+        return Err(FsError::Corrupted);
+    }
+    let crc = read_u32(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != crc {
+        return Err(FsError::Corrupted);
+    }
+
+    let entries = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    let array_size = entry_count
+        .checked_mul(entry_size)
+        .ok_or(FsError::Corrupted)?;
+    if entry_size < GPT_MIN_ENTRY_SIZE
+        || !entry_size.is_power_of_two()
+        || array_size > GPT_MAX_ENTRIES_SIZE
+    {
+        return Err(FsError::Corrupted);
+    }
+    // the array must lie on the disk, even if the header says otherwise
+    let block_size = disk.block_size() as u64;
+    let array_blocks = (array_size as u64).div_ceil(block_size);
+    if entries
+        .checked_add(array_blocks)
+        .is_none_or(|end| end > disk.block_count())
+    {
+        return Err(FsError::Corrupted);
+    }
+    let offset = entries.checked_mul(block_size).ok_or(FsError::Corrupted)?;
+    let mut array = vec![0; array_size];
+    block::read_at(&**disk, offset, &mut array)?;
+    if crc32(&array) != read_u32(&header, 88) {
+        return Err(FsError::Corrupted);
+    }
+    Ok((array, entry_size))
+}
+
+/// returns the CRC-32 (IEEE 802.3) of [data], as used by GPT
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,393 @@
+use crate::allocator::{self, MINIMUM_BLOCK};
+use crate::exceptions;
+use crate::filesystem::vfs::{
+    self, DirEntry, Directory, File, FileType, Filesystem, FsError, Inode, Stat,
+};
+use crate::memory::{self, PageFlag};
+use crate::sync::Arc;
+use crate::{dtb, param, proc, smp};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use fdt::node::FdtNode;
+
+const ROOT_INO: u64 = 1;
+/// Inode numbers of process directories start here, spaced by [PROCESS_INO_STRIDE]
+const PROCESS_INO_BASE: u64 = 0x1000;
+const PROCESS_INO_STRIDE: u64 = 4;
+/// Set in the inode numbers of devicetree entries, which are hashes of their path
+const DEVICETREE_INO_BIT: u64 = 1 << 63;
+
+/// Synthetic filesystem exposing kernel state, usually mounted at `/proc`
+///
+/// Files are generated on every read, so reading one in several calls may mix two snapshots.
+pub struct ProcFs {
+    root: Arc<ProcInode>,
+}
+
+impl ProcFs {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(ProcInode::Root),
+        }
+    }
+}
+
+impl Filesystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Generated {
+    Meminfo,
+    Cmdline,
+    Interrupts,
+    Mounts,
+    Status(usize),
+    Maps(usize),
+}
+
+enum ProcInode {
+    Root,
+    File(Generated),
+    /// directory of the process [pid]
+    Process(usize),
+    /// directory of the fdt node at a path of node names, empty for the root node
+    DeviceTreeNode(Vec<String>),
+    /// raw value of a property of the fdt node at a path
+    DeviceTreeProperty(Vec<String>, String),
+}
+
+/// Files of the root directory besides the process directories
+const ROOT_FILES: [(&str, Generated); 4] = [
+    ("cmdline", Generated::Cmdline),
+    ("interrupts", Generated::Interrupts),
+    ("meminfo", Generated::Meminfo),
+    ("mounts", Generated::Mounts),
+];
+const DEVICETREE: &str = "devicetree";
+
+/// Names of interrupt causes of `scause`, unnamed ones are not listed
+const INTERRUPT_NAMES: [(usize, &str); 3] = [
+    (1, "supervisor software"),
+    (5, "supervisor timer"),
+    (9, "supervisor external"),
+];
+
+/// returns the 64-bit FNV-1a hash of the components of [path]
+fn hash_path<'a>(path: impl Iterator<Item = &'a str>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for component in path {
+        for byte in component.bytes().chain(*b"/") {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// calls [f] with the fdt node at [path], none if it does not exist
+fn with_node<R>(path: &[String], f: impl FnOnce(Option<FdtNode>) -> R) -> R {
+    let fdt = dtb::fdt();
+    let mut node = fdt.find_node("/");
+    for name in path {
+        node = node.and_then(|node| node.children().find(|child| child.name == name));
+    }
+    f(node)
+}
+
+impl Generated {
+    fn ino(self) -> u64 {
+        match self {
+            Generated::Meminfo => ROOT_INO + 1,
+            Generated::Cmdline => ROOT_INO + 2,
+            Generated::Interrupts => ROOT_INO + 3,
+            Generated::Mounts => ROOT_INO + 4,
+            Generated::Status(pid) => PROCESS_INO_BASE + pid as u64 * PROCESS_INO_STRIDE + 1,
+            Generated::Maps(pid) => PROCESS_INO_BASE + pid as u64 * PROCESS_INO_STRIDE + 2,
+        }
+    }
+
+    fn generate(self) -> vfs::Result<String> {
+        if let Generated::Status(pid) | Generated::Maps(pid) = self {
+            proc::find(pid).ok_or(FsError::NotFound)?;
+        }
+        let mut out = String::new();
+        self.write(&mut out).map_err(|_| FsError::Io)?;
+        Ok(out)
+    }
+
+    fn write(self, out: &mut String) -> fmt::Result {
+        match self {
+            Generated::Meminfo => {
+                let region = memory::get_region();
+                let free_blocks = allocator::free_blocks();
+                let free: usize = free_blocks
+                    .iter()
+                    .enumerate()
+                    .map(|(order, count)| count * (MINIMUM_BLOCK << order))
+                    .sum();
+                writeln!(out, "MemTotal:       {:>10} kB", region.size / 1024)?;
+                writeln!(out, "MemFree:        {:>10} kB", free / 1024)?;
+                writeln!(
+                    out,
+                    "KernelHeapFree: {:>10} kB",
+                    crate::kernel_heap_available() / 1024
+                )?;
+                writeln!(
+                    out,
+                    "Region:         {:#x}-{:#x}",
+                    region.addr,
+                    region.end()
+                )?;
+                // free blocks of each order, starting with blocks of MINIMUM_BLOCK bytes
+                write!(out, "FreeBlocks:    ")?;
+                for count in free_blocks {
+                    write!(out, " {count}")?;
+                }
+                out.push('\n');
+            }
+            Generated::Cmdline => {
+                out.push_str(param::cmdline());
+                out.push('\n');
+            }
+            Generated::Interrupts => {
+                let harts: Vec<_> = smp::harts()
+                    .iter()
+                    .filter(|hart| hart.is_online())
+                    .collect();
+                write!(out, "    ")?;
+                for hart in &harts {
+                    write!(out, " {:>10}", format!("CPU{}", hart.hartid))?;
+                }
+                out.push('\n');
+                for (cause, name) in INTERRUPT_NAMES {
+                    write!(out, "{cause:>3}:")?;
+                    for hart in &harts {
+                        write!(
+                            out,
+                            " {:>10}",
+                            exceptions::interrupt_count(cause, hart.index)
+                        )?;
+                    }
+                    writeln!(out, "  {name}")?;
+                }
+            }
+            Generated::Mounts => {
+                // filesystems have no source device, their name is shown instead
+                for (path, name) in vfs::mounts() {
+                    writeln!(out, "{name} {path} {name} rw 0 0")?;
+                }
+            }
+            Generated::Status(pid) => {
+                let Some(proc) = proc::find(pid) else {
+                    return Ok(());
+                };
+                writeln!(out, "Pid:\t{}", proc.pid)?;
+                writeln!(out, "State:\t{:?}", proc.state())?;
+                writeln!(out, "PageTable:\t{:#x}", proc.page_table())?;
+                writeln!(out, "KernelStack:\t{:#x}", proc.kernel_stack_top())?;
+            }
+            Generated::Maps(pid) => {
+                let Some(proc) = proc::find(pid) else {
+                    return Ok(());
+                };
+                for mapping in memory::mappings(proc.page_table()) {
+                    let flag = |flag: PageFlag, c: char| {
+                        if mapping.flags.contains(flag) { c } else { '-' }
+                    };
+                    writeln!(
+                        out,
+                        "{:08x}-{:08x} {}{}{}{}{} {:08x}",
+                        mapping.vaddr.start,
+                        mapping.vaddr.end,
+                        flag(PageFlag::Read, 'r'),
+                        flag(PageFlag::Write, 'w'),
+                        flag(PageFlag::Execute, 'x'),
+                        flag(PageFlag::User, 'u'),
+                        flag(PageFlag::Global, 'g'),
+                        mapping.paddr,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProcInode {
+    fn ino(&self) -> u64 {
+        match self {
+            ProcInode::Root => ROOT_INO,
+            ProcInode::File(generated) => generated.ino(),
+            ProcInode::Process(pid) => PROCESS_INO_BASE + *pid as u64 * PROCESS_INO_STRIDE,
+            ProcInode::DeviceTreeNode(path) => {
+                DEVICETREE_INO_BIT | hash_path(path.iter().map(String::as_str))
+            }
+            ProcInode::DeviceTreeProperty(path, name) => {
+                let path = path.iter().map(String::as_str);
+                DEVICETREE_INO_BIT | hash_path(path.chain([":", name.as_str()]))
+            }
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self {
+            ProcInode::File(_) | ProcInode::DeviceTreeProperty(..) => FileType::Regular,
+            _ => FileType::Directory,
+        }
+    }
+
+    /// returns the entries of a directory
+    fn entries(&self) -> vfs::Result<Vec<(String, ProcInode)>> {
+        match self {
+            ProcInode::Root => {
+                let mut entries: Vec<_> = ROOT_FILES
+                    .iter()
+                    .map(|(name, generated)| (name.to_string(), ProcInode::File(*generated)))
+                    .collect();
+                entries.push((
+                    DEVICETREE.to_string(),
+                    ProcInode::DeviceTreeNode(Vec::new()),
+                ));
+                entries.extend(
+                    proc::pids()
+                        .into_iter()
+                        .map(|pid| (pid.to_string(), ProcInode::Process(pid))),
+                );
+                Ok(entries)
+            }
+            ProcInode::Process(pid) => {
+                proc::find(*pid).ok_or(FsError::NotFound)?;
+                Ok(alloc::vec![
+                    ("maps".to_string(), ProcInode::File(Generated::Maps(*pid))),
+                    (
+                        "status".to_string(),
+                        ProcInode::File(Generated::Status(*pid))
+                    ),
+                ])
+            }
+            ProcInode::DeviceTreeNode(path) => with_node(path, |node| {
+                let node = node.ok_or(FsError::NotFound)?;
+                let properties = node.properties().map(|property| {
+                    let name = property.name.to_string();
+                    (
+                        name.clone(),
+                        ProcInode::DeviceTreeProperty(path.clone(), name),
+                    )
+                });
+                let children = node.children().map(|child| {
+                    let mut child_path = path.clone();
+                    child_path.push(child.name.to_string());
+                    (
+                        child.name.to_string(),
+                        ProcInode::DeviceTreeNode(child_path),
+                    )
+                });
+                Ok(properties.chain(children).collect())
+            }),
+            _ => Err(FsError::NotDirectory),
+        }
+    }
+}
+
+impl Inode for ProcInode {
+    fn stat(&self) -> vfs::Result<Stat> {
+        let (mode, size) = match self {
+            // the size of generated files is only known by reading them
+            ProcInode::File(_) => (0o444, 0),
+            ProcInode::DeviceTreeProperty(path, name) => {
+                let size = with_node(path, |node| {
+                    node.and_then(|node| node.property(name))
+                        .map(|property| property.value.len())
+                        .ok_or(FsError::NotFound)
+                })?;
+                (0o444, size as u64)
+            }
+            _ => (0o555, 0),
+        };
+        Ok(Stat {
+            file_type: self.file_type(),
+            mode,
+            ino: self.ino(),
+            nlink: if self.file_type() == FileType::Directory {
+                2
+            } else {
+                1
+            },
+            size,
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        (self.file_type() == FileType::Regular).then_some(self)
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory> {
+        (self.file_type() == FileType::Directory).then_some(self)
+    }
+}
+
+impl File for ProcInode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let copy = |data: &[u8], buf: &mut [u8]| {
+            let start = (offset as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            len
+        };
+        match self {
+            ProcInode::File(generated) => Ok(copy(generated.generate()?.as_bytes(), buf)),
+            ProcInode::DeviceTreeProperty(path, name) => with_node(path, |node| {
+                node.and_then(|node| node.property(name))
+                    .map(|property| copy(property.value, buf))
+                    .ok_or(FsError::NotFound)
+            }),
+            _ => Err(FsError::IsDirectory),
+        }
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> vfs::Result<usize> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+impl Directory for ProcInode {
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        self.entries()?
+            .into_iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, inode)| Arc::new(inode) as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u32) -> vfs::Result<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> vfs::Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&self, _old_name: &str, _new_parent: &dyn Inode, _new_name: &str) -> vfs::Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn readdir(&self, index: usize) -> vfs::Result<Option<DirEntry>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .nth(index)
+            .map(|(name, inode)| DirEntry {
+                name,
+                ino: inode.ino(),
+                file_type: inode.file_type(),
+            }))
+    }
+}
@@ -0,0 +1,383 @@
+use crate::filesystem::vfs::{
+    self, DirEntry, Directory, File, FileType, Filesystem, FsError, Inode, Stat,
+};
+use crate::sync::{Arc, SpinLock};
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Source of inode numbers, shared by every instance so inodes identify their filesystem
+static NEXT_INO: SpinLock<u64> = SpinLock::new(1);
+/// Serializes renames, the only operation holding the locks of several inodes: both parents
+/// and the directory replaced
+static RENAME_LOCK: SpinLock<()> = SpinLock::new(());
+
+fn next_ino() -> u64 {
+    let mut next = NEXT_INO.lock();
+    let ino = *next;
+    *next += 1;
+    ino
+}
+
+enum Data {
+    /// borrows its data from memory that is never freed (e.g. the initrd) until it is written
+    File(Cow<'static, [u8]>),
+    Directory(BTreeMap<String, Arc<RamInode>>),
+    Symlink(String),
+}
+
+/// Bytes of file data held by a filesystem, charged when files grow and released when they shrink
+/// or their inode is freed
+struct Usage {
+    used: SpinLock<usize>,
+    limit: usize,
+}
+
+impl Usage {
+    /// accounts for a file resized from [old] to [new] bytes, failing past the limit
+    fn resize(&self, old: usize, new: usize) -> vfs::Result<()> {
+        let mut used = self.used.lock();
+        if new > old {
+            let grown = *used + (new - old);
+            if grown > self.limit {
+                return Err(FsError::NoSpace);
+            }
+            *used = grown;
+        } else {
+            *used -= old - new;
+        }
+        Ok(())
+    }
+}
+
+pub struct RamInode {
+    ino: u64,
+    /// inode number of the root of the filesystem
+    fs: u64,
+    usage: Arc<Usage>,
+    /// type of [data], which never changes
+    file_type: FileType,
+    mode: u32,
+    data: SpinLock<Data>,
+}
+
+/// Filesystem keeping every file in memory, used for the initramfs and tmpfs
+pub struct RamFs {
+    name: &'static str,
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    pub fn new(name: &'static str) -> Self {
+        Self::with_limit(name, usize::MAX)
+    }
+
+    /// creates a filesystem whose files hold at most [limit] bytes in total
+    pub fn with_limit(name: &'static str, limit: usize) -> Self {
+        let ino = next_ino();
+        Self {
+            name,
+            root: Arc::new(RamInode {
+                ino,
+                fs: ino,
+                usage: Arc::new(Usage {
+                    used: SpinLock::new(0),
+                    limit,
+                }),
+                file_type: FileType::Directory,
+                mode: 0o755,
+                data: SpinLock::new(Data::Directory(BTreeMap::new())),
+            }),
+        }
+    }
+
+    pub fn root_inode(&self) -> &Arc<RamInode> {
+        &self.root
+    }
+}
+
+impl Filesystem for RamFs {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl RamInode {
+    fn new_inode(&self, mode: u32, data: Data) -> vfs::Result<Arc<RamInode>> {
+        let file_type = match data {
+            Data::File(ref data) => {
+                self.usage.resize(0, data.len())?;
+                FileType::Regular
+            }
+            Data::Directory(_) => FileType::Directory,
+            Data::Symlink(_) => FileType::Symlink,
+        };
+        Ok(Arc::new(RamInode {
+            ino: next_ino(),
+            fs: self.fs,
+            usage: self.usage.clone(),
+            file_type,
+            mode,
+            data: SpinLock::new(data),
+        }))
+    }
+
+    /// adds a regular file whose data is borrowed until it is written
+    pub fn insert_file(
+        &self,
+        name: &str,
+        mode: u32,
+        data: &'static [u8],
+    ) -> vfs::Result<Arc<RamInode>> {
+        self.insert(name, self.new_inode(mode, Data::File(Cow::Borrowed(data)))?)
+    }
+
+    /// returns the subdirectory [name]
+    pub fn directory(&self, name: &str) -> vfs::Result<Arc<RamInode>> {
+        let inode = self.lookup_entry(name)?;
+        match inode.file_type {
+            FileType::Directory => Ok(inode),
+            _ => Err(FsError::NotDirectory),
+        }
+    }
+
+    fn lookup_entry(&self, name: &str) -> vfs::Result<Arc<RamInode>> {
+        let data = self.data.lock();
+        let Data::Directory(entries) = &*data else {
+            return Err(FsError::NotDirectory);
+        };
+        entries.get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn insert(&self, name: &str, inode: Arc<RamInode>) -> vfs::Result<Arc<RamInode>> {
+        let mut data = self.data.lock();
+        let Data::Directory(entries) = &mut *data else {
+            return Err(FsError::NotDirectory);
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        entries.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+}
+
+impl Drop for RamInode {
+    fn drop(&mut self) {
+        if let Data::File(data) = self.data.get_mut() {
+            // shrinking never fails
+            let _ = self.usage.resize(data.len(), 0);
+        }
+    }
+}
+
+impl Inode for RamInode {
+    fn stat(&self) -> vfs::Result<Stat> {
+        let data = self.data.lock();
+        let (file_type, size, nlink) = match &*data {
+            Data::File(data) => (FileType::Regular, data.len(), 1),
+            Data::Directory(entries) => {
+                let subdirectories = entries
+                    .values()
+                    .filter(|inode| inode.file_type == FileType::Directory)
+                    .count();
+                (FileType::Directory, 0, 2 + subdirectories)
+            }
+            Data::Symlink(target) => (FileType::Symlink, target.len(), 1),
+        };
+
+        Ok(Stat {
+            file_type,
+            mode: self.mode & 0o7777,
+            ino: self.ino,
+            nlink: nlink as u32,
+            size: size as u64,
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        (self.file_type == FileType::Regular).then_some(self)
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory> {
+        (self.file_type == FileType::Directory).then_some(self)
+    }
+
+    fn readlink(&self) -> vfs::Result<String> {
+        match &*self.data.lock() {
+            Data::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+impl File for RamInode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let data = self.data.lock();
+        let Data::File(data) = &*data else {
+            return Err(FsError::IsDirectory);
+        };
+
+        let offset = usize::try_from(offset).map_err(|_| FsError::InvalidArgument)?;
+        let start = offset.min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let mut data = self.data.lock();
+        let Data::File(data) = &mut *data else {
+            return Err(FsError::IsDirectory);
+        };
+
+        let offset = usize::try_from(offset).map_err(|_| FsError::InvalidArgument)?;
+        let end = offset
+            .checked_add(buf.len())
+            .ok_or(FsError::InvalidArgument)?;
+        let data = data.to_mut();
+        if data.len() < end {
+            self.usage.resize(data.len(), end)?;
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> vfs::Result<()> {
+        let mut data = self.data.lock();
+        let Data::File(data) = &mut *data else {
+            return Err(FsError::IsDirectory);
+        };
+
+        let size = usize::try_from(size).map_err(|_| FsError::InvalidArgument)?;
+        self.usage.resize(data.len(), size)?;
+        if size == 0 {
+            *data = Cow::Owned(Vec::new());
+        } else {
+            data.to_mut().resize(size, 0);
+        }
+        Ok(())
+    }
+}
+
+impl Directory for RamInode {
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        Ok(self.lookup_entry(name)?)
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> vfs::Result<Arc<dyn Inode>> {
+        let data = match file_type {
+            FileType::Regular => Data::File(Cow::Owned(Vec::new())),
+            FileType::Directory => Data::Directory(BTreeMap::new()),
+            _ => return Err(FsError::Unsupported),
+        };
+        Ok(self.insert(name, self.new_inode(mode, data)?)?)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn Inode>> {
+        Ok(self.insert(
+            name,
+            self.new_inode(0o777, Data::Symlink(target.to_string()))?,
+        )?)
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        // only one inode is locked at a time, see [RENAME_LOCK]
+        let inode = self.lookup_entry(name)?;
+        if let Data::Directory(children) = &*inode.data.lock()
+            && !children.is_empty()
+        {
+            return Err(FsError::NotEmpty);
+        }
+
+        let mut data = self.data.lock();
+        let Data::Directory(entries) = &mut *data else {
+            return Err(FsError::NotDirectory);
+        };
+        match entries.get(name) {
+            Some(entry) if Arc::ptr_eq(entry, &inode) => {
+                entries.remove(name);
+                Ok(())
+            }
+            // replaced concurrently
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &dyn Inode, new_name: &str) -> vfs::Result<()> {
+        let new_parent = (new_parent as &dyn core::any::Any)
+            .downcast_ref::<RamInode>()
+            .filter(|new_parent| new_parent.fs == self.fs)
+            .ok_or(FsError::CrossDevice)?;
+
+        let _guard = RENAME_LOCK.lock();
+        let mut old_data = self.data.lock();
+        let mut new_data = if core::ptr::eq(self, new_parent) {
+            None
+        } else {
+            Some(new_parent.data.lock())
+        };
+
+        let Data::Directory(old_entries) = &mut *old_data else {
+            return Err(FsError::NotDirectory);
+        };
+        let source = old_entries.get(old_name).ok_or(FsError::NotFound)?.clone();
+        let new_entries = match new_data.as_deref_mut() {
+            None => old_entries,
+            Some(Data::Directory(new_entries)) => new_entries,
+            Some(_) => return Err(FsError::NotDirectory),
+        };
+
+        if let Some(existing) = new_entries.get(new_name) {
+            if Arc::ptr_eq(existing, &source) {
+                return Ok(());
+            }
+            // a parent is not empty, and locking it again would deadlock
+            if core::ptr::eq(&**existing, self) || core::ptr::eq(&**existing, new_parent) {
+                return Err(FsError::NotEmpty);
+            }
+            match (source.file_type, existing.file_type) {
+                (FileType::Directory, FileType::Directory) => {
+                    if matches!(&*existing.data.lock(), Data::Directory(entries) if !entries.is_empty())
+                    {
+                        return Err(FsError::NotEmpty);
+                    }
+                }
+                (FileType::Directory, _) => return Err(FsError::NotDirectory),
+                (_, FileType::Directory) => return Err(FsError::IsDirectory),
+                _ => {}
+            }
+        }
+        new_entries.insert(new_name.to_string(), source);
+
+        // the source entry may live in the map borrowed as [new_entries]
+        drop(new_data);
+        let Data::Directory(old_entries) = &mut *old_data else {
+            unreachable!();
+        };
+        if core::ptr::eq(self, new_parent) && old_name == new_name {
+            return Ok(());
+        }
+        old_entries.remove(old_name);
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> vfs::Result<Option<DirEntry>> {
+        let data = self.data.lock();
+        let Data::Directory(entries) = &*data else {
+            return Err(FsError::NotDirectory);
+        };
+
+        Ok(entries.iter().nth(index).map(|(name, inode)| DirEntry {
+            name: name.clone(),
+            ino: inode.ino,
+            file_type: inode.file_type,
+        }))
+    }
+}
@@ -0,0 +1,13 @@
+use crate::filesystem::ramfs::RamFs;
+use crate::memory;
+
+crate::kernel_param!(
+    /// size limit of a tmpfs in bytes (`tmpfs.size=`), half of the memory by default
+    pub static SIZE: Option<usize> = "tmpfs.size", None
+);
+
+/// returns an empty tmpfs, a [RamFs] whose files hold at most [SIZE] bytes
+pub fn new() -> RamFs {
+    let limit = SIZE.get().unwrap_or_else(|| memory::get_region().size / 2);
+    RamFs::with_limit("tmpfs", limit)
+}
@@ -0,0 +1,508 @@
+use crate::filesystem::cache;
+use crate::sync::{Arc, SpinLock};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::any::Any;
+
+/// Maximum number of symlinks followed while resolving a single path
+const MAX_SYMLINKS: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    AlreadyExists,
+    NotEmpty,
+    /// the path is empty or names the root where a parent is required
+    InvalidPath,
+    InvalidArgument,
+    /// the file was not opened for the operation
+    BadMode,
+    ReadOnly,
+    NoSpace,
+    /// the operation spans two filesystems
+    CrossDevice,
+    /// the path is a mount point or has filesystems mounted below it
+    Busy,
+    TooManySymlinks,
+    /// the data on the device is inconsistent
+    Corrupted,
+    Io,
+    Unsupported,
+}
+
+pub type Result<T> = core::result::Result<T, FsError>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stat {
+    pub file_type: FileType,
+    /// permission bits
+    pub mode: u32,
+    pub ino: u64,
+    pub nlink: u32,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub file_type: FileType,
+}
+
+/// Object of a filesystem, implementing [File] and/or [Directory] depending on its type
+pub trait Inode: Any + Send + Sync {
+    fn stat(&self) -> Result<Stat>;
+
+    fn as_file(&self) -> Option<&dyn File> {
+        None
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory> {
+        None
+    }
+
+    /// returns the target of a symlink
+    fn readlink(&self) -> Result<String> {
+        Err(FsError::InvalidArgument)
+    }
+}
+
+/// Content of a regular file or device
+pub trait File {
+    /// reads at most `buf.len()` bytes at [offset], returning 0 at the end of the file
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+
+    /// writes [buf] at [offset], extending the file if needed
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize>;
+
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    /// writes cached data of the file back to the device
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub trait Directory {
+    /// returns the entry [name], which is never `.` or `..`
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>>;
+
+    /// creates an empty regular file or directory [name]
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> Result<Arc<dyn Inode>>;
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>> {
+        Err(FsError::Unsupported)
+    }
+
+    /// removes the file or empty directory [name]
+    fn unlink(&self, name: &str) -> Result<()>;
+
+    /// moves the entry [old_name] to [new_name] in [new_parent], replacing an existing file
+    ///
+    /// [new_parent] is a directory of the same mount, implementations downcast it to their inode
+    /// type and fail with [FsError::CrossDevice] if that is not possible.
+    fn rename(&self, old_name: &str, new_parent: &dyn Inode, new_name: &str) -> Result<()>;
+
+    /// returns the [index]th entry, none past the last one
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>>;
+}
+
+pub trait Filesystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// writes every cached change back to the device
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    /// normalized absolute path
+    path: String,
+    fs: Arc<dyn Filesystem>,
+}
+
+static MOUNTS: SpinLock<Vec<Mount>> = SpinLock::new(Vec::new());
+
+/// returns the components of [path] with `.` and `..` resolved lexically, relative paths are
+/// relative to the root
+fn components(path: &str) -> Result<Vec<&str>> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    Ok(components)
+}
+
+/// returns [path] as normalized absolute path
+pub fn normalize(path: &str) -> Result<String> {
+    Ok(format!("/{}", components(path)?.join("/")))
+}
+
+fn mounted_at(path: &str) -> Option<Arc<dyn Filesystem>> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|mount| mount.path == path)
+        .map(|mount| mount.fs.clone())
+}
+
+/// returns the path of the mount containing the normalized [path]
+fn mount_of(path: &str) -> String {
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter(|mount| {
+            mount.path == "/"
+                || path == mount.path
+                || path
+                    .strip_prefix(mount.path.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .max_by_key(|mount| mount.path.len())
+        .map(|mount| mount.path.clone())
+        .unwrap_or_default()
+}
+
+/// returns the inode at [path], following a symlink in the last component if [follow] is set
+pub fn resolve(path: &str, follow: bool) -> Result<Arc<dyn Inode>> {
+    let mut path = normalize(path)?;
+
+    'restart: for _ in 0..=MAX_SYMLINKS {
+        let mut inode = mounted_at("/").ok_or(FsError::NotFound)?.root();
+        let mut current = String::new();
+
+        let components = components(&path)?;
+        for (index, &component) in components.iter().enumerate() {
+            let directory = inode.as_directory().ok_or(FsError::NotDirectory)?;
+            let mut next = directory.lookup(component)?;
+
+            let parent_len = current.len();
+            current.push('/');
+            current.push_str(component);
+            if let Some(fs) = mounted_at(&current) {
+                next = fs.root();
+            }
+
+            let last = index + 1 == components.len();
+            if (!last || follow) && next.stat()?.file_type == FileType::Symlink {
+                let target = next.readlink()?;
+                let rest = components[index + 1..].join("/");
+                let base = if target.starts_with('/') {
+                    ""
+                } else {
+                    &current[..parent_len]
+                };
+                let resolved = normalize(&format!("{base}/{target}/{rest}"))?;
+                path = resolved;
+                continue 'restart;
+            }
+            inode = next;
+        }
+        return Ok(inode);
+    }
+
+    Err(FsError::TooManySymlinks)
+}
+
+/// returns the directory containing [path] and the last component of [path]
+fn resolve_parent(path: &str) -> Result<(Arc<dyn Inode>, String, String)> {
+    let path = normalize(path)?;
+    let (parent, name) = path.rsplit_once('/').ok_or(FsError::InvalidPath)?;
+    if name.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+
+    let parent_inode = resolve(if parent.is_empty() { "/" } else { parent }, true)?;
+    if parent_inode.as_directory().is_none() {
+        return Err(FsError::NotDirectory);
+    }
+    Ok((parent_inode, String::from(name), path.clone()))
+}
+
+/// mounts [fs] at the directory [path], `/` mounts the root filesystem
+pub fn mount(path: &str, fs: Arc<dyn Filesystem>) -> Result<()> {
+    let path = normalize(path)?;
+    if path != "/" && resolve(&path, true)?.as_directory().is_none() {
+        return Err(FsError::NotDirectory);
+    }
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    crate::println!("vfs: mounted {} at {path}", fs.name());
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// syncs and unmounts the filesystem mounted at [path]
+pub fn umount(path: &str) -> Result<()> {
+    let path = normalize(path)?;
+    let fs = {
+        let mounts = MOUNTS.lock();
+        let below = |mount: &&Mount| {
+            mount
+                .path
+                .strip_prefix(path.as_str())
+                .is_some_and(|rest| rest.starts_with('/') || (path == "/" && !rest.is_empty()))
+        };
+        if mounts.iter().any(|mount| below(&mount)) {
+            return Err(FsError::Busy);
+        }
+        mounts
+            .iter()
+            .find(|mount| mount.path == path)
+            .ok_or(FsError::InvalidArgument)?
+            .fs
+            .clone()
+    };
+
+    fs.sync()?;
+    MOUNTS.lock().retain(|mount| mount.path != path);
+    Ok(())
+}
+
+/// returns the mount points and filesystem names
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.path.clone(), mount.fs.name()))
+        .collect()
+}
+
+/// syncs every mounted filesystem, then the buffer cache
+pub fn sync() -> Result<()> {
+    let filesystems: Vec<_> = MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    filesystems.iter().try_for_each(|fs| fs.sync())?;
+    cache::sync()
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct OpenFlags: u32 {
+        const Read = 1 << 0;
+        const Write = 1 << 1;
+        /// create the file if it does not exist
+        const Create = 1 << 2;
+        /// fail if [OpenFlags::Create] is set and the file exists
+        const Exclusive = 1 << 3;
+        const Truncate = 1 << 4;
+        /// write at the end of the file
+        const Append = 1 << 5;
+        /// fail if the path is not a directory
+        const Directory = 1 << 6;
+
+        const ReadWrite = Self::Read.bits() | Self::Write.bits();
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Open file with its offset and flags
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    /// not held during I/O, concurrent reads of a shared file may use the same offset
+    offset: SpinLock<u64>,
+}
+
+/// opens [path], creating a regular file with [mode] if [OpenFlags::Create] is set
+pub fn open(path: &str, flags: OpenFlags, mode: u32) -> Result<OpenFile> {
+    let inode = if flags.contains(OpenFlags::Create) {
+        let (parent, name, _) = resolve_parent(path)?;
+        let directory = parent.as_directory().ok_or(FsError::NotDirectory)?;
+        match directory.lookup(&name) {
+            Ok(_) if flags.contains(OpenFlags::Exclusive) => return Err(FsError::AlreadyExists),
+            Ok(_) => resolve(path, true)?,
+            Err(FsError::NotFound) => directory.create(&name, FileType::Regular, mode)?,
+            Err(err) => return Err(err),
+        }
+    } else {
+        resolve(path, true)?
+    };
+
+    let is_directory = inode.as_directory().is_some();
+    if flags.contains(OpenFlags::Directory) && !is_directory {
+        return Err(FsError::NotDirectory);
+    }
+    if flags.contains(OpenFlags::Write) && is_directory {
+        return Err(FsError::IsDirectory);
+    }
+    if flags.contains(OpenFlags::Truncate) && flags.contains(OpenFlags::Write) {
+        inode
+            .as_file()
+            .ok_or(FsError::InvalidArgument)?
+            .truncate(0)?;
+    }
+
+    Ok(OpenFile {
+        inode,
+        flags,
+        offset: SpinLock::new(0),
+    })
+}
+
+impl OpenFile {
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    fn file(&self) -> Result<&dyn File> {
+        self.inode.as_file().ok_or(FsError::IsDirectory)
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::Read) {
+            return Err(FsError::BadMode);
+        }
+
+        let offset = *self.offset.lock();
+        let read = self.file()?.read_at(offset, buf)?;
+        *self.offset.lock() = offset + read as u64;
+        Ok(read)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::Write) {
+            return Err(FsError::BadMode);
+        }
+
+        let offset = if self.flags.contains(OpenFlags::Append) {
+            self.inode.stat()?.size
+        } else {
+            *self.offset.lock()
+        };
+        let written = self.file()?.write_at(offset, buf)?;
+        *self.offset.lock() = offset + written as u64;
+        Ok(written)
+    }
+
+    /// moves the offset, returning the new one
+    pub fn seek(&self, position: SeekFrom) -> Result<u64> {
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(delta) => (*self.offset.lock(), delta),
+            SeekFrom::End(delta) => (self.inode.stat()?.size, delta),
+        };
+        let offset = base
+            .checked_add_signed(delta)
+            .ok_or(FsError::InvalidArgument)?;
+        *self.offset.lock() = offset;
+        Ok(offset)
+    }
+
+    pub fn stat(&self) -> Result<Stat> {
+        self.inode.stat()
+    }
+
+    /// returns the next entry of a directory, using the offset as entry index
+    pub fn readdir(&self) -> Result<Option<DirEntry>> {
+        let directory = self.inode.as_directory().ok_or(FsError::NotDirectory)?;
+        let index = *self.offset.lock();
+        let entry = directory.readdir(index as usize)?;
+        if entry.is_some() {
+            *self.offset.lock() = index + 1;
+        }
+        Ok(entry)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.file()?.sync()
+    }
+}
+
+/// returns the status of [path], following symlinks
+pub fn stat(path: &str) -> Result<Stat> {
+    resolve(path, true)?.stat()
+}
+
+pub fn readlink(path: &str) -> Result<String> {
+    resolve(path, false)?.readlink()
+}
+
+pub fn mkdir(path: &str, mode: u32) -> Result<()> {
+    let (parent, name, _) = resolve_parent(path)?;
+    let directory = parent.as_directory().ok_or(FsError::NotDirectory)?;
+    directory.create(&name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<()> {
+    let (parent, name, _) = resolve_parent(path)?;
+    let directory = parent.as_directory().ok_or(FsError::NotDirectory)?;
+    directory.symlink(&name, target)?;
+    Ok(())
+}
+
+/// removes the file or empty directory [path]
+pub fn unlink(path: &str) -> Result<()> {
+    let (parent, name, path) = resolve_parent(path)?;
+    if mounted_at(&path).is_some() {
+        return Err(FsError::Busy);
+    }
+    parent
+        .as_directory()
+        .ok_or(FsError::NotDirectory)?
+        .unlink(&name)
+}
+
+pub fn rename(old_path: &str, new_path: &str) -> Result<()> {
+    let (old_parent, old_name, old_path) = resolve_parent(old_path)?;
+    let (new_parent, new_name, new_path) = resolve_parent(new_path)?;
+    if mounted_at(&old_path).is_some() || mounted_at(&new_path).is_some() {
+        return Err(FsError::Busy);
+    }
+    if mount_of(&old_path) != mount_of(&new_path) {
+        return Err(FsError::CrossDevice);
+    }
+    // a directory cannot be moved into itself
+    if new_path
+        .strip_prefix(old_path.as_str())
+        .is_some_and(|rest| rest.starts_with('/'))
+    {
+        return Err(FsError::InvalidArgument);
+    }
+
+    old_parent
+        .as_directory()
+        .ok_or(FsError::NotDirectory)?
+        .rename(&old_name, &*new_parent, &new_name)
+}
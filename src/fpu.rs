@@ -0,0 +1,298 @@
+use crate::arch::REGBYTES;
+use crate::cpu::{self, Feature};
+use crate::smp::{self, MAX_HARTS};
+use crate::{asm_define_macros, asm_purge_macros, println};
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use riscv::register::sstatus::{self, FS};
+
+/// Floating-point extension implemented by every hart
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Extension {
+    None,
+    /// single precision
+    F,
+    /// double precision (implies F)
+    D,
+}
+
+static mut EXTENSION: Extension = Extension::None;
+
+/// Context whose state the floating-point registers of each hart hold, indexed by the logical
+/// index of the hart
+static LOADED: [AtomicPtr<FpContext>; MAX_HARTS] = [LOADED_INIT; MAX_HARTS];
+
+/// workaround for [https://github.com/rust-lang/rust/issues/44796]
+#[allow(clippy::declare_interior_mutable_const)]
+const LOADED_INIT: AtomicPtr<FpContext> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Position of the `FS` field in `sstatus`
+const SSTATUS_FS: usize = 13;
+
+/// Floating-point register state of a process
+///
+/// The kernel itself never uses floating-point registers, so the state is not part of the
+/// `TrapFrame`. It is saved on the traps taken after the process touched the registers, and
+/// only restored on the first floating-point instruction the process executes on a hart whose
+/// registers hold another state.
+#[repr(C)]
+pub struct FpContext {
+    /// `f0`-`f31`, holding the lower 32 bits only when D is not implemented
+    registers: [u64; 32],
+    fcsr: usize,
+    /// whether [registers] and [fcsr] hold state saved from the process
+    used: bool,
+    /// logical index of the hart the state was last restored on, see [LOADED]
+    hart: usize,
+}
+
+impl FpContext {
+    pub const fn new() -> Self {
+        Self {
+            registers: [0; 32],
+            fcsr: 0,
+            used: false,
+            hart: usize::MAX,
+        }
+    }
+
+    /// returns whether the registers of the current hart hold the state
+    fn is_loaded(&self) -> bool {
+        let hart = smp::current().index;
+        // the state may have been restored on another hart since it was loaded on this one
+        self.hart == hart && core::ptr::eq(LOADED[hart].load(Ordering::Relaxed), self)
+    }
+}
+
+/// enables lazy floating-point switching if every hart implements F or D
+pub fn initialize() {
+    let extension = if cpu::has_feature(Feature::D) {
+        Extension::D
+    } else if cpu::has_feature(Feature::F) {
+        Extension::F
+    } else {
+        Extension::None
+    };
+
+    unsafe { EXTENSION = extension };
+    initialize_hart();
+    println!("fpu: {extension:?}");
+}
+
+/// disables the floating-point registers of the calling hart to trap their first use
+pub fn initialize_hart() {
+    unsafe { sstatus::set_fs(FS::Off) };
+}
+
+fn is_available() -> bool {
+    unsafe { EXTENSION != Extension::None }
+}
+
+/// saves the state of the process owning [context] if it touched the registers since the last
+/// save, returning the `sstatus` of the trap frame to restore
+///
+/// Called on every trap from U-mode with the `sstatus` saved by the trap, the process may be
+/// switched out before it returns.
+pub fn trap_entry(context: &mut FpContext, sstatus: usize) -> usize {
+    if !is_available() || (sstatus >> SSTATUS_FS) & 0b11 != FS::Dirty as usize {
+        return sstatus;
+    }
+
+    save(context);
+    with_fs(sstatus, FS::Clean)
+}
+
+/// returns the `sstatus` to return to U-mode with for the process owning [context]
+///
+/// The registers are disabled unless they still hold the state of the process, so its next
+/// floating-point instruction traps to [handle_illegal_instruction].
+pub fn trap_return(context: &FpContext, sstatus: usize) -> usize {
+    if !is_available() || context.is_loaded() {
+        return sstatus;
+    }
+
+    with_fs(sstatus, FS::Off)
+}
+
+/// returns [sstatus] with its `FS` field set to [fs]
+///
+/// `Sstatus::from_bits` would drop the fields the `riscv` crate does not know of.
+fn with_fs(sstatus: usize, fs: FS) -> usize {
+    (sstatus & !(0b11 << SSTATUS_FS)) | (fs as usize) << SSTATUS_FS
+}
+
+fn save(context: &mut FpContext) {
+    unsafe {
+        // the registers must be accessible while saving
+        sstatus::set_fs(FS::Clean);
+        match EXTENSION {
+            Extension::D => save_d(context),
+            Extension::F => save_f(context),
+            Extension::None => return,
+        }
+        sstatus::set_fs(FS::Off);
+    }
+    context.used = true;
+}
+
+fn restore(context: &mut FpContext) {
+    unsafe {
+        // the registers must be accessible while restoring
+        sstatus::set_fs(FS::Initial);
+        match EXTENSION {
+            Extension::D => restore_d(context),
+            Extension::F => restore_f(context),
+            Extension::None => return,
+        }
+        sstatus::set_fs(FS::Clean);
+    }
+
+    let hart = smp::current().index;
+    context.hart = hart;
+    LOADED[hart].store(context, Ordering::Relaxed);
+}
+
+/// handles an illegal instruction trap with the faulting instruction [stval] and the saved
+/// [sstatus] of the process owning [context]
+///
+/// Returns the `sstatus` to return to the process with if the instruction is a floating-point
+/// instruction that trapped because `FS` is Off, in which case the state of [context] (or the
+/// zeroed initial state) is loaded and the instruction can be retried.
+pub fn handle_illegal_instruction(
+    context: &mut FpContext,
+    sstatus: usize,
+    stval: usize,
+) -> Option<usize> {
+    if !is_available() || (sstatus >> SSTATUS_FS) & 0b11 != FS::Off as usize {
+        return None;
+    }
+
+    // some implementations do not report the instruction, retry it once with FS enabled
+    if stval != 0 && !is_fp_instruction(stval as u32) {
+        return None;
+    }
+
+    if !context.used {
+        *context = FpContext::new();
+        context.used = true;
+    }
+    restore(context);
+    Some(with_fs(sstatus, FS::Clean))
+}
+
+fn is_fp_instruction(instruction: u32) -> bool {
+    const OPCODE_LOAD_FP: u32 = 0x07;
+    const OPCODE_STORE_FP: u32 = 0x27;
+    const OPCODE_FMADD: u32 = 0x43;
+    const OPCODE_FMSUB: u32 = 0x47;
+    const OPCODE_FNMSUB: u32 = 0x4b;
+    const OPCODE_FNMADD: u32 = 0x4f;
+    const OPCODE_OP_FP: u32 = 0x53;
+    const OPCODE_SYSTEM: u32 = 0x73;
+
+    // compressed instructions
+    if instruction & 0b11 != 0b11 {
+        let quadrant = instruction & 0b11;
+        let funct3 = (instruction >> 13) & 0b111;
+        return match quadrant {
+            // c.fld, c.fsd, (rv32) c.flw, c.fsw
+            0b00 | 0b10 => {
+                matches!(funct3, 0b001 | 0b101)
+                    || (cfg!(target_arch = "riscv32") && matches!(funct3, 0b011 | 0b111))
+            }
+            _ => false,
+        };
+    }
+
+    match instruction & 0x7f {
+        OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_FMADD | OPCODE_FMSUB | OPCODE_FNMSUB
+        | OPCODE_FNMADD | OPCODE_OP_FP => true,
+        OPCODE_SYSTEM => {
+            // csr instructions on fflags, frm and fcsr
+            let funct3 = (instruction >> 12) & 0b111;
+            let csr = instruction >> 20;
+            funct3 != 0 && (1..=3).contains(&csr)
+        }
+        _ => false,
+    }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn save_d(context: *mut FpContext) {
+    naked_asm!(
+        asm_define_macros!(),
+        "
+        .option push
+        .option arch, +d
+        .irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+            fsd f\\n, \\n * 8(a0)
+        .endr
+        frcsr t0
+        STORE t0, {fcsr}, a0
+        .option pop
+        ret
+        ",
+        asm_purge_macros!(),
+        fcsr = const core::mem::offset_of!(FpContext, fcsr) / REGBYTES,
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn restore_d(context: *const FpContext) {
+    naked_asm!(
+        asm_define_macros!(),
+        "
+        .option push
+        .option arch, +d
+        .irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+            fld f\\n, \\n * 8(a0)
+        .endr
+        LOAD t0, {fcsr}, a0
+        fscsr t0
+        .option pop
+        ret
+        ",
+        asm_purge_macros!(),
+        fcsr = const core::mem::offset_of!(FpContext, fcsr) / REGBYTES,
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn save_f(context: *mut FpContext) {
+    naked_asm!(
+        asm_define_macros!(),
+        "
+        .option push
+        .option arch, +f
+        .irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+            fsw f\\n, \\n * 8(a0)
+        .endr
+        frcsr t0
+        STORE t0, {fcsr}, a0
+        .option pop
+        ret
+        ",
+        asm_purge_macros!(),
+        fcsr = const core::mem::offset_of!(FpContext, fcsr) / REGBYTES,
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn restore_f(context: *const FpContext) {
+    naked_asm!(
+        asm_define_macros!(),
+        "
+        .option push
+        .option arch, +f
+        .irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+            flw f\\n, \\n * 8(a0)
+        .endr
+        LOAD t0, {fcsr}, a0
+        fscsr t0
+        .option pop
+        ret
+        ",
+        asm_purge_macros!(),
+        fcsr = const core::mem::offset_of!(FpContext, fcsr) / REGBYTES,
+    )
+}
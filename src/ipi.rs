@@ -0,0 +1,174 @@
+use crate::driver::ProbeError;
+use crate::smp::{self, MAX_HARTS};
+use crate::{println, sbi};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use macros::driver;
+
+/// Pending cross-calls indexed by `[target][sender]` hart index, holding the function pointer to
+/// run or `0` when the slot is free
+///
+/// Each sender owns a single slot per target and waits for it to be cleared, so no
+/// read-modify-write atomics are required.
+static CALLS: [[AtomicUsize; MAX_HARTS]; MAX_HARTS] = [CALL_ROW_INIT; MAX_HARTS];
+
+/// workaround for [https://github.com/rust-lang/rust/issues/44796]
+#[allow(clippy::declare_interior_mutable_const)]
+const CALL_INIT: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const CALL_ROW_INIT: [AtomicUsize; MAX_HARTS] = [CALL_INIT; MAX_HARTS];
+
+/// enables supervisor software interrupts on the calling hart
+pub fn initialize_hart() {
+    unsafe {
+        riscv::register::sie::set_ssoft();
+        riscv::register::sstatus::set_sie();
+    }
+}
+
+/// enables the software interrupts of the boot hart, the other harts enable theirs as they come
+/// online
+#[driver(name = "ipi", level = IrqChip)]
+fn probe() -> Result<(), ProbeError> {
+    initialize_hart();
+    Ok(())
+}
+
+/// returns the mask of every online hart except the calling one
+pub fn other_harts() -> usize {
+    let current = smp::current();
+    smp::harts()
+        .iter()
+        .filter(|hart| hart.is_online() && hart.index != current.index)
+        .fold(0, |mask, hart| mask | 1 << hart.index)
+}
+
+/// returns the SBI hart mask and its base of the harts of [mask] (bit n = hart index n)
+///
+/// [smp::initialize] only keeps harts whose hartids fit in a mask relative to the lowest one.
+fn sbi_hart_mask(mask: usize) -> (usize, usize) {
+    let base = smp::hartid_base();
+    let hart_mask = smp::harts()
+        .iter()
+        .filter(|hart| mask & (1 << hart.index) != 0)
+        .fold(0, |hart_mask, hart| hart_mask | 1 << (hart.hartid - base));
+    (hart_mask, base)
+}
+
+/// runs [f] on every online hart of [mask] (bit n = hart index n) and waits until all of them
+/// have returned
+///
+/// The calling hart runs [f] directly when it is part of the mask.
+pub fn run_on(mask: usize, f: fn()) {
+    let current = smp::current();
+
+    let mut sent = 0;
+    for hart in smp::harts() {
+        if mask & (1 << hart.index) == 0 || !hart.is_online() || hart.index == current.index {
+            continue;
+        }
+
+        CALLS[hart.index][current.index].store(f as usize, Ordering::Release);
+        sent |= 1 << hart.index;
+    }
+
+    if sent != 0 {
+        let (hart_mask, base) = sbi_hart_mask(sent);
+        let result = sbi::send_ipi(hart_mask, base);
+        if result.error != 0 {
+            panic!(
+                "failed to send ipi to {hart_mask:#b} (base={base}, error={})",
+                result.error as isize
+            );
+        }
+    }
+
+    if mask & (1 << current.index) != 0 {
+        f();
+    }
+
+    for hart in smp::harts() {
+        if sent & (1 << hart.index) == 0 {
+            continue;
+        }
+
+        while CALLS[hart.index][current.index].load(Ordering::Acquire) != 0 {
+            // serve calls of other harts meanwhile, which may be waiting for this hart
+            run_pending();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// handles a supervisor software interrupt
+pub fn handle() {
+    unsafe { riscv::register::sip::clear_ssoft() };
+    run_pending();
+}
+
+fn run_pending() {
+    let current = smp::current();
+    for slot in &CALLS[current.index] {
+        let f = slot.load(Ordering::Acquire);
+        if f == 0 {
+            continue;
+        }
+
+        let f: fn() = unsafe { core::mem::transmute(f) };
+        f();
+        slot.store(0, Ordering::Release);
+    }
+}
+
+fn flush_local_all() {
+    riscv::asm::sfence_vma_all();
+}
+
+/// flushes the translations of [vaddr, vaddr + size) on every online hart
+pub fn shootdown(vaddr: usize, size: usize) {
+    riscv::asm::sfence_vma(0, vaddr);
+
+    let others = other_harts();
+    if others == 0 {
+        return;
+    }
+
+    if !sbi::probe_extension(sbi::EID_RFENCE) {
+        run_on(others, flush_local_all);
+        return;
+    }
+
+    let (hart_mask, base) = sbi_hart_mask(others);
+    let result = sbi::remote_sfence_vma(hart_mask, base, vaddr, size);
+    if result.error != 0 {
+        println!(
+            "ipi: remote_sfence_vma failed (error={}), flushing all",
+            result.error as isize
+        );
+        run_on(others, flush_local_all);
+    }
+}
+
+/// flushes the translations of [vaddr, vaddr + size) tagged with [asid] on every online hart
+pub fn shootdown_asid(vaddr: usize, size: usize, asid: usize) {
+    riscv::asm::sfence_vma(asid, vaddr);
+
+    let others = other_harts();
+    if others == 0 {
+        return;
+    }
+
+    if !sbi::probe_extension(sbi::EID_RFENCE) {
+        run_on(others, flush_local_all);
+        return;
+    }
+
+    let (hart_mask, base) = sbi_hart_mask(others);
+    let result = sbi::remote_sfence_vma_asid(hart_mask, base, vaddr, size, asid);
+    if result.error != 0 {
+        println!(
+            "ipi: remote_sfence_vma_asid failed (error={}), flushing all",
+            result.error as isize
+        );
+        run_on(others, flush_local_all);
+    }
+}
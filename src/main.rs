@@ -1,30 +1,41 @@
 #![no_std]
 #![no_main]
-#![feature(fn_align)]
+#![feature(coerce_unsized)]
 #![feature(naked_functions_rustic_abi)]
-#![feature(range_into_bounds)]
+#![feature(unsize)]
 
 extern crate alloc;
 
 mod allocator;
 mod arch;
+mod backtrace;
+mod cpu;
+mod driver;
 mod dtb;
+mod elf;
 mod exceptions;
 mod filesystem;
+mod fpu;
+mod ipi;
 mod memory;
 mod paging;
+mod param;
+mod power;
 mod proc;
 mod proc2;
+mod random;
 mod sbi;
+mod smp;
+mod sync;
+mod syscall;
+mod timer;
 mod util;
+mod virtio;
 
 use crate::allocator::BuddyAllocator;
-use crate::filesystem::virtio;
-use alloc::string::String;
-use alloc::vec;
-use core::arch::asm;
+use crate::memory::PAddr;
+use core::arch::naked_asm;
 use core::panic::PanicInfo;
-use riscv::register::stvec::Stvec;
 
 #[macro_export]
 macro_rules! ld_variable {
@@ -44,6 +55,15 @@ unsafe extern "C" {
 
 static mut KERNEL_HEAP: *mut u8 = core::ptr::null_mut();
 
+kernel_param!(
+    /// path of the first user program (`init=`)
+    static INIT: &str = "init", "/init"
+);
+kernel_param!(
+    /// exits with success once the kernel is initialized, for automated boot tests
+    static SELFTEST: bool = "selftest", false
+);
+
 unsafe fn kernel_heap_init() {
     unsafe { KERNEL_HEAP = ld_variable!(__kernel_heap, u8) as *mut u8 }
 }
@@ -69,25 +89,53 @@ fn kernel_heap_available() -> usize {
     unsafe { ld_variable!(__kernel_heap_end, u8) - (KERNEL_HEAP as usize) }
 }
 
+/// Information handed over by the firmware to [kernel_main]
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo {
+    /// hartid of the boot hart
+    pub hartid: usize,
+    /// physical address of the flattened device tree
+    pub dtb: PAddr,
+}
+
+/// Entrypoint of the kernel, called by the firmware with `a0 = hartid` and `a1 = dtb`
+///
+/// Sets up the boot stack and clears `.bss` without touching `a0` and `a1`, which are passed
+/// through to [kernel_main].
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".text.boot")]
-unsafe extern "C" fn boot() {
-    unsafe {
-        dtb::update_fdt_address();
-
-        // reset stack top and jump to kernal_main
-        asm!(
-        "mv sp, {stack_top}
-            j {kernel_main}",
-        stack_top = in(reg) &__stack_top,
+#[unsafe(naked)]
+unsafe extern "C" fn boot() -> ! {
+    naked_asm!(
+        "
+        la sp, __stack_top
+
+        la t0, __bss
+        la t1, __bss_end
+    1:
+        bgeu t0, t1, 2f
+        sb zero, 0(t0)
+        addi t0, t0, 1
+        j 1b
+    2:
+        // terminate the frame pointer chain for backtraces
+        mv s0, zero
+        mv ra, zero
+        j {kernel_main}
+        ",
         kernel_main = sym kernel_main,
-        );
-    }
+    )
 }
 
-unsafe fn kernel_main() -> ! {
+unsafe extern "C" fn kernel_main(hartid: usize, dtb: usize) -> ! {
+    let boot_info = BootInfo {
+        hartid,
+        dtb: PAddr(dtb),
+    };
+
     unsafe {
         println!("kernel is initializing");
+        println!("boot info {boot_info:x?}");
 
         println!("__kernel_base {:#x}", ld_variable!(__kernel_base, u8));
         println!("__stack_top {:#x}", ld_variable!(__stack_top, usize));
@@ -99,21 +147,17 @@ unsafe fn kernel_main() -> ! {
             ld_variable!(__kernel_heap_end, u8)
         );
 
-        // init bss
-        core::ptr::write_bytes(
-            &__bss as *const u8 as *mut u8,
-            0,
-            (__bss_end - __bss) as usize,
-        );
-
         // init kernel heap
         kernel_heap_init();
 
         // initialize trap handler
-        riscv::register::stvec::write(Stvec::from_bits(exceptions::exception_entrypoint as usize));
+        exceptions::initialize_hart();
 
-        dtb::load_fdt();
+        dtb::load_fdt(boot_info.dtb);
+        param::initialize();
         memory::set_region_from_fdt();
+        cpu::initialize();
+        fpu::initialize();
 
         // init allocator
         let required_heap = BuddyAllocator::get_required_heap(memory::get_region().size);
@@ -130,13 +174,34 @@ unsafe fn kernel_main() -> ! {
             &mut *core::ptr::slice_from_raw_parts_mut(allocator_heap, required_heap),
         );
 
-        virtio::initialize();
+        // bring up secondary harts
+        smp::initialize(boot_info.hartid);
+
+        driver::initialize();
+        filesystem::cache::initialize();
+        filesystem::mount_root();
 
         println!("kernel has been initialized");
         println!("kernel heap: {} available", kernel_heap_available());
         println!("global heap region {:x?}", memory::get_region());
 
+        if SELFTEST.get() {
+            println!("selftest: boot completed");
+            power::exit(0);
+        }
+
+        match proc::run_init(INIT.get()) {
+            Ok(status) => {
+                println!("init: exited with status {status}");
+                power::exit(status as u16);
+            }
+            Err(err) => {
+                println!("init: failed to start {} ({err:?})", INIT.get());
+            }
+        }
+
         loop {
+            timer::run_deferred();
             riscv::asm::wfi();
         }
     }
@@ -146,5 +211,18 @@ unsafe fn kernel_main() -> ! {
 pub fn panic_handler(info: &PanicInfo) -> ! {
     println!("kernel panicked: {info}");
 
-    loop {}
+    // do not try to reset again if resetting panicked, nor while another hart is resetting
+    if sync::test_and_set(&power::PANICKING) {
+        power::halt();
+    }
+
+    if !backtrace::trap_printed() {
+        backtrace::print();
+    }
+
+    match power::panic_action() {
+        power::PanicAction::Shutdown => power::shutdown(power::ResetReason::Failure),
+        power::PanicAction::Halt => power::halt(),
+        power::PanicAction::Reboot => power::reboot(),
+    }
 }
@@ -1,5 +1,8 @@
+use crate::arch::{Paging, PagingMode};
 use crate::{__kernel_base, __stack_top, ld_variable};
+use alloc::vec::Vec;
 use bitflags::bitflags;
+use core::alloc::Layout;
 use core::fmt::{Formatter, LowerHex, UpperHex};
 use core::ops::{Add, AddAssign, Range};
 use core::ptr;
@@ -86,7 +89,6 @@ impl Region {
 
 pub const PAGE_SIZE: usize = 4096;
 static mut CURRENT_REGION: Option<Region> = None;
-static mut NEXT_PAGE_ADDR: PAddr = PAddr::zero();
 
 fn exclude_range_from_range<T: PartialOrd + Copy>(
     base: &Range<T>,
@@ -132,13 +134,19 @@ pub fn set_region_from_fdt() {
 
     let kernel_reserved_range =
         unsafe { ld_variable!(__kernel_base, u8)..ld_variable!(__stack_top, usize) };
+    // the initrd stays in place to back the files of the initramfs
+    let initrd_reserved_range = crate::filesystem::initramfs::reserved_range().unwrap_or(0..0);
     let mut largest_region: Option<Region> = None;
     for region in crate::dtb::fdt().memory().regions() {
         let base = (region.starting_address as usize)
             ..(region.starting_address as usize + region.size.unwrap());
         let base = ((base.start + 4096 - 1) & !(4096 - 1))..((base.end + 4096 - 1) & !(4096 - 1));
         let regions = exclude_range_from_range(&base, &kernel_reserved_range);
-        for region in regions.into_iter().flatten() {
+        let regions = regions
+            .into_iter()
+            .flatten()
+            .flat_map(|region| exclude_range_from_range(&region, &initrd_reserved_range));
+        for region in regions.flatten() {
             let size = region.end - region.start;
             let region = Region {
                 addr: PAddr(region.start + PAGE_SIZE),
@@ -159,7 +167,6 @@ pub fn set_region_from_fdt() {
     let region = largest_region.unwrap();
 
     unsafe {
-        NEXT_PAGE_ADDR = region.addr;
         CURRENT_REGION = Some(region);
     };
 }
@@ -171,21 +178,25 @@ pub fn get_region() -> &'static Region {
     }
 }
 
-/// allocate [n] pages and return the start address
-pub fn allocate(n: usize) -> PAddr {
-    let addr = unsafe { NEXT_PAGE_ADDR };
+/// returns global region or none if it is not selected yet
+pub fn try_get_region() -> Option<&'static Region> {
+    #[allow(static_mut_refs)]
     unsafe {
-        NEXT_PAGE_ADDR += PAddr(n * PAGE_SIZE);
-
-        #[allow(static_mut_refs)]
-        if NEXT_PAGE_ADDR > CURRENT_REGION.clone().unwrap().end() {
-            panic!("out of current memory region");
-        }
-
-        ptr::write_bytes(addr.as_mut_ptr(), 0, n * PAGE_SIZE / size_of::<u8>());
+        CURRENT_REGION.as_ref()
     }
+}
 
-    addr
+/// allocates [n] zeroed pages from the global allocator and returns the start address
+///
+/// The pages come from the same blocks as the heap, so they must not be taken from the region
+/// directly.
+pub fn allocate(n: usize) -> PAddr {
+    let layout = Layout::from_size_align(n * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        alloc::alloc::handle_alloc_error(layout);
+    }
+    PAddr(ptr.addr())
 }
 
 bitflags! {
@@ -204,8 +215,222 @@ bitflags! {
     }
 }
 
-/// Sv32: VPN1(10bits) + VPN2(10bits) + Offset(12bits)
-pub fn map_page_sv32(table1: PAddr, vaddr: VAddr, paddr: PAddr, flags: PageFlag) {
+/// returns the entry of [vaddr] in the table of [level] (0 is the leaf level) of the table tree
+/// of [root]
+///
+/// Missing tables above [level] are allocated if [create] is set, otherwise null is returned.
+/// Returns null as well if [vaddr] lies in a superpage, whose entry is a leaf above [level].
+fn entry(root: PAddr, vaddr: VAddr, level: usize, create: bool) -> *mut usize {
+    unsafe {
+        let mut table = root.addr() as *mut usize;
+        for current in (level + 1..Paging::LEVELS).rev() {
+            let entry = table.add(Paging::vpn(vaddr.addr(), current));
+            if (entry.read() & PageFlag::Valid.bits()) == 0 {
+                if !create {
+                    return ptr::null_mut();
+                }
+
+                // create next level virtual page table
+                let page_table = allocate(1);
+                entry.write(((page_table.addr() / PAGE_SIZE) << 10) | PageFlag::Valid.bits());
+            } else if entry.read() & PageFlag::ReadWriteExecute.bits() != 0 {
+                return ptr::null_mut();
+            }
+
+            table = (Paging::ppn(entry.read()) * PAGE_SIZE) as *mut usize;
+        }
+
+        table.add(Paging::vpn(vaddr.addr(), level))
+    }
+}
+
+/// returns the leaf entry of [vaddr] in the table tree of [root]
+///
+/// Missing intermediate tables are allocated if [create] is set, otherwise null is returned.
+/// Returns null as well if [vaddr] lies in a superpage, whose entry is a leaf above the last
+/// level.
+fn leaf_entry(root: PAddr, vaddr: VAddr, create: bool) -> *mut usize {
+    entry(root, vaddr, 0, create)
+}
+
+/// returns the physical address [vaddr] translates to in the table tree of [root] and the flags
+/// of its page, none if it is not mapped
+pub fn translate(root: PAddr, vaddr: VAddr) -> Option<(PAddr, PageFlag)> {
+    let mut table = root;
+    for level in (0..Paging::LEVELS).rev() {
+        let entry = unsafe {
+            (table.addr() as *const usize)
+                .add(Paging::vpn(vaddr.addr(), level))
+                .read()
+        };
+        if entry & PageFlag::Valid.bits() == 0 {
+            return None;
+        }
+
+        let paddr = Paging::ppn(entry) * PAGE_SIZE;
+        let flags = PageFlag::from_bits_truncate(entry & 0x3ff);
+        if flags.intersects(PageFlag::ReadWriteExecute) {
+            let size = PAGE_SIZE << (level * Paging::VPN_BITS);
+            return Some((PAddr(paddr + (vaddr.addr() & (size - 1))), flags));
+        }
+        table = PAddr(paddr);
+    }
+
+    // a pointer to a next level table is corrupt at the last level
+    None
+}
+
+/// identity maps the pages overlapping [range] in the table tree of [root], using the largest
+/// pages the alignment allows and keeping pages that are already mapped
+pub fn map_identity(root: PAddr, range: Range<usize>, flags: PageFlag) {
+    // the end of the last page of the address space is not representable
+    let end = range
+        .end
+        .checked_next_multiple_of(PAGE_SIZE)
+        .unwrap_or(!(PAGE_SIZE - 1));
+    let mut addr = range.start & !(PAGE_SIZE - 1);
+    while addr < end {
+        let mut size = PAGE_SIZE;
+        if translate(root, VAddr(addr)).is_none() {
+            for level in (0..Paging::LEVELS).rev() {
+                size = PAGE_SIZE << (level * Paging::VPN_BITS);
+                if !addr.is_multiple_of(size) || end - addr < size {
+                    continue;
+                }
+
+                // a valid entry above the last level is a table mapping part of the range
+                let entry = entry(root, VAddr(addr), level, true);
+                if unsafe { entry.read() } & PageFlag::Valid.bits() == 0 {
+                    unsafe {
+                        entry.write(((addr / PAGE_SIZE) << 10) | (flags | PageFlag::Valid).bits())
+                    };
+                    break;
+                }
+            }
+        }
+
+        let Some(next) = addr.checked_add(size) else {
+            break;
+        };
+        addr = next;
+    }
+}
+
+/// identity maps the memory and the devices used by the kernel into the table tree of [root] as
+/// global supervisor pages, so the kernel keeps running once it is loaded into `satp`
+pub fn map_kernel(root: PAddr) {
+    // the accessed and dirty bits are preset as not every hart updates them
+    let flags = PageFlag::Global | PageFlag::Accessed | PageFlag::Dirty;
+    let fdt = crate::dtb::fdt();
+    // the fdt and the initrd lie in memory
+    for region in fdt.memory().regions() {
+        let start = region.starting_address as usize;
+        let size = region.size.unwrap_or(0);
+        map_identity(
+            root,
+            start..start.saturating_add(size),
+            flags | PageFlag::ReadWriteExecute,
+        );
+    }
+    let kernel = unsafe { ld_variable!(__kernel_base, u8)..ld_variable!(__stack_top, usize) };
+    map_identity(root, kernel, flags | PageFlag::ReadWriteExecute);
+
+    // devices are children of the root node or of `/soc`
+    let children = |path| {
+        fdt.find_node(path)
+            .into_iter()
+            .flat_map(|node| node.children())
+    };
+    for device in children("/").chain(children("/soc")) {
+        for reg in device.reg().into_iter().flatten() {
+            let start = reg.starting_address as usize;
+            let size = reg.size.unwrap_or(PAGE_SIZE);
+            map_identity(
+                root,
+                start..start.saturating_add(size),
+                flags | PageFlag::Read | PageFlag::Write,
+            );
+        }
+    }
+}
+
+/// frees [n] pages returned by [allocate]
+pub fn free(addr: PAddr, n: usize) {
+    let layout = Layout::from_size_align(n * PAGE_SIZE, PAGE_SIZE).unwrap();
+    unsafe { alloc::alloc::dealloc(addr.as_mut_ptr(), layout) };
+}
+
+/// frees the tables of the table tree of [root] including [root], but not the pages they map
+pub fn free_tables(root: PAddr) {
+    fn walk(table: PAddr, level: usize) {
+        for index in 0..(1 << Paging::VPN_BITS) {
+            let entry = unsafe { (table.addr() as *const usize).add(index).read() };
+            if level > 0
+                && entry & PageFlag::Valid.bits() != 0
+                && entry & PageFlag::ReadWriteExecute.bits() == 0
+            {
+                walk(PAddr(Paging::ppn(entry) * PAGE_SIZE), level - 1);
+            }
+        }
+        free(table, 1);
+    }
+
+    walk(root, Paging::LEVELS - 1);
+}
+
+/// Contiguous range of virtual memory mapped to contiguous physical memory with the same flags
+pub struct Mapping {
+    pub vaddr: Range<usize>,
+    pub paddr: PAddr,
+    pub flags: PageFlag,
+}
+
+/// returns the mappings of the table tree of [root] in ascending virtual address order, merging
+/// adjacent pages
+pub fn mappings(root: PAddr) -> Vec<Mapping> {
+    fn walk(table: PAddr, level: usize, base: usize, mappings: &mut Vec<Mapping>) {
+        for index in 0..(1 << Paging::VPN_BITS) {
+            let entry = unsafe { (table.addr() as *const usize).add(index).read() };
+            if entry & PageFlag::Valid.bits() == 0 {
+                continue;
+            }
+
+            let vaddr = base | (index << (12 + level * Paging::VPN_BITS));
+            let paddr = PAddr(Paging::ppn(entry) * PAGE_SIZE);
+            let flags = PageFlag::from_bits_truncate(entry & 0x3ff);
+            if !flags.intersects(PageFlag::ReadWriteExecute) {
+                // a pointer to a next level table is corrupt at the last level
+                if level > 0 {
+                    walk(paddr, level - 1, vaddr, mappings);
+                }
+                continue;
+            }
+
+            let size = PAGE_SIZE << (level * Paging::VPN_BITS);
+            let flags = flags - PageFlag::Accessed - PageFlag::Dirty;
+            if let Some(last) = mappings.last_mut()
+                && last.vaddr.end == vaddr
+                && last.paddr.addr() + last.vaddr.len() == paddr.addr()
+                && last.flags.bits() == flags.bits()
+            {
+                last.vaddr.end += size;
+            } else {
+                mappings.push(Mapping {
+                    vaddr: vaddr..vaddr + size,
+                    paddr,
+                    flags,
+                });
+            }
+        }
+    }
+
+    let mut mappings = Vec::new();
+    walk(root, Paging::LEVELS - 1, 0, &mut mappings);
+    mappings
+}
+
+/// maps [vaddr] to [paddr] in the table tree of [root] using the paging mode of the target
+pub fn map_page(root: PAddr, vaddr: VAddr, paddr: PAddr, flags: PageFlag) {
     unsafe {
         if !vaddr.as_ptr().is_aligned() {
             panic!("unaligned vaddr {vaddr:#x}");
@@ -214,30 +439,47 @@ pub fn map_page_sv32(table1: PAddr, vaddr: VAddr, paddr: PAddr, flags: PageFlag)
             panic!("unaligned paddr {paddr:#x}");
         };
 
-        let table1 = table1.addr() as *mut usize;
-
-        let vpn1 = ((vaddr.addr() >> 22) & 0x3ff) as isize;
-        if (table1.offset(vpn1).read() & PageFlag::Valid.bits()) == 0 {
-            // create second level virtual page table
-            let page_table = allocate(1);
-            table1
-                .offset(vpn1)
-                .write(((page_table.addr() / PAGE_SIZE) << 10) | PageFlag::Valid.bits());
+        let entry = leaf_entry(root, vaddr, true);
+        if entry.is_null() {
+            panic!("vaddr {vaddr:#x} is already mapped by a superpage");
         }
+        entry.write(((paddr.addr() / PAGE_SIZE) << 10) | (flags | PageFlag::Valid).bits())
+    }
+}
 
-        let vpn0 = ((vaddr.addr() >> 12) & 0x3ff) as isize;
-        let table0 = ((*table1.offset(vpn1) >> 10) * PAGE_SIZE) as *mut usize;
-        table0
-            .offset(vpn0)
-            .write(((paddr.addr() / PAGE_SIZE) << 10) | (flags | PageFlag::Valid).bits())
+/// flushes the translation of [vaddr] of [entry] from the tlb of every hart, for every address
+/// space if it is global and for [asid] otherwise
+fn flush(entry: usize, vaddr: VAddr, asid: usize) {
+    if entry & PageFlag::Global.bits() != 0 {
+        crate::ipi::shootdown(vaddr.addr(), PAGE_SIZE);
+    } else {
+        crate::ipi::shootdown_asid(vaddr.addr(), PAGE_SIZE, asid);
     }
 }
 
-pub fn map_page_to_heap(table: PAddr, flags: PageFlag, mapper: fn(PAddr, VAddr, PAddr, PageFlag)) {
-    #[allow(static_mut_refs)]
-    let region = unsafe { CURRENT_REGION.as_ref().unwrap() };
-    for paddr in (region.addr.addr()..(region.addr.addr() + region.size)).step_by(PAGE_SIZE) {
-        // println!("[map_page_to_heap#] map {paddr:#x}");
-        mapper(table, VAddr(paddr), PAddr(paddr), flags)
+/// removes the mapping of [vaddr] from the table tree of [root], whose translations are tagged
+/// with [asid], and flushes it from the tlb of every hart
+pub fn unmap_page(root: PAddr, vaddr: VAddr, asid: usize) {
+    let entry = leaf_entry(root, vaddr, false);
+    if entry.is_null() {
+        return;
     }
+
+    let previous = unsafe { entry.read() };
+    unsafe { entry.write(0) };
+    flush(previous, vaddr, asid);
+}
+
+/// replaces the flags of the mapping of [vaddr] in the table tree of [root], whose translations
+/// are tagged with [asid], and flushes it from the tlb of every hart
+pub fn protect_page(root: PAddr, vaddr: VAddr, flags: PageFlag, asid: usize) {
+    let entry = leaf_entry(root, vaddr, false);
+    if entry.is_null() || unsafe { entry.read() } & PageFlag::Valid.bits() == 0 {
+        panic!("protecting unmapped vaddr {vaddr:#x}");
+    }
+
+    let previous = unsafe { entry.read() };
+    let ppn = Paging::ppn(previous);
+    unsafe { entry.write((ppn << 10) | (flags | PageFlag::Valid).bits()) };
+    flush(previous, vaddr, asid);
 }
@@ -0,0 +1,177 @@
+use crate::{dtb, println};
+use core::cell::UnsafeCell;
+
+/// Value type of a kernel parameter
+pub trait ParamValue: Copy + 'static {
+    /// parses the value of `key=value`, [value] is none for a bare `key`
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        match value {
+            None | Some("1" | "y" | "yes" | "on" | "true") => Some(true),
+            Some("0" | "n" | "no" | "off" | "false") => Some(false),
+            Some(_) => None,
+        }
+    }
+}
+
+impl ParamValue for usize {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        let value = value?;
+        match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        value
+    }
+}
+
+impl<T: ParamValue> ParamValue for Option<T> {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        T::parse(value).map(Some)
+    }
+}
+
+/// Kernel parameter declared with [kernel_param]
+pub struct Param<T> {
+    value: UnsafeCell<T>,
+}
+
+// only written by [initialize] on the boot hart before the secondary harts are started
+unsafe impl<T: Sync> Sync for Param<T> {}
+
+impl<T: ParamValue> Param<T> {
+    pub const fn new(default: T) -> Self {
+        Self {
+            value: UnsafeCell::new(default),
+        }
+    }
+
+    pub fn get(&self) -> T {
+        unsafe { *self.value.get() }
+    }
+
+    /// sets the parameter from the value on the command line, returning whether it is valid
+    pub fn set(&self, value: Option<&'static str>) -> bool {
+        match T::parse(value) {
+            Some(value) => {
+                unsafe { *self.value.get() = value };
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Entry of the parameter registry, placed in the `.params` section by [kernel_param]
+#[repr(C)]
+pub struct Entry {
+    pub key: &'static str,
+    pub set: fn(Option<&'static str>) -> bool,
+}
+
+/// Declares a kernel parameter set by `key=value` (or a bare `key`) on the command line
+///
+/// ```ignore
+/// kernel_param!(pub static LOGLEVEL: usize = "loglevel", 6);
+/// ```
+#[macro_export]
+macro_rules! kernel_param {
+    ($(#[$attr:meta])* $vis:vis static $ident:ident: $ty:ty = $key:literal, $default:expr $(;)?) => {
+        $(#[$attr])*
+        $vis static $ident: $crate::param::Param<$ty> = $crate::param::Param::new($default);
+
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".params")]
+            static ENTRY: $crate::param::Entry = $crate::param::Entry {
+                key: $key,
+                set: |value| $ident.set(value),
+            };
+        };
+    };
+}
+
+// only the addresses of the section bounds are used, [Entry] is not an ffi type
+unsafe extern "C" {
+    static __params: u8;
+    static __params_end: u8;
+}
+
+fn entries() -> &'static [Entry] {
+    unsafe {
+        let start = (&raw const __params).cast::<Entry>();
+        let end = (&raw const __params_end).cast::<Entry>();
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+static mut CMDLINE: &str = "";
+
+/// returns the kernel command line, empty before [initialize]
+pub fn cmdline() -> &'static str {
+    unsafe { CMDLINE }
+}
+
+/// splits [cmdline] into `key` / `key=value` arguments, where values may be double quoted to
+/// contain spaces
+fn arguments(cmdline: &'static str) -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    let mut rest = cmdline;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map_or(rest.len(), |(index, _)| index);
+        let argument = &rest[..end];
+        rest = &rest[end..];
+
+        Some(match argument.split_once('=') {
+            Some((key, value)) => {
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                (key, Some(value))
+            }
+            None => (argument, None),
+        })
+    })
+}
+
+/// parses `/chosen/bootargs` into the registered parameters
+///
+/// Must run on the boot hart before the secondary harts are started.
+pub fn initialize() {
+    let cmdline = dtb::fdt().chosen().bootargs().unwrap_or_default();
+    unsafe { CMDLINE = cmdline };
+    println!("cmdline: {cmdline:?}");
+
+    for (key, value) in arguments(cmdline) {
+        let Some(entry) = entries().iter().find(|entry| entry.key == key) else {
+            println!("cmdline: unknown parameter {key}");
+            continue;
+        };
+
+        if !(entry.set)(value) {
+            println!("cmdline: invalid value {value:?} for {key}, using the default");
+        }
+    }
+}
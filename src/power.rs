@@ -0,0 +1,200 @@
+use crate::filesystem::vfs;
+use crate::param::ParamValue;
+use crate::{dtb, kernel_param, println, sbi};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const SIFIVE_TEST_FAIL: u32 = 0x3333;
+const SIFIVE_TEST_PASS: u32 = 0x5555;
+const SIFIVE_TEST_RESET: u32 = 0x7777;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    None = 0,
+    Failure = 1,
+}
+
+/// Action taken by the panic handler, selected with `panic=` on the command line
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    /// shut down reporting a failure (default)
+    Shutdown,
+    /// stop the hart and wait for a debugger
+    Halt,
+    Reboot,
+}
+
+impl ParamValue for PanicAction {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        match value? {
+            "shutdown" => Some(PanicAction::Shutdown),
+            "halt" => Some(PanicAction::Halt),
+            "reboot" => Some(PanicAction::Reboot),
+            _ => None,
+        }
+    }
+}
+
+kernel_param!(static PANIC: PanicAction = "panic", PanicAction::Shutdown);
+
+impl ParamValue for ResetType {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        match value? {
+            "cold" => Some(ResetType::ColdReboot),
+            "warm" => Some(ResetType::WarmReboot),
+            _ => None,
+        }
+    }
+}
+
+kernel_param!(
+    /// reset type of [reboot], `cold` (default) or `warm` (`reboot=`)
+    static REBOOT: ResetType = "reboot", ResetType::ColdReboot
+);
+
+/// Set by the first hart to panic, the filesystems are not written back after it as their
+/// state may be what panicked
+pub static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// writes back the filesystems and shuts down the system
+pub fn shutdown(reason: ResetReason) -> ! {
+    sync();
+    system_reset(ResetType::Shutdown, reason)
+}
+
+/// writes back the filesystems and reboots the system
+pub fn reboot() -> ! {
+    sync();
+    system_reset(REBOOT.get(), ResetReason::None)
+}
+
+/// shuts down the system and reports [code] as the exit code of the emulator
+///
+/// The exit code only reaches the host through the `sifive,test` device, other methods just
+/// report whether [code] is zero.
+pub fn exit(code: u16) -> ! {
+    // the test device resets the system at once
+    sync();
+    if let Some(test) = find_sifive_test() {
+        let value = if code == 0 {
+            SIFIVE_TEST_PASS
+        } else {
+            SIFIVE_TEST_FAIL | (code as u32) << 16
+        };
+        unsafe { test.write_volatile(value) };
+    }
+
+    shutdown(if code == 0 {
+        ResetReason::None
+    } else {
+        ResetReason::Failure
+    })
+}
+
+/// resets the system using the first available method of SBI System Reset, `syscon-poweroff` /
+/// `syscon-reboot` and `sifive,test`, halting if all of them failed
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> ! {
+    if sbi::probe_extension(sbi::EID_SRST) {
+        let result = sbi::system_reset(reset_type as u32, reason as u32);
+        println!(
+            "power: sbi system reset failed (error={})",
+            result.error as isize
+        );
+    }
+
+    // syscon nodes cannot report a failure, prefer the test device for it
+    let failure = reset_type == ResetType::Shutdown && reason == ResetReason::Failure;
+    if !failure {
+        let syscon = match reset_type {
+            ResetType::Shutdown => "syscon-poweroff",
+            ResetType::ColdReboot | ResetType::WarmReboot => "syscon-reboot",
+        };
+        syscon_write(syscon);
+    }
+
+    if let Some(test) = find_sifive_test() {
+        let value = match reset_type {
+            ResetType::Shutdown if failure => SIFIVE_TEST_FAIL | 1 << 16,
+            ResetType::Shutdown => SIFIVE_TEST_PASS,
+            ResetType::ColdReboot | ResetType::WarmReboot => SIFIVE_TEST_RESET,
+        };
+        unsafe { test.write_volatile(value) };
+    }
+
+    if failure {
+        syscon_write("syscon-poweroff");
+    }
+
+    println!("power: failed to reset the system, halting");
+    halt()
+}
+
+/// stops the calling hart forever
+pub fn halt() -> ! {
+    unsafe { riscv::register::sstatus::clear_sie() };
+    loop {
+        riscv::asm::wfi();
+    }
+}
+
+fn sync() {
+    if PANICKING.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Err(err) = vfs::sync() {
+        println!("power: failed to write back the filesystems ({err:?})");
+    }
+}
+
+/// returns the action the panic handler should take, [PanicAction::Shutdown] by default
+pub fn panic_action() -> PanicAction {
+    PANIC.get()
+}
+
+/// writes `value` of the [compatible] syscon node to its `regmap` register, if one exists
+fn syscon_write(compatible: &str) {
+    let Some(fdt) = dtb::try_fdt() else {
+        return;
+    };
+    let Some(node) = fdt.find_compatible(&[compatible]) else {
+        return;
+    };
+
+    let property = |name| node.property(name).and_then(|p| p.as_usize());
+    let (Some(regmap), Some(offset), Some(value)) =
+        (property("regmap"), property("offset"), property("value"))
+    else {
+        println!("power: {} node is missing properties", node.name);
+        return;
+    };
+    let mask = property("mask").unwrap_or(u32::MAX as usize) as u32;
+
+    let Some(base) = fdt
+        .find_phandle(regmap as u32)
+        .and_then(|regmap| regmap.reg())
+        .and_then(|mut reg| reg.next())
+    else {
+        println!("power: regmap of {} is not found", node.name);
+        return;
+    };
+
+    unsafe {
+        let register = (base.starting_address as usize + offset) as *mut u32;
+        let previous = register.read_volatile();
+        register.write_volatile((previous & !mask) | (value as u32 & mask));
+    }
+}
+
+fn find_sifive_test() -> Option<*mut u32> {
+    dtb::try_fdt()?
+        .find_compatible(&["sifive,test1", "sifive,test0"])?
+        .reg()?
+        .next()
+        .map(|reg| reg.starting_address as *mut u32)
+}
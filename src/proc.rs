@@ -1,19 +1,44 @@
-use crate::memory::{PAGE_SIZE, PAddr, PageFlag, VAddr, map_page_sv32, map_page_to_heap};
-use crate::{__kernel_base, __stack_top, ld_variable};
+use crate::arch::{Paging, PagingMode};
+use crate::elf::{self, Executable};
+use crate::exceptions::{self, FRAME_SIZE, TrapFrame};
+use crate::filesystem::vfs::{self, FsError, OpenFile, OpenFlags};
+use crate::fpu::FpContext;
+use crate::memory::{self, PAGE_SIZE, PAddr, PageFlag, VAddr, map_page};
+use crate::println;
+use crate::smp::MAX_HARTS;
+use crate::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::asm;
-use core::ops::Sub;
 use macros::repeat;
 
 const MAX_PROCESSES: usize = 8;
+/// Maximum number of files a process has open at once
+const MAX_FILES: usize = 64;
+/// Size of the kernel stack of a process in pages, syscalls run whole filesystem paths on it
+const KERNEL_STACK_PAGES: usize = 4;
+/// Size of the user stack of a process in pages
+const USER_STACK_PAGES: usize = 16;
+/// End of the user part of the address space, the lower half of what the paging mode translates
+const USER_END: usize = 1 << (11 + Paging::LEVELS * Paging::VPN_BITS);
+/// Flags of every user page, the accessed and dirty bits are preset as not every hart updates
+/// them
+pub const USER_PAGE: PageFlag = PageFlag::User
+    .union(PageFlag::Accessed)
+    .union(PageFlag::Dirty);
 
 /// workaround for [https://github.com/rust-lang/rust/issues/44796]
 const PROC_INIT: Proc = Proc::placeholder();
 static mut PROCS: [Proc; MAX_PROCESSES] = [PROC_INIT; MAX_PROCESSES];
+/// process running on each hart, indexed by the logical index of the hart
+static mut CURRENT: [*mut Proc; MAX_HARTS] = [core::ptr::null_mut(); MAX_HARTS];
 
 #[derive(PartialOrd, PartialEq, Ord, Eq, Copy, Clone, Debug)]
 pub enum ProcState {
     Empty,
     Loaded,
+    /// exited with a status, waiting to be released
+    Exited(i32),
 }
 
 pub struct Proc {
@@ -21,7 +46,14 @@ pub struct Proc {
     state: ProcState,
     pub stack_pointer: VAddr,
     page_table: PAddr,
-    stack: [u8; 8192],
+    /// address space identifier tagging the translations of [page_table], as far as the hart
+    /// implements it
+    asid: usize,
+    pub fp: FpContext,
+    /// top of the kernel stack, used by the kernel side of the process and by its traps
+    kernel_stack_top: VAddr,
+    /// open files indexed by descriptor
+    files: Vec<Option<Arc<OpenFile>>>,
 }
 
 impl Proc {
@@ -31,56 +63,119 @@ impl Proc {
             state: ProcState::Empty,
             stack_pointer: VAddr::zero(),
             page_table: PAddr::zero(),
-            stack: [0; 8192],
+            asid: 0,
+            fp: FpContext::new(),
+            kernel_stack_top: VAddr::zero(),
+            files: Vec::new(),
         }
     }
 }
 
+/// returns the process [pid] if it exists
+pub fn find(pid: usize) -> Option<&'static Proc> {
+    #[allow(static_mut_refs)]
+    unsafe { &PROCS }
+        .iter()
+        .find(|proc| proc.state != ProcState::Empty && proc.pid == pid)
+}
+
+/// returns the pids of every process
+pub fn pids() -> Vec<usize> {
+    #[allow(static_mut_refs)]
+    unsafe { &PROCS }
+        .iter()
+        .filter(|proc| proc.state != ProcState::Empty)
+        .map(|proc| proc.pid)
+        .collect()
+}
+
+/// returns the process running on the current hart
+pub fn current() -> Option<&'static mut Proc> {
+    unsafe { CURRENT[crate::smp::current().index].as_mut() }
+}
+
 impl Proc {
-    pub fn create(entrypoint: usize) -> &'static mut Proc {
+    pub fn state(&self) -> ProcState {
+        self.state
+    }
+
+    /// returns the root of the page table tree of the process
+    pub fn page_table(&self) -> PAddr {
+        self.page_table
+    }
+
+    /// returns the address space identifier of the process
+    pub fn asid(&self) -> usize {
+        self.asid
+    }
+
+    pub fn kernel_stack_top(&self) -> VAddr {
+        self.kernel_stack_top
+    }
+
+    /// returns the open file [fd]
+    pub fn file(&self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.get(fd).cloned().flatten()
+    }
+
+    /// adds [file] at the lowest free descriptor and returns it, none if the process has
+    /// [MAX_FILES] open files
+    pub fn add_file(&mut self, file: Arc<OpenFile>) -> Option<usize> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return None,
+        };
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    /// closes [fd], returning the file if it was open
+    pub fn close_file(&mut self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.get_mut(fd)?.take()
+    }
+
+    /// creates a process running the executable at [path] in U-mode
+    ///
+    /// The process runs once it is switched to with [switch_context](Proc::switch_context).
+    pub fn create(path: &str) -> vfs::Result<&'static mut Proc> {
         static mut PID_NEXT: usize = 0;
 
-        let mut available: Option<&mut Proc> = None;
         #[allow(static_mut_refs)]
-        for proc in unsafe { &mut PROCS } {
-            if proc.state == ProcState::Empty {
-                available = Some(proc);
-            }
-        }
+        let (slot, available) = unsafe { &mut PROCS }
+            .iter_mut()
+            .enumerate()
+            .find(|(_, proc)| proc.state == ProcState::Empty)
+            .ok_or(FsError::NoSpace)?;
+        // asid 0 is left to the kernel, harts with fewer asid bits keep the bits they implement
+        let asid = slot + 1;
 
-        let Some(available) = available else {
-            panic!("failed to create new process: reached max")
+        let file = vfs::open(path, OpenFlags::Read, 0)?;
+        let executable = elf::parse(&file)?;
+        let page_table = memory::allocate(1);
+        memory::map_kernel(page_table);
+        let user_stack_top = match load(page_table, &file, &executable) {
+            Ok(user_stack_top) => user_stack_top,
+            Err(err) => {
+                release(page_table, asid);
+                return Err(err);
+            }
         };
 
-        let stack_pointer = unsafe {
-            available
-                .stack
-                .as_mut_ptr().add(size_of_val(&available.stack)) as *mut usize
-        };
+        // the first switch to the process returns to U-mode through the trap frame at the top of
+        // its kernel stack
+        let kernel_stack = memory::allocate(KERNEL_STACK_PAGES);
+        let kernel_stack_top = VAddr(kernel_stack.addr() + KERNEL_STACK_PAGES * PAGE_SIZE);
+        let frame = (kernel_stack_top.addr() - FRAME_SIZE) as *mut TrapFrame;
+        let stack_pointer = frame as *mut usize;
         unsafe {
+            frame.write(TrapFrame::user(executable.entry, user_stack_top.addr()));
             // s11 - s0 (12 writes)
             repeat!(12 as n, { *stack_pointer.sub(n + 1) = 0 });
-            *stack_pointer.sub(13) = entrypoint; // ra
-        }
-
-        let page_table = crate::memory::allocate(1);
-
-        map_page_to_heap(
-            page_table,
-            PageFlag::ReadWriteExecute,
-            map_page_sv32,
-        );
-        // map kernel static memories
-        for paddr in unsafe { ld_variable!(__kernel_base, u8)..ld_variable!(__stack_top, usize) }
-            .step_by(PAGE_SIZE)
-        {
-            // println!("map {paddr:#x}");
-            map_page_sv32(
-                page_table,
-                VAddr(paddr),
-                PAddr(paddr),
-                PageFlag::ReadWriteExecute,
-            );
+            *stack_pointer.sub(13) = exceptions::trap_return as *const () as usize; // ra
         }
 
         unsafe {
@@ -90,28 +185,47 @@ impl Proc {
         available.stack_pointer = VAddr(unsafe { stack_pointer.sub(13) } as usize);
         available.state = ProcState::Loaded;
         available.page_table = page_table;
+        available.asid = asid;
+        available.kernel_stack_top = kernel_stack_top;
+        // standard input, output and error
+        let console = vfs::open("/dev/console", OpenFlags::ReadWrite, 0)
+            .ok()
+            .map(Arc::new);
+        available.files = vec![console; 3];
 
-        available
+        Ok(available)
     }
 
     #[inline(always)]
     pub unsafe fn switch_context(previous: &mut Proc, next: &mut Proc) {
         unsafe {
-            let satp = riscv::register::satp::Mode::Sv32.into_usize() << 31
-                | (next.page_table.addr() / PAGE_SIZE);
-            let sscratch = (&mut next.stack as *mut [u8] as *mut u8)
-                .add(size_of_val(&next.stack)) as *mut u32;
+            // the kernel runs untranslated outside of processes
+            let satp = if next.page_table == PAddr::zero() {
+                0
+            } else {
+                Paging::satp(next.page_table, next.asid)
+            };
 
             asm!(
                 "
                     sfence.vma
                     csrw satp, {satp}
                     sfence.vma
-                    csrw sscratch, {sscratch}
                 ",
                 satp = in(reg) satp,
-                sscratch = in(reg) sscratch
             );
+            if next.page_table != PAddr::zero() {
+                next.asid = riscv::register::satp::read().asid();
+            }
+
+            let hart = crate::smp::current();
+            hart.set_kernel_sp(next.kernel_stack_top.addr());
+            // the idle context of the hart is no process
+            CURRENT[hart.index] = if next.state == ProcState::Empty {
+                core::ptr::null_mut()
+            } else {
+                next
+            };
 
             crate::arch::rvc::context_switch(
                 &mut previous.stack_pointer.0,
@@ -120,3 +234,122 @@ impl Proc {
         }
     }
 }
+
+/// loads the segments of [executable] into newly allocated pages mapped in [page_table] and
+/// maps a user stack above them, returning the top of the stack
+fn load(page_table: PAddr, file: &OpenFile, executable: &Executable) -> vfs::Result<VAddr> {
+    let mut image_end = 0;
+    for segment in &executable.segments {
+        let end = segment
+            .vaddr
+            .checked_add(segment.mem_size)
+            .filter(|&end| end <= USER_END)
+            .ok_or(FsError::InvalidArgument)?;
+        let mut flags = USER_PAGE;
+        for (bit, flag) in [
+            (elf::PF_R, PageFlag::Read),
+            (elf::PF_W, PageFlag::Write),
+            (elf::PF_X, PageFlag::Execute),
+        ] {
+            if segment.flags & bit != 0 {
+                flags |= flag;
+            }
+        }
+
+        for vaddr in (segment.vaddr & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+            match memory::translate(page_table, VAddr(vaddr)) {
+                None => map_page(page_table, VAddr(vaddr), memory::allocate(1), flags),
+                // a page shared by two segments gets the permissions of both
+                Some((paddr, existing)) if existing.contains(PageFlag::User) => {
+                    map_page(page_table, VAddr(vaddr), paddr, existing | flags)
+                }
+                // the segment overlaps the kernel mappings
+                Some(_) => return Err(FsError::InvalidArgument),
+            }
+        }
+
+        // the pages are zeroed, only the part backed by the file is read
+        let mut done = 0;
+        while done < segment.file_size {
+            let vaddr = segment.vaddr + done;
+            let len = (PAGE_SIZE - vaddr % PAGE_SIZE).min(segment.file_size - done);
+            let (paddr, _) = memory::translate(page_table, VAddr(vaddr)).unwrap();
+            let page = unsafe { core::slice::from_raw_parts_mut(paddr.as_mut_ptr(), len) };
+            elf::read_exact(file, segment.offset + done as u64, page)?;
+            done += len;
+        }
+        image_end = image_end.max(end);
+    }
+
+    // the stack is placed after an unmapped guard page
+    let stack_bottom = image_end.next_multiple_of(PAGE_SIZE) + PAGE_SIZE;
+    let stack_top = stack_bottom + USER_STACK_PAGES * PAGE_SIZE;
+    if stack_top > USER_END {
+        return Err(FsError::InvalidArgument);
+    }
+    for vaddr in (stack_bottom..stack_top).step_by(PAGE_SIZE) {
+        if memory::translate(page_table, VAddr(vaddr)).is_some() {
+            return Err(FsError::InvalidArgument);
+        }
+        let flags = USER_PAGE | PageFlag::Read | PageFlag::Write;
+        map_page(page_table, VAddr(vaddr), memory::allocate(1), flags);
+    }
+    // `argc`, `argv`, `envp` and the auxiliary vector are empty
+    Ok(VAddr(stack_top - 4 * size_of::<usize>()))
+}
+
+/// unmaps and frees the user pages and frees the page tables of the table tree of [page_table],
+/// whose translations are tagged with [asid]
+fn release(page_table: PAddr, asid: usize) {
+    for mapping in memory::mappings(page_table) {
+        if !mapping.flags.contains(PageFlag::User) {
+            continue;
+        }
+        // user pages are allocated one by one, no hart may keep using a page once it is freed
+        for offset in (0..mapping.vaddr.len()).step_by(PAGE_SIZE) {
+            memory::unmap_page(page_table, VAddr(mapping.vaddr.start + offset), asid);
+            memory::free(mapping.paddr + PAddr(offset), 1);
+        }
+    }
+    memory::free_tables(page_table);
+}
+
+/// Context of each hart while it runs no process, indexed by the logical index of the hart
+static mut IDLE: [Proc; MAX_HARTS] = [PROC_INIT; MAX_HARTS];
+
+/// runs the executable at [path] as the first process on the current hart, returning its exit
+/// status once it exited
+pub fn run_init(path: &str) -> vfs::Result<i32> {
+    let init = Proc::create(path)?;
+    println!("init: started {path} as pid {}", init.pid);
+
+    let hart = crate::smp::current().index;
+    #[allow(static_mut_refs)]
+    unsafe {
+        Proc::switch_context(&mut IDLE[hart], init)
+    };
+
+    let ProcState::Exited(status) = init.state else {
+        panic!("init switched back without exiting");
+    };
+    release(init.page_table, init.asid);
+    memory::free(
+        PAddr(init.kernel_stack_top.addr() - KERNEL_STACK_PAGES * PAGE_SIZE),
+        KERNEL_STACK_PAGES,
+    );
+    *init = Proc::placeholder();
+    Ok(status)
+}
+
+/// ends the current process with [status] and switches to the idle context of the hart
+pub fn exit(status: i32) -> ! {
+    let proc = current().expect("exit outside of a process");
+    proc.state = ProcState::Exited(status);
+
+    let hart = crate::smp::current().index;
+    #[allow(static_mut_refs)]
+    unsafe {
+        Proc::switch_context(proc, &mut IDLE[hart])
+    };
+    unreachable!("switched back to exited process {}", proc.pid);
+}
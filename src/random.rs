@@ -0,0 +1,96 @@
+use crate::dtb;
+use crate::sync::SpinLock;
+
+/// Key of the generator, none until an entropy source seeds it
+static KEY: SpinLock<Option<[u32; 8]>> = SpinLock::new(None);
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// returns the ChaCha20 block [counter] of [key] with a zero nonce
+fn chacha20_block(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+/// mixes [bytes] into [key] and spreads them over the whole key, as they may be short or biased
+fn mix(key: &mut [u32; 8], bytes: &[u8]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        key[i / 4 % 8] ^= (*byte as u32) << (i % 4 * 8);
+    }
+    let block = chacha20_block(key, 0);
+    key.copy_from_slice(&block[..8]);
+}
+
+/// returns the initial key from `/chosen/rng-seed`, none if the fdt has no seed
+fn seed() -> Option<[u32; 8]> {
+    let fdt = dtb::try_fdt()?;
+    let seed = fdt.find_node("/chosen")?.property("rng-seed")?.value;
+    let mut key = [0; 8];
+    mix(&mut key, seed);
+    Some(key)
+}
+
+/// seeds the generator with [bytes] from an entropy source, or mixes them into its key
+pub fn add_entropy(bytes: &[u8]) {
+    let mut key = KEY.lock();
+    mix(key.get_or_insert([0; 8]), bytes);
+}
+
+/// fills [buf] with random bytes from a ChaCha20 generator, returning false without filling it
+/// while no entropy source seeded the generator
+///
+/// The key is replaced after every call, so earlier output cannot be recovered from the state.
+pub fn fill_bytes(buf: &mut [u8]) -> bool {
+    let mut guard = KEY.lock();
+    if guard.is_none() {
+        *guard = seed();
+    }
+    let Some(key) = guard.as_mut() else {
+        return false;
+    };
+
+    // block 0 becomes the next key, the output starts at block 1
+    for (counter, chunk) in buf.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter as u64 + 1);
+        for (byte, value) in chunk
+            .iter_mut()
+            .zip(block.iter().flat_map(|word| word.to_le_bytes()))
+        {
+            *byte = value;
+        }
+    }
+    let next = chacha20_block(key, 0);
+    key.copy_from_slice(&next[..8]);
+    true
+}
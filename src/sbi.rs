@@ -1,3 +1,4 @@
+use crate::kernel_param;
 use core::arch::asm;
 use core::fmt;
 use core::fmt::Write;
@@ -7,7 +8,11 @@ pub struct SBIReturn {
     pub value: usize,
 }
 
-
+const EID_BASE: usize = 0x10;
+pub const EID_HSM: usize = 0x48534D;
+pub const EID_IPI: usize = 0x735049;
+pub const EID_RFENCE: usize = 0x52464E43;
+pub const EID_SRST: usize = 0x53525354;
 
 pub fn sbi_call(
     mut arg0: usize,
@@ -29,11 +34,101 @@ pub fn sbi_call(
     }
 }
 
+/// returns whether the sbi implementation provides the extension of [eid]
+pub fn probe_extension(eid: usize) -> bool {
+    sbi_call(eid, 0, 0, 0, 0, 0, 3, EID_BASE).value != 0
+}
+
+/// starts [hartid] in supervisor mode at [start_addr] with `a0 = hartid` and `a1 = opaque`
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SBIReturn {
+    sbi_call(hartid, start_addr, opaque, 0, 0, 0, 0, EID_HSM)
+}
+
+/// resets the system with [reset_type] (0 = shutdown, 1 = cold reboot, 2 = warm reboot) and
+/// [reset_reason] (0 = no reason, 1 = system failure)
+///
+/// returns only if the reset failed
+pub fn system_reset(reset_type: u32, reset_reason: u32) -> SBIReturn {
+    sbi_call(
+        reset_type as usize,
+        reset_reason as usize,
+        0,
+        0,
+        0,
+        0,
+        0,
+        EID_SRST,
+    )
+}
+
+/// raises a supervisor software interrupt on the harts of [hart_mask] (offset by [hart_mask_base])
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SBIReturn {
+    sbi_call(hart_mask, hart_mask_base, 0, 0, 0, 0, 0, EID_IPI)
+}
+
+/// executes `sfence.vma` for [start_addr, start_addr + size) on the harts of [hart_mask]
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+) -> SBIReturn {
+    sbi_call(
+        hart_mask,
+        hart_mask_base,
+        start_addr,
+        size,
+        0,
+        0,
+        1,
+        EID_RFENCE,
+    )
+}
+
+/// executes `sfence.vma` for [start_addr, start_addr + size) and [asid] on the harts of [hart_mask]
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+    asid: usize,
+) -> SBIReturn {
+    sbi_call(
+        hart_mask,
+        hart_mask_base,
+        start_addr,
+        size,
+        asid,
+        0,
+        2,
+        EID_RFENCE,
+    )
+}
 
 pub fn put_char(ch: char) {
     sbi_call(ch as usize, 0, 0, 0, 0, 0, 0, 1);
 }
 
+/// returns the next byte received by the SBI console, none if there is none
+pub fn get_char() -> Option<u8> {
+    // the legacy extension returns the byte or -1 in a0
+    let ret = sbi_call(0, 0, 0, 0, 0, 0, 0, 2);
+    (ret.error as isize >= 0).then_some(ret.error as u8)
+}
+
+kernel_param!(
+    /// console verbosity (0-7, Linux numbering), [debug] messages are only printed at
+    /// `loglevel=7` and every other message is printed regardless
+    pub static LOGLEVEL: usize = "loglevel", 6
+);
+kernel_param!(
+    /// console device selected with `console=`, only the SBI console is implemented
+    pub static CONSOLE: &str = "console", "sbi"
+);
+
+/// Debug level of [LOGLEVEL], used by [debug]
+pub const LOGLEVEL_DEBUG: usize = 7;
+
 pub struct SBIWriter;
 
 impl Write for SBIWriter {
@@ -56,4 +151,14 @@ macro_rules! print {
 macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
-}
\ No newline at end of file
+}
+
+/// prints like [println] if `loglevel=` enables debug messages
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::sbi::LOGLEVEL.get() >= $crate::sbi::LOGLEVEL_DEBUG {
+            $crate::println!($($arg)*);
+        }
+    };
+}
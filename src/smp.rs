@@ -0,0 +1,250 @@
+use crate::arch::REGBYTES;
+use crate::memory::PAGE_SIZE;
+use crate::{asm_define_macros, asm_purge_macros, exceptions, println, sbi, timer};
+use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Maximum number of harts the kernel brings up
+pub const MAX_HARTS: usize = 8;
+/// Size of the kernel stack of a secondary hart in pages (same as the boot stack)
+const HART_STACK_PAGES: usize = 32;
+
+/// Per-hart data block, reachable through `tp` once the hart is initialized
+#[repr(C)]
+pub struct Hart {
+    /// top of the kernel stack, loaded by `secondary_boot`
+    stack_top: AtomicUsize,
+    /// top of the kernel stack of the process running on the hart, where `exception_entrypoint`
+    /// saves traps taken from U-mode
+    kernel_sp: AtomicUsize,
+    /// stack pointer of the interrupted context while `exception_entrypoint` switches stacks
+    scratch_sp: AtomicUsize,
+    pub hartid: usize,
+    /// logical index of the hart in [HARTS]
+    pub index: usize,
+    online: AtomicBool,
+    arrived: AtomicBool,
+}
+
+impl Hart {
+    const fn placeholder() -> Self {
+        Self {
+            stack_top: AtomicUsize::new(0),
+            kernel_sp: AtomicUsize::new(0),
+            scratch_sp: AtomicUsize::new(0),
+            hartid: 0,
+            index: 0,
+            online: AtomicBool::new(false),
+            arrived: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// sets the kernel stack the next trap from U-mode is saved on
+    pub fn set_kernel_sp(&self, sp: usize) {
+        self.kernel_sp.store(sp, Ordering::Relaxed);
+    }
+}
+
+/// Slot of [Hart::kernel_sp] for the `LOAD`/`STORE` assembler macros
+pub const KERNEL_SP_SLOT: usize = core::mem::offset_of!(Hart, kernel_sp) / REGBYTES;
+/// Slot of [Hart::scratch_sp] for the `LOAD`/`STORE` assembler macros
+pub const SCRATCH_SP_SLOT: usize = core::mem::offset_of!(Hart, scratch_sp) / REGBYTES;
+
+/// workaround for [https://github.com/rust-lang/rust/issues/44796]
+#[allow(clippy::declare_interior_mutable_const)]
+const HART_INIT: Hart = Hart::placeholder();
+/// Harts found in the fdt, filled by [initialize] before any secondary hart is started and only
+/// shared afterward, their mutable state is atomic
+static mut HARTS: [Hart; MAX_HARTS] = [HART_INIT; MAX_HARTS];
+static mut HART_COUNT: usize = 0;
+static mut BOOT_HARTID: usize = 0;
+static RELEASED: AtomicBool = AtomicBool::new(false);
+
+pub fn boot_hartid() -> usize {
+    unsafe { BOOT_HARTID }
+}
+
+/// returns the lowest hartid of [harts], the base SBI hart masks are relative to
+pub fn hartid_base() -> usize {
+    harts().iter().map(|hart| hart.hartid).min().unwrap_or(0)
+}
+
+/// returns all harts found in the fdt
+pub fn harts() -> &'static [Hart] {
+    #[allow(static_mut_refs)]
+    unsafe {
+        &HARTS[..HART_COUNT]
+    }
+}
+
+/// returns the data block of the current hart
+///
+/// only valid after [initialize] on the boot hart
+pub fn current() -> &'static Hart {
+    let tp: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) tp);
+        &*(tp as *const Hart)
+    }
+}
+
+/// enumerates harts from `/cpus` with [hartid] as the boot hart, starts the secondary ones
+/// through SBI HSM and waits until every started hart has finished its initialization
+///
+/// Harts are kept only if all their hartids fit in a single SBI hart mask.
+pub fn initialize(hartid: usize) {
+    unsafe { BOOT_HARTID = hartid };
+
+    let (mut lowest, mut highest) = (hartid, hartid);
+
+    for cpu in crate::dtb::fdt().cpus() {
+        if cpu
+            .property("status")
+            .and_then(|status| status.as_str())
+            .is_some_and(|status| status != "okay")
+        {
+            continue;
+        }
+
+        let id = cpu.ids().first();
+        if id.max(highest) - id.min(lowest) >= usize::BITS as usize {
+            println!("smp: ignoring hart {id}, too far from the other hartids for a hart mask");
+            continue;
+        }
+
+        unsafe {
+            if HART_COUNT == MAX_HARTS {
+                println!("smp: ignoring harts above MAX_HARTS={MAX_HARTS}");
+                break;
+            }
+
+            // no other hart runs yet, nor holds a reference to the table
+            let hart = &mut HARTS[HART_COUNT];
+            hart.hartid = id;
+            hart.index = HART_COUNT;
+            HART_COUNT += 1;
+        }
+        (lowest, highest) = (id.min(lowest), id.max(highest));
+    }
+
+    let harts = harts();
+    let Some(boot) = harts.iter().find(|hart| hart.hartid == boot_hartid()) else {
+        panic!("boot hart {} is not listed in /cpus", boot_hartid());
+    };
+    boot.stack_top.store(
+        unsafe { crate::ld_variable!(crate::__stack_top, usize) },
+        Ordering::Relaxed,
+    );
+    boot.online.store(true, Ordering::Release);
+    unsafe { asm!("mv tp, {}", in(reg) boot as *const Hart) };
+
+    if cfg!(feature = "uniprocessor") {
+        println!("smp: uniprocessor build, running on the boot hart only");
+        return;
+    }
+
+    if !sbi::probe_extension(sbi::EID_HSM) {
+        println!("smp: sbi has no HSM extension, running on the boot hart only");
+        return;
+    }
+
+    for hart in harts {
+        if hart.hartid == boot_hartid() {
+            continue;
+        }
+
+        let stack = crate::memory::allocate(HART_STACK_PAGES);
+        hart.stack_top.store(
+            stack.addr() + HART_STACK_PAGES * PAGE_SIZE,
+            Ordering::Relaxed,
+        );
+
+        // the hart start call orders the store before the hart loads its stack
+        let result = sbi::hart_start(
+            hart.hartid,
+            secondary_boot as *const () as usize,
+            hart as *const Hart as usize,
+        );
+        if result.error != 0 {
+            println!(
+                "smp: failed to start hart {} (error={})",
+                hart.hartid, result.error as isize
+            );
+            continue;
+        }
+        hart.online.store(true, Ordering::Release);
+    }
+
+    init_barrier();
+    println!(
+        "smp: {} harts online",
+        harts.iter().filter(|hart| hart.is_online()).count()
+    );
+}
+
+/// Waits until all online harts have finished their initialization
+///
+/// The boot hart waits for every other online hart to arrive and releases them afterward.
+fn init_barrier() {
+    let current = current();
+    if current.hartid != boot_hartid() {
+        current.arrived.store(true, Ordering::Release);
+        while !RELEASED.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    for hart in harts() {
+        if hart.hartid == boot_hartid() {
+            continue;
+        }
+
+        // harts that failed to start are never marked online
+        while hart.is_online() && !hart.arrived.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+    }
+    RELEASED.store(true, Ordering::Release);
+}
+
+/// Entrypoint of secondary harts started by [sbi::hart_start]
+///
+/// `a0` holds the hartid and `a1` the [Hart] of it
+#[unsafe(naked)]
+unsafe extern "C" fn secondary_boot() -> ! {
+    naked_asm!(
+        asm_define_macros!(),
+        "
+        mv tp, a1
+        LOAD sp, {stack_top}, tp
+
+        // terminate the frame pointer chain for backtraces
+        mv s0, zero
+        mv ra, zero
+        j {secondary_main}
+        ",
+        asm_purge_macros!(),
+        stack_top = const core::mem::offset_of!(Hart, stack_top) / REGBYTES,
+        secondary_main = sym secondary_main,
+    )
+}
+
+unsafe extern "C" fn secondary_main(hartid: usize) -> ! {
+    exceptions::initialize_hart();
+    crate::fpu::initialize_hart();
+    crate::ipi::initialize_hart();
+
+    println!("smp: hart {hartid} is online");
+
+    init_barrier();
+
+    loop {
+        timer::run_deferred();
+        riscv::asm::wfi();
+    }
+}
@@ -0,0 +1,280 @@
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::Unsize;
+use core::ops::{CoerceUnsized, Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+
+#[cfg(not(any(feature = "uniprocessor", target_has_atomic = "ptr")))]
+compile_error!("the target has no atomic read-modify-write instructions, enable `uniprocessor`");
+
+/// Disables supervisor interrupts on the current hart until dropped, restoring the previous state
+pub struct InterruptGuard {
+    enabled: bool,
+}
+
+impl InterruptGuard {
+    pub fn new() -> Self {
+        let enabled = sstatus::read().sie();
+        unsafe { sstatus::clear_sie() };
+        Self { enabled }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            unsafe { sstatus::set_sie() };
+        }
+    }
+}
+
+/// runs [f] with interrupts disabled on the current hart
+#[cfg(feature = "uniprocessor")]
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = InterruptGuard::new();
+    f()
+}
+
+/// sets [flag], returning whether it was already set
+#[cfg(not(feature = "uniprocessor"))]
+pub fn test_and_set(flag: &AtomicBool) -> bool {
+    flag.swap(true, Ordering::AcqRel)
+}
+
+/// sets [flag], returning whether it was already set
+#[cfg(feature = "uniprocessor")]
+pub fn test_and_set(flag: &AtomicBool) -> bool {
+    without_interrupts(|| {
+        let previous = flag.load(Ordering::Relaxed);
+        flag.store(true, Ordering::Relaxed);
+        previous
+    })
+}
+
+/// Spinning mutual exclusion lock
+///
+/// Interrupts stay disabled while the lock is held, so trap handlers may take locks that are
+/// also taken outside of them without deadlocking the hart. With the `uniprocessor` feature
+/// (targets without the A extension) masking interrupts is the whole critical section, which is
+/// only correct while a single hart runs the kernel.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+        self.acquire();
+        SpinLockGuard {
+            lock: self,
+            _interrupts: interrupts,
+        }
+    }
+
+    #[cfg(not(feature = "uniprocessor"))]
+    fn acquire(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// with interrupts masked nothing else can run, so a held lock means recursive locking
+    #[cfg(feature = "uniprocessor")]
+    fn acquire(&self) {
+        if self.locked.load(Ordering::Relaxed) {
+            panic!("spinlock locked recursively");
+        }
+        self.locked.store(true, Ordering::Relaxed);
+    }
+
+    fn release(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    /// returns the data without locking, as no other reference can exist
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    // dropped after the lock is released
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+/// Reference counter for shared objects
+pub struct RefCount {
+    count: AtomicUsize,
+}
+
+impl RefCount {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// increments the count, returning the previous one
+    pub fn increment(&self) -> usize {
+        self.add(1)
+    }
+
+    /// decrements the count, returning whether it dropped to zero
+    pub fn decrement(&self) -> bool {
+        let previous = self.sub(1);
+        if previous == 0 {
+            panic!("reference count decremented below zero");
+        }
+        previous == 1
+    }
+
+    #[cfg(not(feature = "uniprocessor"))]
+    fn add(&self, n: usize) -> usize {
+        self.count.fetch_add(n, Ordering::Relaxed)
+    }
+
+    #[cfg(not(feature = "uniprocessor"))]
+    fn sub(&self, n: usize) -> usize {
+        self.count.fetch_sub(n, Ordering::AcqRel)
+    }
+
+    #[cfg(feature = "uniprocessor")]
+    fn add(&self, n: usize) -> usize {
+        without_interrupts(|| {
+            let previous = self.count.load(Ordering::Relaxed);
+            self.count
+                .store(previous.wrapping_add(n), Ordering::Relaxed);
+            previous
+        })
+    }
+
+    #[cfg(feature = "uniprocessor")]
+    fn sub(&self, n: usize) -> usize {
+        without_interrupts(|| {
+            let previous = self.count.load(Ordering::Relaxed);
+            self.count
+                .store(previous.wrapping_sub(n), Ordering::Release);
+            previous
+        })
+    }
+}
+
+/// Atomically reference counted pointer built on [RefCount]
+///
+/// `alloc::sync::Arc` requires atomic read-modify-write instructions, which the `uniprocessor`
+/// builds do not have.
+pub struct Arc<T: ?Sized> {
+    inner: NonNull<ArcInner<T>>,
+}
+
+struct ArcInner<T: ?Sized> {
+    count: RefCount,
+    data: T,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Arc<T> {}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<Arc<U>> for Arc<T> {}
+
+impl<T> Arc<T> {
+    pub fn new(data: T) -> Self {
+        let inner = Box::new(ArcInner {
+            count: RefCount::new(1),
+            data,
+        });
+        Self {
+            inner: NonNull::from(Box::leak(inner)),
+        }
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
+    /// returns whether [a] and [b] point to the same allocation
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        core::ptr::addr_eq(a.inner.as_ptr(), b.inner.as_ptr())
+    }
+
+    /// returns the number of pointers to the allocation of [this]
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().count.get()
+    }
+
+    fn inner(&self) -> &ArcInner<T> {
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+impl<T: ?Sized> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        self.inner().count.increment();
+        Self { inner: self.inner }
+    }
+}
+
+impl<T: ?Sized> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().data
+    }
+}
+
+impl<T: ?Sized> Drop for Arc<T> {
+    fn drop(&mut self) {
+        if self.inner().count.decrement() {
+            drop(unsafe { Box::from_raw(self.inner.as_ptr()) });
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
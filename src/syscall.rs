@@ -0,0 +1,500 @@
+use crate::exceptions::TrapFrame;
+use crate::filesystem::vfs::{self, FileType, FsError, OpenFile, OpenFlags, SeekFrom, Stat};
+use crate::memory::{self, PAGE_SIZE, PageFlag, VAddr};
+use crate::power::{self, ResetReason};
+use crate::sync::Arc;
+use crate::{debug, filesystem, proc};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use riscv::register::sstatus;
+
+// numbers of the Linux RISC-V syscall table
+const SYS_MKDIRAT: usize = 34;
+const SYS_UNLINKAT: usize = 35;
+const SYS_SYMLINKAT: usize = 36;
+const SYS_UMOUNT2: usize = 39;
+const SYS_MOUNT: usize = 40;
+const SYS_FTRUNCATE: usize = 46;
+const SYS_OPENAT: usize = 56;
+const SYS_CLOSE: usize = 57;
+const SYS_GETDENTS64: usize = 61;
+const SYS_LSEEK: usize = 62;
+const SYS_READ: usize = 63;
+const SYS_WRITE: usize = 64;
+const SYS_READLINKAT: usize = 78;
+const SYS_SYNC: usize = 81;
+const SYS_FSYNC: usize = 82;
+const SYS_EXIT: usize = 93;
+const SYS_EXIT_GROUP: usize = 94;
+const SYS_REBOOT: usize = 142;
+const SYS_GETPID: usize = 172;
+const SYS_MPROTECT: usize = 226;
+const SYS_RENAMEAT2: usize = 276;
+const SYS_STATX: usize = 291;
+
+const ENOENT: isize = 2;
+const EIO: isize = 5;
+const EBADF: isize = 9;
+const ENOMEM: isize = 12;
+const EFAULT: isize = 14;
+const EBUSY: isize = 16;
+const EEXIST: isize = 17;
+const EXDEV: isize = 18;
+const ENOTDIR: isize = 20;
+const EISDIR: isize = 21;
+const EINVAL: isize = 22;
+const EMFILE: isize = 24;
+const ENOSPC: isize = 28;
+const EROFS: isize = 30;
+const ENAMETOOLONG: isize = 36;
+const ENOSYS: isize = 38;
+const ENOTEMPTY: isize = 39;
+const ELOOP: isize = 40;
+const EOPNOTSUPP: isize = 95;
+const EUCLEAN: isize = 117;
+
+/// Directory file descriptor of the working directory, which is the root for every process
+const AT_FDCWD: isize = -100;
+const AT_SYMLINK_NOFOLLOW: usize = 0x100;
+const AT_EMPTY_PATH: usize = 0x1000;
+
+const O_WRONLY: usize = 0o1;
+const O_RDWR: usize = 0o2;
+const O_CREAT: usize = 0o100;
+const O_EXCL: usize = 0o200;
+const O_TRUNC: usize = 0o1000;
+const O_APPEND: usize = 0o2000;
+const O_DIRECTORY: usize = 0o200000;
+
+const PROT_READ: usize = 0x1;
+const PROT_WRITE: usize = 0x2;
+const PROT_EXEC: usize = 0x4;
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+const LINUX_REBOOT_MAGIC1: usize = 0xfee1dead;
+const LINUX_REBOOT_MAGIC2: usize = 672274793;
+const LINUX_REBOOT_CMD_RESTART: usize = 0x01234567;
+const LINUX_REBOOT_CMD_HALT: usize = 0xcdef0123;
+const LINUX_REBOOT_CMD_POWER_OFF: usize = 0x4321fedc;
+
+/// Maximum length of a path including the terminating nul
+const PATH_MAX: usize = 4096;
+/// Maximum number of bytes a single read, write or getdents64 transfers
+const MAX_IO: usize = 64 * 1024;
+
+/// Size of `struct statx`
+const STATX_SIZE: usize = 0x100;
+/// `stx_mask` of the fields filled by [statx]: type, mode, link count, inode and size
+const STATX_BASIC: u32 = 0x1 | 0x2 | 0x4 | 0x100 | 0x200;
+
+/// result of a syscall, a negated errno is returned for an error
+type Result = core::result::Result<usize, isize>;
+
+fn errno(err: FsError) -> isize {
+    match err {
+        FsError::NotFound | FsError::InvalidPath => ENOENT,
+        FsError::NotDirectory => ENOTDIR,
+        FsError::IsDirectory => EISDIR,
+        FsError::AlreadyExists => EEXIST,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::InvalidArgument => EINVAL,
+        FsError::BadMode => EBADF,
+        FsError::ReadOnly => EROFS,
+        FsError::NoSpace => ENOSPC,
+        FsError::CrossDevice => EXDEV,
+        FsError::Busy => EBUSY,
+        FsError::TooManySymlinks => ELOOP,
+        FsError::Corrupted => EUCLEAN,
+        FsError::Io => EIO,
+        FsError::Unsupported => EOPNOTSUPP,
+    }
+}
+
+/// handles the `ecall` of the current process, which passes the number in `a7` and the arguments
+/// in `a0`-`a5`, returning the result or a negated errno in `a0`
+pub fn handle(frame: &mut TrapFrame) {
+    // resume after the ecall
+    frame.sepc += 4;
+    let args = [frame.a0, frame.a1, frame.a2, frame.a3, frame.a4, frame.a5];
+
+    // syscalls may run long, interrupts are taken meanwhile
+    unsafe { sstatus::set_sie() };
+    let result = match frame.a7 {
+        SYS_OPENAT => openat(args[0] as isize, args[1], args[2], args[3]),
+        SYS_CLOSE => close(args[0]),
+        SYS_READ => read(args[0], args[1], args[2]),
+        SYS_WRITE => write(args[0], args[1], args[2]),
+        SYS_LSEEK => lseek(args[0], args[1] as isize, args[2]),
+        SYS_GETDENTS64 => getdents64(args[0], args[1], args[2]),
+        SYS_FTRUNCATE => ftruncate(args[0], args[1]),
+        SYS_FSYNC => fsync(args[0]),
+        SYS_STATX => statx(args[0] as isize, args[1], args[2], args[4]),
+        SYS_MKDIRAT => mkdirat(args[0] as isize, args[1], args[2]),
+        SYS_UNLINKAT => unlinkat(args[0] as isize, args[1]),
+        SYS_SYMLINKAT => symlinkat(args[0], args[1] as isize, args[2]),
+        SYS_READLINKAT => readlinkat(args[0] as isize, args[1], args[2], args[3]),
+        SYS_RENAMEAT2 => renameat2(
+            args[0] as isize,
+            args[1],
+            args[2] as isize,
+            args[3],
+            args[4],
+        ),
+        SYS_MOUNT => mount(args[0], args[1], args[2]),
+        SYS_UMOUNT2 => umount2(args[0]),
+        SYS_SYNC => vfs::sync().map(|()| 0).map_err(errno),
+        SYS_GETPID => Ok(current().pid),
+        SYS_MPROTECT => mprotect(args[0], args[1], args[2]),
+        SYS_REBOOT => reboot(args[0], args[1], args[2]),
+        SYS_EXIT | SYS_EXIT_GROUP => proc::exit(args[0] as i32 & 0xff),
+        number => {
+            debug!("syscall: unknown syscall {number}");
+            Err(ENOSYS)
+        }
+    };
+    unsafe { sstatus::clear_sie() };
+
+    frame.a0 = match result {
+        Ok(value) => value,
+        Err(errno) => -errno as usize,
+    };
+}
+
+fn current() -> &'static mut proc::Proc {
+    proc::current().expect("syscall outside of a process")
+}
+
+/// calls [f] with the kernel address and the length of each page of the user memory
+/// `addr..addr + len` of the current process, failing with `EFAULT` if a page is not mapped for
+/// user [access]
+fn for_each_user_page(
+    addr: usize,
+    len: usize,
+    access: PageFlag,
+    mut f: impl FnMut(*mut u8, usize),
+) -> core::result::Result<(), isize> {
+    let end = addr.checked_add(len).ok_or(EFAULT)?;
+    let page_table = current().page_table();
+    let mut addr = addr;
+    while addr < end {
+        let (paddr, flags) = memory::translate(page_table, VAddr(addr)).ok_or(EFAULT)?;
+        if !flags.contains(PageFlag::User | access) {
+            return Err(EFAULT);
+        }
+        // the kernel maps the memory of processes at its physical address
+        let len = (PAGE_SIZE - addr % PAGE_SIZE).min(end - addr);
+        f(unsafe { paddr.as_mut_ptr() }, len);
+        addr += len;
+    }
+    Ok(())
+}
+
+fn copy_from_user(addr: usize, buf: &mut [u8]) -> core::result::Result<(), isize> {
+    let mut done = 0;
+    for_each_user_page(addr, buf.len(), PageFlag::Read, |page, len| {
+        unsafe { core::ptr::copy_nonoverlapping(page, buf[done..].as_mut_ptr(), len) };
+        done += len;
+    })
+}
+
+fn copy_to_user(addr: usize, data: &[u8]) -> core::result::Result<(), isize> {
+    let mut done = 0;
+    for_each_user_page(addr, data.len(), PageFlag::Write, |page, len| {
+        unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), page, len) };
+        done += len;
+    })
+}
+
+/// returns the nul-terminated path at the user address [addr]
+fn read_path(addr: usize) -> core::result::Result<String, isize> {
+    let mut path = Vec::new();
+    let mut byte = [0];
+    loop {
+        copy_from_user(addr.checked_add(path.len()).ok_or(EFAULT)?, &mut byte)?;
+        match byte[0] {
+            0 => break,
+            _ if path.len() + 1 == PATH_MAX => return Err(ENAMETOOLONG),
+            byte => path.push(byte),
+        }
+    }
+    String::from_utf8(path).map_err(|_| EINVAL)
+}
+
+/// returns the path at [addr] relative to the directory [dirfd]
+///
+/// Processes have no working directory besides the root and directory descriptors are not
+/// supported, so relative paths are only accepted with [AT_FDCWD].
+fn resolve_at(dirfd: isize, addr: usize) -> core::result::Result<String, isize> {
+    let path = read_path(addr)?;
+    if dirfd != AT_FDCWD && !path.starts_with('/') {
+        return Err(EINVAL);
+    }
+    Ok(path)
+}
+
+fn file(fd: usize) -> core::result::Result<Arc<OpenFile>, isize> {
+    current().file(fd).ok_or(EBADF)
+}
+
+fn openat(dirfd: isize, path: usize, flags: usize, mode: usize) -> Result {
+    let path = resolve_at(dirfd, path)?;
+    let mut open_flags = match flags & 0b11 {
+        O_WRONLY => OpenFlags::Write,
+        O_RDWR => OpenFlags::ReadWrite,
+        _ => OpenFlags::Read,
+    };
+    for (bit, flag) in [
+        (O_CREAT, OpenFlags::Create),
+        (O_EXCL, OpenFlags::Exclusive),
+        (O_TRUNC, OpenFlags::Truncate),
+        (O_APPEND, OpenFlags::Append),
+        (O_DIRECTORY, OpenFlags::Directory),
+    ] {
+        if flags & bit != 0 {
+            open_flags |= flag;
+        }
+    }
+
+    let file = vfs::open(&path, open_flags, mode as u32 & 0o7777).map_err(errno)?;
+    current().add_file(Arc::new(file)).ok_or(EMFILE)
+}
+
+fn close(fd: usize) -> Result {
+    current().close_file(fd).map(|_| 0).ok_or(EBADF)
+}
+
+fn read(fd: usize, buf: usize, count: usize) -> Result {
+    let file = file(fd)?;
+    let mut data = vec![0; count.min(MAX_IO)];
+    let read = file.read(&mut data).map_err(errno)?;
+    copy_to_user(buf, &data[..read])?;
+    Ok(read)
+}
+
+fn write(fd: usize, buf: usize, count: usize) -> Result {
+    let file = file(fd)?;
+    let mut data = vec![0; count.min(MAX_IO)];
+    copy_from_user(buf, &mut data)?;
+    file.write(&data).map_err(errno)
+}
+
+fn lseek(fd: usize, offset: isize, whence: usize) -> Result {
+    let position = match whence {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(EINVAL),
+    };
+    let offset = file(fd)?.seek(position).map_err(errno)?;
+    usize::try_from(offset).map_err(|_| EINVAL)
+}
+
+/// returns the `d_type` of `struct linux_dirent64` of [file_type]
+fn dirent_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::CharDevice => 2,
+        FileType::Directory => 4,
+        FileType::BlockDevice => 6,
+        FileType::Regular => 8,
+        FileType::Symlink => 10,
+    }
+}
+
+fn getdents64(fd: usize, buf: usize, count: usize) -> Result {
+    let file = file(fd)?;
+    let mut data = Vec::new();
+    let count = count.min(MAX_IO);
+    while let Some(entry) = file.readdir().map_err(errno)? {
+        // `d_ino`, `d_off`, `d_reclen` and `d_type` precede the nul-terminated name
+        let len = (19 + entry.name.len() + 1).next_multiple_of(8);
+        if data.len() + len > count {
+            // the entry is returned by the next call
+            file.seek(SeekFrom::Current(-1)).map_err(errno)?;
+            if data.is_empty() {
+                return Err(EINVAL);
+            }
+            break;
+        }
+
+        let next = file.seek(SeekFrom::Current(0)).map_err(errno)?;
+        let start = data.len();
+        data.extend_from_slice(&entry.ino.to_le_bytes());
+        data.extend_from_slice(&next.to_le_bytes());
+        data.extend_from_slice(&(len as u16).to_le_bytes());
+        data.push(dirent_type(entry.file_type));
+        data.extend_from_slice(entry.name.as_bytes());
+        // the padding terminates the name
+        data.resize(start + len, 0);
+    }
+    copy_to_user(buf, &data)?;
+    Ok(data.len())
+}
+
+fn ftruncate(fd: usize, length: usize) -> Result {
+    let file = file(fd)?;
+    if !file.flags().contains(OpenFlags::Write) {
+        return Err(EINVAL);
+    }
+    file.inode()
+        .as_file()
+        .ok_or(EINVAL)?
+        .truncate(length as u64)
+        .map(|()| 0)
+        .map_err(errno)
+}
+
+fn fsync(fd: usize) -> Result {
+    file(fd)?.sync().map(|()| 0).map_err(errno)
+}
+
+/// returns `struct statx` of [stat]
+fn statx_of(stat: &Stat) -> [u8; STATX_SIZE] {
+    let mut statx = [0; STATX_SIZE];
+    let file_type: u16 = match stat.file_type {
+        FileType::Regular => 0o100000,
+        FileType::Directory => 0o040000,
+        FileType::Symlink => 0o120000,
+        FileType::CharDevice => 0o020000,
+        FileType::BlockDevice => 0o060000,
+    };
+    let mut put = |offset: usize, bytes: &[u8]| {
+        statx[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0x00, &STATX_BASIC.to_le_bytes());
+    put(0x04, &(PAGE_SIZE as u32).to_le_bytes());
+    put(0x10, &stat.nlink.to_le_bytes());
+    put(0x1c, &(file_type | stat.mode as u16 & 0o7777).to_le_bytes());
+    put(0x20, &stat.ino.to_le_bytes());
+    put(0x28, &stat.size.to_le_bytes());
+    put(0x30, &stat.size.div_ceil(512).to_le_bytes());
+    statx
+}
+
+fn statx(dirfd: isize, path: usize, flags: usize, buf: usize) -> Result {
+    let path = read_path(path)?;
+    let stat = if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        file(dirfd as usize)?.stat()
+    } else {
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EINVAL);
+        }
+        if flags & AT_SYMLINK_NOFOLLOW == 0 {
+            vfs::stat(&path)
+        } else {
+            vfs::resolve(&path, false).and_then(|inode| inode.stat())
+        }
+    };
+    copy_to_user(buf, &statx_of(&stat.map_err(errno)?))?;
+    Ok(0)
+}
+
+fn mkdirat(dirfd: isize, path: usize, mode: usize) -> Result {
+    let path = resolve_at(dirfd, path)?;
+    vfs::mkdir(&path, mode as u32 & 0o7777)
+        .map(|()| 0)
+        .map_err(errno)
+}
+
+fn unlinkat(dirfd: isize, path: usize) -> Result {
+    let path = resolve_at(dirfd, path)?;
+    vfs::unlink(&path).map(|()| 0).map_err(errno)
+}
+
+fn symlinkat(target: usize, dirfd: isize, path: usize) -> Result {
+    let target = read_path(target)?;
+    let path = resolve_at(dirfd, path)?;
+    vfs::symlink(&target, &path).map(|()| 0).map_err(errno)
+}
+
+fn readlinkat(dirfd: isize, path: usize, buf: usize, size: usize) -> Result {
+    let path = resolve_at(dirfd, path)?;
+    let target = vfs::readlink(&path).map_err(errno)?;
+    // the target is truncated to the buffer and not nul-terminated
+    let len = target.len().min(size);
+    copy_to_user(buf, &target.as_bytes()[..len])?;
+    Ok(len)
+}
+
+fn renameat2(
+    old_dirfd: isize,
+    old_path: usize,
+    new_dirfd: isize,
+    new_path: usize,
+    flags: usize,
+) -> Result {
+    if flags != 0 {
+        return Err(EINVAL);
+    }
+    let old_path = resolve_at(old_dirfd, old_path)?;
+    let new_path = resolve_at(new_dirfd, new_path)?;
+    vfs::rename(&old_path, &new_path).map(|()| 0).map_err(errno)
+}
+
+/// mounts the filesystem created by [filesystem::create], mount flags and data are ignored
+fn mount(source: usize, target: usize, fstype: usize) -> Result {
+    let source = read_path(source)?;
+    let target = read_path(target)?;
+    let fstype = read_path(fstype)?;
+    let fs = filesystem::create(&fstype, &source).map_err(errno)?;
+    vfs::mount(&target, fs).map(|()| 0).map_err(errno)
+}
+
+fn umount2(target: usize) -> Result {
+    let target = read_path(target)?;
+    vfs::umount(&target).map(|()| 0).map_err(errno)
+}
+
+/// changes the permissions of the user pages of `addr..addr + len` to [prot]
+///
+/// Pages without any permission can't be expressed by a leaf entry, so `PROT_NONE` is rejected.
+fn mprotect(addr: usize, len: usize, prot: usize) -> Result {
+    let all = PROT_READ | PROT_WRITE | PROT_EXEC;
+    if !addr.is_multiple_of(PAGE_SIZE) || prot & !all != 0 || prot == 0 {
+        return Err(EINVAL);
+    }
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(ENOMEM)?;
+
+    let mut flags = proc::USER_PAGE;
+    for (bit, flag) in [
+        (PROT_READ, PageFlag::Read),
+        // writable pages have to be readable
+        (PROT_WRITE, PageFlag::Read | PageFlag::Write),
+        (PROT_EXEC, PageFlag::Execute),
+    ] {
+        if prot & bit != 0 {
+            flags |= flag;
+        }
+    }
+
+    let proc = current();
+    // nothing changes unless the whole range is mapped
+    for vaddr in (addr..end).step_by(PAGE_SIZE) {
+        match memory::translate(proc.page_table(), VAddr(vaddr)) {
+            Some((_, existing)) if existing.contains(PageFlag::User) => {}
+            _ => return Err(ENOMEM),
+        }
+    }
+    for vaddr in (addr..end).step_by(PAGE_SIZE) {
+        memory::protect_page(proc.page_table(), VAddr(vaddr), flags, proc.asid());
+    }
+    Ok(0)
+}
+
+fn reboot(magic1: usize, magic2: usize, cmd: usize) -> Result {
+    if magic1 as u32 != LINUX_REBOOT_MAGIC1 as u32 || magic2 != LINUX_REBOOT_MAGIC2 {
+        return Err(EINVAL);
+    }
+    match cmd {
+        LINUX_REBOOT_CMD_RESTART => power::reboot(),
+        LINUX_REBOOT_CMD_HALT => power::halt(),
+        LINUX_REBOOT_CMD_POWER_OFF => power::shutdown(ResetReason::None),
+        _ => Err(EINVAL),
+    }
+}
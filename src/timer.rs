@@ -0,0 +1,175 @@
+use crate::cpu::{self, Feature};
+use crate::driver::ProbeError;
+use crate::sync::SpinLock;
+use crate::{dtb, println, sbi};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use macros::driver;
+use riscv::register::time;
+
+crate::kernel_param!(
+    /// timer interrupts per second on the boot hart (`timer.hz=`)
+    pub static HZ: usize = "timer.hz", 100
+);
+
+const EID_TIME: usize = 0x54494D45;
+
+/// `time` ticks per second, from the fdt
+static FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+/// `time` ticks between two timer interrupts
+static INTERVAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Function run every [Periodic::interval] ticks
+struct Periodic {
+    interval: u64,
+    next: u64,
+    callback: fn(),
+    /// whether the callback runs from [run_deferred] instead of the interrupt
+    deferred: bool,
+    /// whether a deferred callback is waiting for [run_deferred]
+    due: bool,
+}
+
+static PERIODIC: SpinLock<Vec<Periodic>> = SpinLock::new(Vec::new());
+
+/// starts the periodic timer interrupt on the boot hart
+///
+/// The deadline is programmed through `stimecmp` with Sstc, through the sbi otherwise.
+#[driver(name = "timer", level = Timer)]
+fn probe() -> Result<(), ProbeError> {
+    let frequency = dtb::fdt()
+        .cpus()
+        .next()
+        .map_or(0, |cpu| cpu.timebase_frequency());
+    if frequency == 0 {
+        return Err(ProbeError::Unsupported("no timebase-frequency"));
+    }
+    let interval = (frequency / HZ.get().max(1)).max(1);
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    INTERVAL.store(interval, Ordering::Relaxed);
+
+    println!(
+        "timer: {frequency} Hz timebase, {} interrupts per second through {}",
+        HZ.get(),
+        if cpu::has_feature(Feature::Sstc) {
+            "sstc"
+        } else {
+            "sbi"
+        }
+    );
+
+    set_deadline(now() + interval as u64);
+    unsafe { riscv::register::sie::set_stimer() };
+    Ok(())
+}
+
+/// returns the current value of `time`
+pub fn now() -> u64 {
+    time::read64()
+}
+
+/// converts [ms] milliseconds to `time` ticks, at least one
+pub fn ms_to_ticks(ms: usize) -> u64 {
+    (ms as u64 * FREQUENCY.load(Ordering::Relaxed) as u64 / 1000).max(1)
+}
+
+/// runs [callback] from the timer interrupt about every [interval_ms] milliseconds
+///
+/// Must be called once the timer is probed.
+pub fn periodic(interval_ms: usize, callback: fn()) {
+    add(interval_ms, callback, false);
+}
+
+/// runs [callback] from [run_deferred] about every [interval_ms] milliseconds, the timer
+/// interrupt only marks it due
+///
+/// For work that takes locks or waits for devices, which must not happen in interrupt context.
+/// Must be called once the timer is probed.
+pub fn deferred(interval_ms: usize, callback: fn()) {
+    add(interval_ms, callback, true);
+}
+
+fn add(interval_ms: usize, callback: fn(), deferred: bool) {
+    let interval = ms_to_ticks(interval_ms);
+    PERIODIC.lock().push(Periodic {
+        interval,
+        next: now() + interval,
+        callback,
+        deferred,
+        due: false,
+    });
+}
+
+/// runs the deferred callbacks marked due by the timer interrupt
+///
+/// Called outside of interrupt context with interrupts enabled: from the idle loop of each hart
+/// and before returning to U-mode. Each due callback runs once, on the first hart to get here.
+pub fn run_deferred() {
+    let due: Vec<fn()> = PERIODIC
+        .lock()
+        .iter_mut()
+        .filter_map(|periodic| core::mem::take(&mut periodic.due).then_some(periodic.callback))
+        .collect();
+    for callback in due {
+        callback();
+    }
+}
+
+/// handles a supervisor timer interrupt
+pub fn handle() {
+    let now = now();
+    set_deadline(now + INTERVAL.load(Ordering::Relaxed) as u64);
+
+    // run the callbacks unlocked, they may register others
+    let due: Vec<fn()> = PERIODIC
+        .lock()
+        .iter_mut()
+        .filter(|periodic| now >= periodic.next)
+        .filter_map(|periodic| {
+            periodic.next = now + periodic.interval;
+            periodic.due = periodic.deferred;
+            (!periodic.deferred).then_some(periodic.callback)
+        })
+        .collect();
+    for callback in due {
+        callback();
+    }
+}
+
+/// programs the next timer interrupt of the calling hart at [deadline], which also clears the
+/// pending one
+fn set_deadline(deadline: u64) {
+    if cpu::has_feature(Feature::Sstc) {
+        #[cfg(target_pointer_width = "32")]
+        unsafe {
+            // keep the deadline in the future while its halves are written
+            asm!(
+                "csrw 0x15d, {max}",
+                "csrw 0x14d, {low}",
+                "csrw 0x15d, {high}",
+                max = in(reg) usize::MAX,
+                low = in(reg) deadline as usize,
+                high = in(reg) (deadline >> 32) as usize,
+            );
+        }
+        #[cfg(target_pointer_width = "64")]
+        unsafe {
+            asm!("csrw 0x14d, {}", in(reg) deadline as usize);
+        }
+    } else {
+        #[cfg(target_pointer_width = "32")]
+        sbi::sbi_call(
+            deadline as usize,
+            (deadline >> 32) as usize,
+            0,
+            0,
+            0,
+            0,
+            0,
+            EID_TIME,
+        );
+        #[cfg(target_pointer_width = "64")]
+        sbi::sbi_call(deadline as usize, 0, 0, 0, 0, 0, 0, EID_TIME);
+    }
+}
@@ -0,0 +1,504 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::queue::{Buffer, UnknownChain, Virtqueue};
+use super::{Transport, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED};
+use crate::driver::ProbeError;
+use crate::filesystem::block::{self, BlockDevice};
+use crate::filesystem::vfs::{self, FsError};
+use crate::println;
+use crate::sync::{Arc, SpinLock};
+
+const SECTOR_SIZE: usize = 512;
+/// Most entries of the request queue
+const QUEUE_SIZE: u16 = 128;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_GEOMETRY: u64 = 1 << 4;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1 << 11;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+/// Features the driver accepts when the device offers them
+const VIRTIO_BLK_FEATURES: u64 = VIRTIO_BLK_F_SEG_MAX
+    | VIRTIO_BLK_F_GEOMETRY
+    | VIRTIO_BLK_F_RO
+    | VIRTIO_BLK_F_BLK_SIZE
+    | VIRTIO_BLK_F_FLUSH
+    | VIRTIO_BLK_F_CONFIG_WCE
+    | VIRTIO_BLK_F_DISCARD
+    | VIRTIO_BLK_F_WRITE_ZEROES
+    | VIRTIO_F_INDIRECT_DESC
+    | VIRTIO_F_EVENT_IDX
+    | VIRTIO_F_RING_PACKED;
+/// Offsets of the fields of the device configuration
+const VIRTIO_BLK_CONFIG_CAPACITY: usize = 0;
+const VIRTIO_BLK_CONFIG_SEG_MAX: usize = 12;
+const VIRTIO_BLK_CONFIG_GEOMETRY: usize = 16;
+const VIRTIO_BLK_CONFIG_BLK_SIZE: usize = 20;
+const VIRTIO_BLK_CONFIG_WRITEBACK: usize = 32;
+const VIRTIO_BLK_CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const VIRTIO_BLK_CONFIG_MAX_WRITE_ZEROES_SECTORS: usize = 48;
+const VIRTIO_BLK_T_IN: usize = 0;
+const VIRTIO_BLK_T_OUT: usize = 1;
+const VIRTIO_BLK_T_FLUSH: usize = 4;
+const VIRTIO_BLK_T_GET_ID: usize = 8;
+const VIRTIO_BLK_T_DISCARD: usize = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: usize = 13;
+/// Length of the serial number returned by [VIRTIO_BLK_T_GET_ID]
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// Status of a request the device has not completed
+const STATUS_PENDING: u8 = 0xff;
+/// Most data buffers of a request built by merging adjacent ones
+const MAX_SEGMENTS: usize = 64;
+
+#[repr(C)]
+struct VirtqBlkRequest {
+    t: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Range of a [VIRTIO_BLK_T_DISCARD] or [VIRTIO_BLK_T_WRITE_ZEROES] request
+#[repr(C)]
+struct VirtioBlkDiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+/// Transfer of a caller waiting for its completion
+struct Request {
+    t: u32,
+    sector: u64,
+    data: *mut u8,
+    len: usize,
+    /// status of the device, [STATUS_PENDING] until [done] is set
+    status: AtomicU8,
+    done: AtomicBool,
+}
+
+/// Requests submitted to the device as one chain, several if they were merged
+struct InFlight {
+    header: VirtqBlkRequest,
+    status: u8,
+    requests: Vec<*const Request>,
+}
+
+struct Queue {
+    virtq: Virtqueue,
+    /// requests waiting for free descriptors
+    pending: VecDeque<*const Request>,
+    /// chains handed to the device, indexed by their id
+    in_flight: Vec<Option<Box<InFlight>>>,
+    /// most data descriptors of a chain, bounded by the `seg_max` of the device
+    max_segments: usize,
+    /// set once the device used a chain it was not given, every request fails afterward
+    broken: bool,
+}
+
+// requests are owned by their callers, which wait for them to complete before returning
+unsafe impl Send for Queue {}
+
+impl Queue {
+    /// moves pending requests to the virtqueue while descriptors are free, merging requests of
+    /// the same type on adjacent sectors into one chain
+    fn dispatch(&mut self, transport: &dyn Transport) {
+        if self.broken {
+            self.fail();
+            return;
+        }
+        while let Some(&first) = self.pending.front() {
+            let first = unsafe { &*first };
+            let mut requests = Vec::from([first as *const Request]);
+            let mut end = first.sector + (first.len / SECTOR_SIZE) as u64;
+            let mergeable = first.t == VIRTIO_BLK_T_IN as u32 || first.t == VIRTIO_BLK_T_OUT as u32;
+            while mergeable
+                && requests.len() < self.max_segments
+                && self.virtq.can_add(requests.len() + 3)
+                && let Some(position) = self.pending.iter().skip(1).position(|&request| {
+                    let request = unsafe { &*request };
+                    request.t == first.t && request.sector == end
+                })
+            {
+                let request = self.pending.remove(position + 1).unwrap();
+                end += (unsafe { &*request }.len / SECTOR_SIZE) as u64;
+                requests.push(request);
+            }
+
+            let mut in_flight = Box::new(InFlight {
+                header: VirtqBlkRequest {
+                    t: first.t,
+                    reserved: 0,
+                    sector: first.sector,
+                },
+                status: STATUS_PENDING,
+                requests,
+            });
+            let device_writes =
+                first.t == VIRTIO_BLK_T_IN as u32 || first.t == VIRTIO_BLK_T_GET_ID as u32;
+            let mut buffers = Vec::with_capacity(in_flight.requests.len() + 2);
+            buffers.push(Buffer {
+                addr: &in_flight.header as *const VirtqBlkRequest as usize,
+                len: size_of::<VirtqBlkRequest>() as u32,
+                device_writes: false,
+            });
+            for &request in &in_flight.requests {
+                let request = unsafe { &*request };
+                if request.len != 0 {
+                    buffers.push(Buffer {
+                        addr: request.data as usize,
+                        len: request.len as u32,
+                        device_writes,
+                    });
+                }
+            }
+            buffers.push(Buffer {
+                addr: &mut in_flight.status as *mut u8 as usize,
+                len: 1,
+                device_writes: true,
+            });
+
+            let Some(head) = self.virtq.add(&buffers) else {
+                // the merged requests wait for descriptors as well
+                for &request in in_flight.requests.iter().skip(1).rev() {
+                    self.pending.insert(1, request);
+                }
+                break;
+            };
+            self.pending.pop_front();
+            self.in_flight[head as usize] = Some(in_flight);
+        }
+        self.virtq.notify(transport);
+    }
+
+    /// completes the requests of every chain used by the device, waking their callers
+    fn reap(&mut self, transport: &dyn Transport) {
+        let mut reaped = false;
+        while !self.broken {
+            let head = match self.virtq.pop_used() {
+                Ok(None) => break,
+                Ok(Some((head, _))) => head as u32,
+                Err(UnknownChain(id)) => id,
+            };
+            reaped = true;
+            let Some(in_flight) = self.in_flight.get_mut(head as usize).and_then(Option::take)
+            else {
+                println!("virtio-blk: device used unknown chain {head}, failing every request");
+                self.broken = true;
+                self.fail();
+                break;
+            };
+
+            let status = unsafe { (&raw const in_flight.status).read_volatile() };
+            complete(&in_flight.requests, status);
+        }
+
+        if reaped {
+            transport.ack_interrupt();
+        }
+    }
+
+    /// completes the requests in flight and pending with an error
+    ///
+    /// The device may still write to the buffers of the requests in flight, but it cannot be
+    /// trusted to ever complete them.
+    fn fail(&mut self) {
+        for in_flight in self.in_flight.iter_mut().filter_map(Option::take) {
+            complete(&in_flight.requests, VIRTIO_BLK_S_IOERR);
+        }
+        let pending: Vec<_> = self.pending.drain(..).collect();
+        complete(&pending, VIRTIO_BLK_S_IOERR);
+    }
+}
+
+/// completes [requests] with [status], waking their callers
+fn complete(requests: &[*const Request], status: u8) {
+    for &request in requests {
+        let request = unsafe { &*request };
+        request.status.store(status, Ordering::Relaxed);
+        request.done.store(true, Ordering::Release);
+    }
+}
+
+/// virtio-blk device
+///
+/// Callers queue their requests and poll for completion. Whichever caller polls the used ring
+/// completes the requests of the others, so several harts keep requests in flight at once. The
+/// device interrupts are left suppressed, as nothing routes them to the driver.
+pub struct VirtioBlk {
+    name: String,
+    transport: Box<dyn Transport>,
+    /// features negotiated with the device
+    features: u64,
+    /// size of a logical block, a multiple of the sector size
+    block_size: usize,
+    /// number of logical blocks
+    block_count: u64,
+    /// most sectors of a discard or write zeroes request
+    max_discard_sectors: u32,
+    max_write_zeroes_sectors: u32,
+    queue: SpinLock<Queue>,
+}
+
+impl VirtioBlk {
+    fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    /// returns the serial number of the device, none if it has none
+    pub fn serial(&self) -> Option<String> {
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        self.request(VIRTIO_BLK_T_GET_ID, 0, id.as_mut_ptr(), id.len())
+            .ok()?;
+        // NUL terminated unless it takes the whole buffer
+        let len = id.iter().position(|&byte| byte == 0).unwrap_or(id.len());
+        let serial = core::str::from_utf8(&id[..len]).ok()?;
+        (!serial.is_empty()).then(|| String::from(serial))
+    }
+
+    /// returns the first sector of [block], checking the [count] blocks from it lie on the device
+    fn sector(&self, block: u64, count: u64) -> vfs::Result<u64> {
+        match block.checked_add(count) {
+            Some(end) if end <= self.block_count => {
+                Ok(block * (self.block_size / SECTOR_SIZE) as u64)
+            }
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    /// issues discard or write zeroes requests of [t] for the [count] blocks from [block], each
+    /// of at most [max_sectors]
+    fn request_range(&self, t: usize, block: u64, count: u64, max_sectors: u32) -> vfs::Result<()> {
+        let sectors_per_block = (self.block_size / SECTOR_SIZE) as u64;
+        let mut sector = self.sector(block, count)?;
+        let mut remaining = count * sectors_per_block;
+        // keep each request a whole number of blocks
+        let max_sectors =
+            (max_sectors as u64 / sectors_per_block * sectors_per_block).max(sectors_per_block);
+        while remaining != 0 {
+            let num_sectors = remaining.min(max_sectors).min(u32::MAX as u64);
+            let mut range = VirtioBlkDiscardWriteZeroes {
+                sector,
+                num_sectors: num_sectors as u32,
+                flags: 0,
+            };
+            self.request(
+                t,
+                0,
+                &mut range as *mut VirtioBlkDiscardWriteZeroes as *mut u8,
+                size_of::<VirtioBlkDiscardWriteZeroes>(),
+            )?;
+            sector += num_sectors;
+            remaining -= num_sectors;
+        }
+        Ok(())
+    }
+
+    /// transfers [len] bytes at [data] from or to the sectors starting at [sector]
+    fn request(&self, t: usize, sector: u64, data: *mut u8, len: usize) -> vfs::Result<()> {
+        let request = Request {
+            t: t as u32,
+            sector,
+            data,
+            len,
+            status: AtomicU8::new(STATUS_PENDING),
+            done: AtomicBool::new(false),
+        };
+
+        {
+            let mut queue = self.queue.lock();
+            queue.pending.push_back(&request);
+            queue.dispatch(&*self.transport);
+        }
+        // the request must stay alive until the device is done with it
+        while !request.done.load(Ordering::Acquire) {
+            let mut queue = self.queue.lock();
+            queue.reap(&*self.transport);
+            queue.dispatch(&*self.transport);
+            drop(queue);
+            core::hint::spin_loop();
+        }
+
+        match request.status.load(Ordering::Relaxed) {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_UNSUPP => Err(FsError::Unsupported),
+            _ => Err(FsError::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> vfs::Result<()> {
+        let sector = self.sector(block, (buf.len() / self.block_size) as u64)?;
+        self.request(VIRTIO_BLK_T_IN, sector, buf.as_mut_ptr(), buf.len())
+    }
+
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> vfs::Result<()> {
+        if self.read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        let sector = self.sector(block, (buf.len() / self.block_size) as u64)?;
+        // the device only reads from the buffer of an OUT request
+        self.request(VIRTIO_BLK_T_OUT, sector, buf.as_ptr() as *mut u8, buf.len())
+    }
+
+    /// without [VIRTIO_BLK_F_FLUSH] the device writes through, so completed writes are stable
+    fn flush(&self) -> vfs::Result<()> {
+        if !self.has_feature(VIRTIO_BLK_F_FLUSH) {
+            return Ok(());
+        }
+        self.request(VIRTIO_BLK_T_FLUSH, 0, core::ptr::null_mut(), 0)
+    }
+
+    fn read_only(&self) -> bool {
+        self.has_feature(VIRTIO_BLK_F_RO)
+    }
+
+    fn discard(&self, block: u64, count: u64) -> vfs::Result<()> {
+        if !self.has_feature(VIRTIO_BLK_F_DISCARD) {
+            return Err(FsError::Unsupported);
+        }
+        if self.read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        self.request_range(VIRTIO_BLK_T_DISCARD, block, count, self.max_discard_sectors)
+    }
+
+    fn write_zeroes(&self, block: u64, count: u64) -> vfs::Result<()> {
+        if self.read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.has_feature(VIRTIO_BLK_F_WRITE_ZEROES) {
+            // the default writes the zeros
+            let zeros = alloc::vec![0; self.block_size];
+            for block in block..block + count {
+                self.write_blocks(block, &zeros)?;
+            }
+            return Ok(());
+        }
+        self.request_range(
+            VIRTIO_BLK_T_WRITE_ZEROES,
+            block,
+            count,
+            self.max_write_zeroes_sectors,
+        )
+    }
+}
+
+/// names the device behind [transport] `vda`, `vdb`, ... and registers it
+pub fn probe(transport: Box<dyn Transport>) -> Result<(), ProbeError> {
+    /// Number of virtio-blk devices probed so far
+    static DEVICES: SpinLock<usize> = SpinLock::new(0);
+
+    let index = {
+        let mut devices = DEVICES.lock();
+        *devices += 1;
+        *devices - 1
+    };
+    let name = format!("vd{}", (b'a' + index as u8) as char);
+    block::register(Arc::new(initialize(name, transport)?));
+    Ok(())
+}
+
+fn initialize(name: String, transport: Box<dyn Transport>) -> Result<VirtioBlk, ProbeError> {
+    let features = super::negotiate(&*transport, VIRTIO_BLK_FEATURES)?;
+    let virtq = Virtqueue::new(&*transport, 0, QUEUE_SIZE, features)?;
+
+    let config = |offset| transport.read_config_u32(offset);
+    let block_size = if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+        config(VIRTIO_BLK_CONFIG_BLK_SIZE) as usize
+    } else {
+        SECTOR_SIZE
+    };
+    if block_size < SECTOR_SIZE || !block_size.is_power_of_two() {
+        return Err(ProbeError::Unsupported("invalid block size"));
+    }
+    let max_segments = if features & VIRTIO_BLK_F_SEG_MAX != 0 {
+        (config(VIRTIO_BLK_CONFIG_SEG_MAX) as usize).clamp(1, MAX_SEGMENTS)
+    } else {
+        1
+    };
+    let (max_discard_sectors, max_write_zeroes_sectors) = (
+        config(VIRTIO_BLK_CONFIG_MAX_DISCARD_SECTORS),
+        config(VIRTIO_BLK_CONFIG_MAX_WRITE_ZEROES_SECTORS),
+    );
+    // with a flush command a volatile write cache is safe, otherwise writes must go through
+    if features & VIRTIO_BLK_F_CONFIG_WCE != 0 {
+        let writeback = features & VIRTIO_BLK_F_FLUSH != 0;
+        transport.write_config_u8(VIRTIO_BLK_CONFIG_WRITEBACK, writeback as u8);
+    }
+    let geometry =
+        (features & VIRTIO_BLK_F_GEOMETRY != 0).then(|| config(VIRTIO_BLK_CONFIG_GEOMETRY));
+
+    super::driver_ok(&*transport);
+
+    // the capacity is always given in 512-byte sectors
+    let capacity = transport.read_config_u64(VIRTIO_BLK_CONFIG_CAPACITY);
+    let in_flight = (0..virtq.size()).map(|_| None).collect();
+    let device = VirtioBlk {
+        name,
+        transport,
+        features,
+        block_size,
+        block_count: capacity * SECTOR_SIZE as u64 / block_size as u64,
+        max_discard_sectors,
+        max_write_zeroes_sectors,
+        queue: SpinLock::new(Queue {
+            virtq,
+            pending: VecDeque::new(),
+            in_flight,
+            max_segments,
+            broken: false,
+        }),
+    };
+
+    println!(
+        "virtio-blk: {}, capacity {}, features {features:#x}, {} queue{}",
+        device.name,
+        capacity * SECTOR_SIZE as u64,
+        match device.queue.lock().virtq {
+            Virtqueue::Split(_) => "split",
+            Virtqueue::Packed(_) => "packed",
+        },
+        if device.read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    if let Some(geometry) = geometry {
+        println!(
+            "virtio-blk: {} geometry {} cylinders, {} heads, {} sectors",
+            device.name,
+            geometry & 0xffff,
+            (geometry >> 16) & 0xff,
+            geometry >> 24
+        );
+    }
+    if let Some(serial) = device.serial() {
+        println!("virtio-blk: {} serial {serial}", device.name);
+    }
+    Ok(device)
+}
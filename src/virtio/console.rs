@@ -0,0 +1,485 @@
+use super::queue::{Buffer, Virtqueue};
+use super::{Transport, VIRTIO_F_EVENT_IDX, VIRTIO_F_RING_PACKED};
+use crate::driver::ProbeError;
+use crate::filesystem::chardev::{self, CharDevice};
+use crate::filesystem::vfs::{self, FsError};
+use crate::sync::{Arc, SpinLock};
+use crate::{println, timer};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+/// Features the driver accepts when the device offers them
+const VIRTIO_CONSOLE_FEATURES: u64 =
+    VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_F_EVENT_IDX | VIRTIO_F_RING_PACKED;
+/// Offsets of the fields of the device configuration
+const VIRTIO_CONSOLE_CONFIG_SIZE: usize = 0;
+const VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS: usize = 4;
+/// Events of control messages
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+/// Length of a control message without its data, an id, an event and a value
+const CONTROL_HEADER_SIZE: usize = 8;
+/// Most ports of a device, queues are set up for each of them before the device is ready
+const MAX_PORTS: u32 = 8;
+const QUEUE_SIZE: u16 = 16;
+/// Receive buffers kept available to the device on each receive queue
+const RECEIVE_BUFFERS: usize = 8;
+const RECEIVE_BUFFER_SIZE: usize = 512;
+/// Most bytes received on a port kept until they are read, older ones are dropped
+const MAX_PENDING: usize = 4096;
+/// Milliseconds between two polls of the devices by the timer
+const POLL_MS: usize = 50;
+
+/// Queue the device writes incoming data to, kept filled with buffers
+struct Receiver {
+    queue: Virtqueue,
+    /// buffers handed to the device, indexed by their chain id
+    buffers: Vec<Option<Box<[u8]>>>,
+    /// data received on a port but not read yet
+    pending: VecDeque<u8>,
+}
+
+impl Receiver {
+    /// sets up the queue [index] with its buffers, the device is notified of them once it is ready
+    fn new(transport: &dyn Transport, index: u16, features: u64) -> Result<Self, ProbeError> {
+        let queue = Virtqueue::new(transport, index, QUEUE_SIZE, features)?;
+        let mut receiver = Self {
+            buffers: (0..queue.size()).map(|_| None).collect(),
+            queue,
+            pending: VecDeque::new(),
+        };
+        for _ in 0..RECEIVE_BUFFERS.min(receiver.buffers.len()) {
+            receiver.post(vec![0; RECEIVE_BUFFER_SIZE].into_boxed_slice());
+        }
+        Ok(receiver)
+    }
+
+    fn post(&mut self, buffer: Box<[u8]>) {
+        let id = self
+            .queue
+            .add(&[Buffer {
+                addr: buffer.as_ptr() as usize,
+                len: buffer.len() as u32,
+                device_writes: true,
+            }])
+            .expect("virtio-console: receive queue is full");
+        self.buffers[id as usize] = Some(buffer);
+    }
+
+    /// returns the next message written by the device, handing its buffer back
+    fn receive(&mut self, transport: &dyn Transport) -> Option<Vec<u8>> {
+        let (id, len) = self.queue.pop_used().ok().flatten()?;
+        let buffer = self.buffers[id as usize]
+            .take()
+            .expect("virtio-console: device used an unknown buffer");
+        let message = buffer[..(len as usize).min(buffer.len())].to_vec();
+        self.post(buffer);
+        self.queue.notify(transport);
+        Some(message)
+    }
+
+    /// moves the messages written by the device to [Receiver::pending]
+    fn receive_pending(&mut self, transport: &dyn Transport) {
+        while let Some(message) = self.receive(transport) {
+            self.pending.extend(message);
+        }
+        let excess = self.pending.len().saturating_sub(MAX_PENDING);
+        self.pending.drain(..excess);
+    }
+}
+
+/// sends [data] on [queue], waiting for the device to consume it
+fn transmit(queue: &mut Virtqueue, transport: &dyn Transport, data: &[u8]) {
+    // the caller holds the queue, so it is empty
+    queue
+        .add(&[Buffer {
+            addr: data.as_ptr() as usize,
+            len: data.len() as u32,
+            device_writes: false,
+        }])
+        .expect("virtio-console: transmit queue is full");
+    queue.notify(transport);
+    while let Ok(None) = queue.pop_used() {
+        core::hint::spin_loop();
+    }
+}
+
+struct PortQueues {
+    receive: SpinLock<Receiver>,
+    transmit: SpinLock<Virtqueue>,
+}
+
+/// Port as announced by control messages
+#[derive(Default)]
+struct PortState {
+    added: bool,
+    removed: bool,
+    registered: bool,
+    /// the port is a console, named `hvc<n>`
+    console: bool,
+    /// name given by the host, e.g. `org.qemu.guest_agent.0`
+    name: Option<String>,
+    /// whether a program on the host has the port open
+    host_connected: bool,
+}
+
+/// Control queues of a device with [VIRTIO_CONSOLE_F_MULTIPORT]
+struct Control {
+    receive: SpinLock<ControlReceiver>,
+    state: SpinLock<ControlState>,
+}
+
+/// Receive side of the control queues, drained by the timer
+struct ControlReceiver {
+    receiver: Receiver,
+    /// control messages received but not handled yet
+    messages: VecDeque<Vec<u8>>,
+}
+
+/// Transmit side of the control queues and the ports the messages announced
+struct ControlState {
+    transmit: Virtqueue,
+    ports: Vec<PortState>,
+}
+
+impl ControlState {
+    fn send(&mut self, transport: &dyn Transport, id: u32, event: u16, value: u16) {
+        let mut message = [0; CONTROL_HEADER_SIZE];
+        message[..4].copy_from_slice(&id.to_le_bytes());
+        message[4..6].copy_from_slice(&event.to_le_bytes());
+        message[6..].copy_from_slice(&value.to_le_bytes());
+        transmit(&mut self.transmit, transport, &message);
+    }
+}
+
+/// virtio-console device, each of its ports is registered as a character device
+///
+/// Without [VIRTIO_CONSOLE_F_MULTIPORT] the device has a single console port. With it, ports are
+/// added, named and removed by the host through control messages. As there are no interrupts,
+/// the timer interrupt drains the receive queues so they do not fill up while nothing reads. The
+/// control messages are handled outside of the interrupt, by [timer::deferred] and whenever a port
+/// is accessed, as replying to them waits for the device.
+pub struct VirtioConsole {
+    /// number of the device, naming its unnamed ports `vport<index>p<port>`
+    index: usize,
+    transport: Box<dyn Transport>,
+    /// queues of the ports, indexed by port id
+    ports: Vec<PortQueues>,
+    control: Option<Control>,
+}
+
+impl VirtioConsole {
+    /// moves the control messages written by the device to [ControlReceiver::messages]
+    fn receive_control(&self) {
+        let Some(control) = &self.control else {
+            return;
+        };
+        let mut receive = control.receive.lock();
+        while let Some(message) = receive.receiver.receive(&*self.transport) {
+            receive.messages.push_back(message);
+        }
+    }
+}
+
+/// Devices polled by the timer
+static CONSOLES: SpinLock<Vec<Arc<VirtioConsole>>> = SpinLock::new(Vec::new());
+
+/// moves the data and the control messages received by every device to their ports and control
+/// queues, called from the timer interrupt
+fn poll() {
+    let consoles = CONSOLES.lock().clone();
+    for console in consoles {
+        console.receive_control();
+        for port in &console.ports {
+            port.receive.lock().receive_pending(&*console.transport);
+        }
+    }
+}
+
+/// handles the control messages received by every device
+fn poll_controls() {
+    let consoles = CONSOLES.lock().clone();
+    for console in consoles {
+        poll_control(&console);
+    }
+}
+
+/// handles the pending control messages of [console], registering the ports it adds
+fn poll_control(console: &Arc<VirtioConsole>) {
+    let Some(control) = &console.control else {
+        return;
+    };
+    let transport = &*console.transport;
+    let mut control = control.state.lock();
+
+    loop {
+        console.receive_control();
+        let Some(message) = control_message(console) else {
+            break;
+        };
+        if message.len() < CONTROL_HEADER_SIZE {
+            continue;
+        }
+        let id = u32::from_le_bytes(message[..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        let data = &message[CONTROL_HEADER_SIZE..];
+
+        let Some(port) = control.ports.get_mut(id as usize) else {
+            if event == VIRTIO_CONSOLE_DEVICE_ADD {
+                println!("virtio-console: ignoring port {id}, at most {MAX_PORTS} are supported");
+                control.send(transport, id, VIRTIO_CONSOLE_PORT_READY, 0);
+            }
+            continue;
+        };
+        match event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                *port = PortState {
+                    added: true,
+                    ..PortState::default()
+                };
+                control.send(transport, id, VIRTIO_CONSOLE_PORT_READY, 1);
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => port.removed = true,
+            VIRTIO_CONSOLE_CONSOLE_PORT => port.console = true,
+            VIRTIO_CONSOLE_PORT_OPEN => port.host_connected = value != 0,
+            VIRTIO_CONSOLE_PORT_NAME => {
+                let len = data
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(data.len());
+                port.name = core::str::from_utf8(&data[..len]).ok().map(String::from);
+            }
+            VIRTIO_CONSOLE_RESIZE if data.len() >= 4 => {
+                // struct virtio_console_resize is rows then cols, unlike the configuration
+                let rows = u16::from_le_bytes([data[0], data[1]]);
+                let cols = u16::from_le_bytes([data[2], data[3]]);
+                println!("virtio-console: port {id} resized to {cols}x{rows}");
+            }
+            _ => {}
+        }
+    }
+
+    // the name and console messages follow the port ready reply, so they have been handled
+    for id in 0..control.ports.len() {
+        let port = &mut control.ports[id];
+        if !port.added || port.removed || port.registered {
+            continue;
+        }
+        port.registered = true;
+        let name = if port.console {
+            console_name()
+        } else {
+            port.name
+                .clone()
+                .filter(|name| chardev::find(name).is_none())
+                .unwrap_or_else(|| format!("vport{}p{id}", console.index))
+        };
+        chardev::register(Arc::new(Port {
+            console: console.clone(),
+            id: id as u32,
+            name,
+        }));
+        control.send(transport, id as u32, VIRTIO_CONSOLE_PORT_OPEN, 1);
+    }
+}
+
+/// returns the oldest control message of [console] not handled yet
+fn control_message(console: &VirtioConsole) -> Option<Vec<u8>> {
+    console
+        .control
+        .as_ref()?
+        .receive
+        .lock()
+        .messages
+        .pop_front()
+}
+
+/// returns the next name of a console port, `hvc0`, `hvc1`, ...
+fn console_name() -> String {
+    static NEXT_HVC: SpinLock<usize> = SpinLock::new(0);
+    let mut next = NEXT_HVC.lock();
+    *next += 1;
+    format!("hvc{}", *next - 1)
+}
+
+/// Port of a virtio-console
+struct Port {
+    console: Arc<VirtioConsole>,
+    id: u32,
+    name: String,
+}
+
+impl Port {
+    /// returns the queues of the port and whether the host side is open, failing if the host
+    /// removed the port
+    fn queues(&self) -> vfs::Result<(&PortQueues, bool)> {
+        poll_control(&self.console);
+        let connected = match &self.console.control {
+            Some(control) => {
+                let control = control.state.lock();
+                let port = &control.ports[self.id as usize];
+                if port.removed {
+                    return Err(FsError::Io);
+                }
+                port.console || port.host_connected
+            }
+            // the single port is a console
+            None => true,
+        };
+        Ok((&self.console.ports[self.id as usize], connected))
+    }
+}
+
+impl CharDevice for Port {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// blocks until data is received, then returns what is available
+    ///
+    /// Returns 0 once no program on the host has the port open and nothing is left to read, and
+    /// fails once the host removed the port.
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let (queues, connected) = self.queues()?;
+            let mut receiver = queues.receive.lock();
+            receiver.receive_pending(&*self.console.transport);
+            if !receiver.pending.is_empty() {
+                let len = buf.len().min(receiver.pending.len());
+                for (byte, value) in buf.iter_mut().zip(receiver.pending.drain(..len)) {
+                    *byte = value;
+                }
+                return Ok(len);
+            }
+            if !connected {
+                return Ok(0);
+            }
+            drop(receiver);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// discards the data while no program on the host has the port open
+    fn write(&self, buf: &[u8]) -> vfs::Result<usize> {
+        let (queues, connected) = self.queues()?;
+        if connected && !buf.is_empty() {
+            transmit(&mut queues.transmit.lock(), &*self.console.transport, buf);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// sets up the device behind [transport] and registers its ports
+pub fn probe(transport: Box<dyn Transport>) -> Result<(), ProbeError> {
+    /// Number of virtio-console devices probed so far
+    static DEVICES: SpinLock<usize> = SpinLock::new(0);
+
+    let features = super::negotiate(&*transport, VIRTIO_CONSOLE_FEATURES)?;
+    let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    let port_count = if multiport {
+        transport
+            .read_config_u32(VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS)
+            .clamp(1, MAX_PORTS)
+    } else {
+        1
+    };
+
+    let mut ports = Vec::new();
+    for id in 0..port_count {
+        // the queues of port 0 come first, the ones of the other ports follow the control queues
+        let index = if id == 0 { 0 } else { 2 * (id + 1) } as u16;
+        ports.push(PortQueues {
+            receive: SpinLock::new(Receiver::new(&*transport, index, features)?),
+            transmit: SpinLock::new(Virtqueue::new(
+                &*transport,
+                index + 1,
+                QUEUE_SIZE,
+                features,
+            )?),
+        });
+    }
+    let control = if multiport {
+        Some(Control {
+            receive: SpinLock::new(ControlReceiver {
+                receiver: Receiver::new(&*transport, 2, features)?,
+                messages: VecDeque::new(),
+            }),
+            state: SpinLock::new(ControlState {
+                transmit: Virtqueue::new(&*transport, 3, QUEUE_SIZE, features)?,
+                ports: (0..port_count).map(|_| PortState::default()).collect(),
+            }),
+        })
+    } else {
+        None
+    };
+
+    super::driver_ok(&*transport);
+    // the receive buffers were added before the device was ready
+    for port in &ports {
+        port.receive.lock().queue.notify(&*transport);
+    }
+
+    let index = {
+        let mut devices = DEVICES.lock();
+        *devices += 1;
+        *devices - 1
+    };
+    println!("virtio-console: device {index} with {port_count} ports, features {features:#x}");
+    if features & VIRTIO_CONSOLE_F_SIZE != 0 {
+        let size = transport.read_config_u32(VIRTIO_CONSOLE_CONFIG_SIZE);
+        println!(
+            "virtio-console: console size {}x{}",
+            size & 0xffff,
+            size >> 16
+        );
+    }
+
+    let console = Arc::new(VirtioConsole {
+        index,
+        transport,
+        ports,
+        control,
+    });
+    match &console.control {
+        Some(control) => {
+            control
+                .receive
+                .lock()
+                .receiver
+                .queue
+                .notify(&*console.transport);
+            control
+                .state
+                .lock()
+                .send(&*console.transport, 0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        }
+        None => chardev::register(Arc::new(Port {
+            console: console.clone(),
+            id: 0,
+            name: console_name(),
+        })),
+    }
+    poll_control(&console);
+    CONSOLES.lock().push(console);
+    if index == 0 {
+        timer::periodic(POLL_MS, poll);
+        timer::deferred(POLL_MS, poll_controls);
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use super::Transport;
+use crate::driver::ProbeError;
+use crate::memory::PAGE_SIZE;
+
+const VIRTIO_MMIO_MAGIC: u32 = 0x74726976;
+const VIRTIO_REG_MAGIC: usize = 0x00;
+const VIRTIO_REG_VERSION: usize = 0x04;
+const VIRTIO_REG_DEVICE_ID: usize = 0x08;
+const VIRTIO_REG_DEVICE_FEATURES: usize = 0x10;
+const VIRTIO_REG_DEVICE_FEATURES_SEL: usize = 0x14;
+const VIRTIO_REG_DRIVER_FEATURES: usize = 0x20;
+const VIRTIO_REG_DRIVER_FEATURES_SEL: usize = 0x24;
+const VIRTIO_REG_GUEST_PAGE_SIZE: usize = 0x28;
+const VIRTIO_REG_QUEUE_SEL: usize = 0x30;
+const VIRTIO_REG_QUEUE_NUM_MAX: usize = 0x34;
+const VIRTIO_REG_QUEUE_NUM: usize = 0x38;
+const VIRTIO_REG_QUEUE_ALIGN: usize = 0x3c;
+const VIRTIO_REG_QUEUE_PFN: usize = 0x40;
+const VIRTIO_REG_QUEUE_READY: usize = 0x44;
+const VIRTIO_REG_QUEUE_NOTIFY: usize = 0x50;
+const VIRTIO_REG_INTERRUPT_STATUS: usize = 0x60;
+const VIRTIO_REG_INTERRUPT_ACK: usize = 0x64;
+const VIRTIO_REG_DEVICE_STATUS: usize = 0x70;
+const VIRTIO_REG_QUEUE_DESC_LOW: usize = 0x80;
+const VIRTIO_REG_QUEUE_DESC_HIGH: usize = 0x84;
+const VIRTIO_REG_QUEUE_DRIVER_LOW: usize = 0x90;
+const VIRTIO_REG_QUEUE_DRIVER_HIGH: usize = 0x94;
+const VIRTIO_REG_QUEUE_DEVICE_LOW: usize = 0xa0;
+const VIRTIO_REG_QUEUE_DEVICE_HIGH: usize = 0xa4;
+const VIRTIO_REG_CONFIG_GENERATION: usize = 0xfc;
+const VIRTIO_REG_DEVICE_CONFIG: usize = 0x100;
+/// Version of the legacy interface, where queues are given by page frame number
+const VIRTIO_MMIO_LEGACY: u32 = 1;
+
+/// virtio-mmio transport, in its legacy or its current version
+pub struct MmioTransport {
+    base: usize,
+    version: u32,
+}
+
+impl MmioTransport {
+    /// returns the transport of the registers at [base], [ProbeError::NoDevice] if the slot is
+    /// empty
+    pub fn new(base: usize) -> Result<Self, ProbeError> {
+        let transport = Self { base, version: 0 };
+        if transport.read(VIRTIO_REG_MAGIC) != VIRTIO_MMIO_MAGIC {
+            return Err(ProbeError::Unsupported("invalid magic value"));
+        }
+        let version = transport.read(VIRTIO_REG_VERSION);
+        if !(1..=2).contains(&version) {
+            return Err(ProbeError::Unsupported("invalid version"));
+        }
+        if transport.read(VIRTIO_REG_DEVICE_ID) == 0 {
+            return Err(ProbeError::NoDevice);
+        }
+        Ok(Self { base, version })
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let ptr = (self.base + offset) as *mut u32;
+        unsafe { ptr.read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        let ptr = (self.base + offset) as *mut u32;
+        unsafe {
+            ptr.write_volatile(value);
+        }
+    }
+
+    fn write_u64(&self, low: usize, high: usize, value: u64) {
+        self.write(low, value as u32);
+        self.write(high, (value >> 32) as u32);
+    }
+}
+
+impl Transport for MmioTransport {
+    fn device_id(&self) -> u32 {
+        self.read(VIRTIO_REG_DEVICE_ID)
+    }
+
+    fn device_features(&self) -> u64 {
+        self.write(VIRTIO_REG_DEVICE_FEATURES_SEL, 0);
+        let low = self.read(VIRTIO_REG_DEVICE_FEATURES) as u64;
+        self.write(VIRTIO_REG_DEVICE_FEATURES_SEL, 1);
+        let high = self.read(VIRTIO_REG_DEVICE_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write(VIRTIO_REG_DRIVER_FEATURES_SEL, 0);
+        self.write(VIRTIO_REG_DRIVER_FEATURES, features as u32);
+        self.write(VIRTIO_REG_DRIVER_FEATURES_SEL, 1);
+        self.write(VIRTIO_REG_DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    fn status(&self) -> u32 {
+        self.read(VIRTIO_REG_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u32) {
+        self.write(VIRTIO_REG_DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&self, index: u16) -> u16 {
+        self.write(VIRTIO_REG_QUEUE_SEL, index as u32);
+        self.read(VIRTIO_REG_QUEUE_NUM_MAX).min(u16::MAX as u32) as u16
+    }
+
+    fn used_ring_align(&self) -> usize {
+        if self.version == VIRTIO_MMIO_LEGACY {
+            PAGE_SIZE
+        } else {
+            4
+        }
+    }
+
+    fn setup_queue(&self, index: u16, size: u16, descs: usize, driver: usize, device: usize) {
+        self.write(VIRTIO_REG_QUEUE_SEL, index as u32);
+        self.write(VIRTIO_REG_QUEUE_NUM, size as u32);
+        if self.version == VIRTIO_MMIO_LEGACY {
+            // the device computes the address of the rings from the one of the descriptors
+            debug_assert!(driver == descs + 16 * size as usize);
+            debug_assert!(device.is_multiple_of(PAGE_SIZE));
+            self.write(VIRTIO_REG_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            self.write(VIRTIO_REG_QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(VIRTIO_REG_QUEUE_PFN, (descs / PAGE_SIZE) as u32);
+        } else {
+            self.write_u64(
+                VIRTIO_REG_QUEUE_DESC_LOW,
+                VIRTIO_REG_QUEUE_DESC_HIGH,
+                descs as u64,
+            );
+            self.write_u64(
+                VIRTIO_REG_QUEUE_DRIVER_LOW,
+                VIRTIO_REG_QUEUE_DRIVER_HIGH,
+                driver as u64,
+            );
+            self.write_u64(
+                VIRTIO_REG_QUEUE_DEVICE_LOW,
+                VIRTIO_REG_QUEUE_DEVICE_HIGH,
+                device as u64,
+            );
+            self.write(VIRTIO_REG_QUEUE_READY, 1);
+        }
+    }
+
+    fn notify(&self, index: u16) {
+        self.write(VIRTIO_REG_QUEUE_NOTIFY, index as u32);
+    }
+
+    fn ack_interrupt(&self) -> u32 {
+        let interrupts = self.read(VIRTIO_REG_INTERRUPT_STATUS);
+        self.write(VIRTIO_REG_INTERRUPT_ACK, interrupts);
+        interrupts
+    }
+
+    fn config_generation(&self) -> u32 {
+        if self.version == VIRTIO_MMIO_LEGACY {
+            0
+        } else {
+            self.read(VIRTIO_REG_CONFIG_GENERATION)
+        }
+    }
+
+    fn read_config_u32(&self, offset: usize) -> u32 {
+        self.read(VIRTIO_REG_DEVICE_CONFIG + offset)
+    }
+
+    fn write_config_u8(&self, offset: usize, value: u8) {
+        let ptr = (self.base + VIRTIO_REG_DEVICE_CONFIG + offset) as *mut u8;
+        unsafe {
+            ptr.write_volatile(value);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+pub mod blk;
+pub mod console;
+pub mod mmio;
+pub mod packed;
+pub mod queue;
+pub mod rng;
+
+use crate::driver::ProbeError;
+use alloc::boxed::Box;
+use fdt::node::FdtNode;
+use macros::driver;
+use mmio::MmioTransport;
+
+pub const VIRTIO_DEVICE_BLK: u32 = 2;
+pub const VIRTIO_DEVICE_CONSOLE: u32 = 3;
+pub const VIRTIO_DEVICE_RNG: u32 = 4;
+
+pub const VIRTIO_STATUS_ACK: u32 = 1;
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+pub const VIRTIO_STATUS_FAILED: u32 = 128;
+
+/// Features of the transport and the virtqueues, device types use the bits below 24
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
+crate::kernel_param!(
+    /// use packed virtqueues when devices offer them, split ones otherwise (`virtio.packed=`)
+    pub static PACKED: bool = "virtio.packed", true
+);
+
+/// Access to a virtio device, independent of the bus it is found on
+pub trait Transport: Send + Sync {
+    fn device_id(&self) -> u32;
+
+    /// returns the features offered by the device
+    fn device_features(&self) -> u64;
+
+    fn set_driver_features(&self, features: u64);
+
+    fn status(&self) -> u32;
+
+    fn set_status(&self, status: u32);
+
+    /// returns the largest size of the queue [index], zero if it does not exist
+    fn max_queue_size(&self, index: u16) -> u16;
+
+    /// returns the alignment of the used ring following the available ring of a split queue,
+    /// legacy devices only take the address of the descriptors and expect the rings after them
+    fn used_ring_align(&self) -> usize;
+
+    /// hands the queue [index] of [size] entries to the device, with the physical addresses of
+    /// its descriptor, driver and device areas, e.g. the available and used rings of a split queue
+    fn setup_queue(&self, index: u16, size: u16, descs: usize, driver: usize, device: usize);
+
+    /// tells the device new buffers are available on the queue [index]
+    fn notify(&self, index: u16);
+
+    /// acknowledges the pending interrupts, returning their causes
+    fn ack_interrupt(&self) -> u32;
+
+    /// returns the generation of the device configuration, which changes when it is modified
+    fn config_generation(&self) -> u32;
+
+    fn read_config_u32(&self, offset: usize) -> u32;
+
+    fn write_config_u8(&self, offset: usize, value: u8);
+
+    /// reads a 64-bit field, which the device may change between the accesses to both halves
+    fn read_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let low = self.read_config_u32(offset) as u64;
+            let high = self.read_config_u32(offset + 4) as u64;
+            if self.config_generation() == generation {
+                return (high << 32) | low;
+            }
+        }
+    }
+}
+
+/// resets the device behind [transport] and accepts the features of [supported] it offers,
+/// returning them
+///
+/// The driver sets up its queues afterwards and then calls [driver_ok].
+pub fn negotiate(transport: &dyn Transport, supported: u64) -> Result<u64, ProbeError> {
+    transport.set_status(0);
+    transport.set_status(VIRTIO_STATUS_ACK);
+    transport.set_status(VIRTIO_STATUS_ACK | VIRTIO_STATUS_DRIVER);
+
+    // only offered by non-legacy devices, which require it
+    let mut supported = supported | VIRTIO_F_VERSION_1;
+    if !PACKED.get() {
+        supported &= !VIRTIO_F_RING_PACKED;
+    }
+    let features = transport.device_features() & supported;
+    transport.set_driver_features(features);
+    transport.set_status(VIRTIO_STATUS_ACK | VIRTIO_STATUS_DRIVER | VIRTIO_STATUS_FEATURES_OK);
+    if transport.status() & VIRTIO_STATUS_FEATURES_OK == 0 {
+        transport.set_status(transport.status() | VIRTIO_STATUS_FAILED);
+        return Err(ProbeError::Unsupported("features not accepted"));
+    }
+    Ok(features)
+}
+
+/// tells the device its driver is ready
+pub fn driver_ok(transport: &dyn Transport) {
+    transport.set_status(transport.status() | VIRTIO_STATUS_DRIVER_OK);
+}
+
+#[driver(name = "virtio-mmio", level = Bus, compatible = ["virtio,mmio"])]
+fn probe(node: &FdtNode<'_, 'static>) -> Result<(), ProbeError> {
+    let base = node
+        .reg()
+        .and_then(|mut reg| reg.next())
+        .ok_or(ProbeError::Unsupported("missing reg"))?
+        .starting_address as usize;
+
+    let transport = MmioTransport::new(base)?;
+    match transport.device_id() {
+        VIRTIO_DEVICE_BLK => blk::probe(Box::new(transport)),
+        VIRTIO_DEVICE_CONSOLE => console::probe(Box::new(transport)),
+        VIRTIO_DEVICE_RNG => rng::probe(Box::new(transport)),
+        _ => Err(ProbeError::Unsupported("unsupported device type")),
+    }
+}
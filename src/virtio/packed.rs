@@ -0,0 +1,356 @@
+use super::queue::{Buffer, UnknownChain};
+use super::{Transport, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
+use crate::driver::ProbeError;
+use crate::memory::PAGE_SIZE;
+use alloc::alloc::{Layout, alloc_zeroed, dealloc};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{Ordering, fence};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+/// Notifications are only wanted at the descriptor given by the offset and wrap counter
+const RING_EVENT_FLAGS_DESC: u16 = 2;
+/// Bit of the wrap counter in the offset of an event suppression structure
+const RING_EVENT_WRAP: u16 = 15;
+/// Largest size of a packed queue, offsets of event suppression structures have 15 bits
+const MAX_SIZE: u16 = 1 << 15;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+/// Event suppression structure, the driver and the device each publish one
+#[repr(C)]
+struct Event {
+    /// descriptor offset and wrap counter to be notified at, with [RING_EVENT_FLAGS_DESC]
+    off_wrap: u16,
+    flags: u16,
+}
+
+/// Chain of descriptors handed to the device, identified by its buffer id
+struct Chain {
+    /// number of descriptors of the ring it takes
+    descs: u16,
+    indirect: Option<Box<[Descriptor]>>,
+}
+
+/// Packed virtqueue, with a single ring the driver and the device both write descriptors to
+///
+/// Descriptors are available or used depending on their flags and the wrap counters, which flip
+/// each time the ring wraps around. Unlike split queues, chains are identified by a buffer id
+/// instead of the index of their first descriptor.
+pub struct PackedQueue {
+    index: u16,
+    size: u16,
+    memory: *mut u8,
+    layout: Layout,
+    ring: *mut Descriptor,
+    /// event suppression structure of the driver, telling the device when to interrupt
+    driver_event: *mut Event,
+    /// event suppression structure of the device, telling the driver when to notify
+    device_event: *mut Event,
+    free_count: u16,
+    next_available: u16,
+    available_wrap: bool,
+    next_used: u16,
+    used_wrap: bool,
+    /// descriptors made available since the last notification
+    added: u16,
+    /// chains by buffer id, none if the id is free
+    chains: Vec<Option<Chain>>,
+    free_ids: Vec<u16>,
+    indirect_desc: bool,
+    event_idx: bool,
+    interrupts: bool,
+}
+
+// the ring is owned by the queue, the device accesses it through physical addresses only
+unsafe impl Send for PackedQueue {}
+
+impl PackedQueue {
+    /// sets up the queue [index] of the device with at most [max_size] entries, using the
+    /// indirect descriptors and event index of the negotiated [features]
+    ///
+    /// Interrupts are suppressed until [set_interrupts] enables them.
+    pub fn new(
+        transport: &dyn Transport,
+        index: u16,
+        max_size: u16,
+        features: u64,
+    ) -> Result<Self, ProbeError> {
+        // packed queues need not have a power of two size
+        let size = transport.max_queue_size(index).min(max_size).min(MAX_SIZE);
+        if size == 0 {
+            return Err(ProbeError::Unsupported("missing queue"));
+        }
+
+        let ring_size = size_of::<Descriptor>() * size as usize;
+        let layout =
+            Layout::from_size_align(ring_size + 2 * size_of::<Event>(), PAGE_SIZE).unwrap();
+        let memory = unsafe { alloc_zeroed(layout) };
+        if memory.is_null() {
+            return Err(ProbeError::Unsupported("out of memory"));
+        }
+
+        let mut queue = Self {
+            index,
+            size,
+            memory,
+            layout,
+            ring: memory as *mut Descriptor,
+            driver_event: unsafe { memory.add(ring_size) } as *mut Event,
+            device_event: unsafe { memory.add(ring_size + size_of::<Event>()) } as *mut Event,
+            free_count: size,
+            next_available: 0,
+            available_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            added: 0,
+            chains: (0..size).map(|_| None).collect(),
+            free_ids: (0..size).rev().collect(),
+            indirect_desc: features & VIRTIO_F_INDIRECT_DESC != 0,
+            event_idx: features & VIRTIO_F_EVENT_IDX != 0,
+            interrupts: true,
+        };
+        queue.set_interrupts(false);
+
+        transport.setup_queue(
+            index,
+            size,
+            memory as usize,
+            queue.driver_event as usize,
+            queue.device_event as usize,
+        );
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// returns whether a chain of [buffers] can be added now
+    pub fn can_add(&self, buffers: usize) -> bool {
+        let needed = if self.indirect_desc && buffers > 1 {
+            1
+        } else {
+            buffers
+        };
+        // chains, indirect or not, cannot be longer than the queue
+        buffers <= self.size as usize && needed <= self.free_count as usize
+    }
+
+    /// adds the chain of [buffers] to the ring, returning its buffer id or none if there are not
+    /// enough free descriptors
+    ///
+    /// The device only sees the chain once [notify] is called.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || !self.can_add(buffers.len()) {
+            return None;
+        }
+        let id = self.free_ids.pop()?;
+
+        let (descs, indirect) = if self.indirect_desc && buffers.len() > 1 {
+            // descriptors of an indirect table follow each other without the next flag
+            let table: Box<[Descriptor]> = buffers
+                .iter()
+                .map(|buffer| Descriptor {
+                    addr: buffer.addr as u64,
+                    len: buffer.len,
+                    id: 0,
+                    flags: if buffer.device_writes {
+                        VIRTQ_DESC_F_WRITE
+                    } else {
+                        0
+                    },
+                })
+                .collect();
+            let desc = Descriptor {
+                addr: table.as_ptr() as u64,
+                len: size_of_val(&*table) as u32,
+                id,
+                flags: VIRTQ_DESC_F_INDIRECT,
+            };
+            (Vec::from([desc]), Some(table))
+        } else {
+            let descs = buffers
+                .iter()
+                .enumerate()
+                .map(|(i, buffer)| {
+                    let mut flags = 0;
+                    if buffer.device_writes {
+                        flags |= VIRTQ_DESC_F_WRITE;
+                    }
+                    if i + 1 < buffers.len() {
+                        flags |= VIRTQ_DESC_F_NEXT;
+                    }
+                    Descriptor {
+                        addr: buffer.addr as u64,
+                        len: buffer.len,
+                        id,
+                        flags,
+                    }
+                })
+                .collect();
+            (descs, None)
+        };
+
+        // the first descriptor is made available last, so the device sees the whole chain at once
+        let head = self.next_available;
+        let head_flags = descs[0].flags | self.available_flags();
+        for (i, desc) in descs.iter().enumerate() {
+            let mut desc = *desc;
+            // the wrap counter may flip within the chain
+            desc.flags = if i == 0 {
+                0
+            } else {
+                desc.flags | self.available_flags()
+            };
+            unsafe {
+                self.ring
+                    .add(self.next_available as usize)
+                    .write_volatile(desc)
+            };
+            self.next_available += 1;
+            if self.next_available == self.size {
+                self.next_available = 0;
+                self.available_wrap = !self.available_wrap;
+            }
+        }
+        fence(Ordering::SeqCst);
+        unsafe {
+            (&raw mut (*self.ring.add(head as usize)).flags).write_volatile(head_flags);
+        }
+
+        self.free_count -= descs.len() as u16;
+        self.added += descs.len() as u16;
+        self.chains[id as usize] = Some(Chain {
+            descs: descs.len() as u16,
+            indirect,
+        });
+        Some(id)
+    }
+
+    /// returns the flags marking a descriptor available in the current lap of the ring
+    fn available_flags(&self) -> u16 {
+        if self.available_wrap {
+            VIRTQ_DESC_F_AVAIL
+        } else {
+            VIRTQ_DESC_F_USED
+        }
+    }
+
+    /// notifies the device of the chains added since the last notification, unless its event
+    /// suppression structure asks not to be
+    pub fn notify(&mut self, transport: &dyn Transport) {
+        fence(Ordering::SeqCst);
+        if self.added == 0 {
+            return;
+        }
+        let new = self.next_available;
+        let old = new.wrapping_sub(self.added);
+        self.added = 0;
+
+        let (off_wrap, flags) = unsafe {
+            (
+                (&raw const (*self.device_event).off_wrap).read_volatile(),
+                (&raw const (*self.device_event).flags).read_volatile(),
+            )
+        };
+        let needed = match flags {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.event_idx => {
+                // an offset of the previous lap is before the start of this one
+                let mut event = off_wrap & !(1 << RING_EVENT_WRAP);
+                if (off_wrap >> RING_EVENT_WRAP != 0) != self.available_wrap {
+                    event = event.wrapping_sub(self.size);
+                }
+                new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+            }
+            _ => true,
+        };
+        if needed {
+            transport.notify(self.index);
+        }
+    }
+
+    /// returns the buffer id of the next chain used by the device and the length it wrote,
+    /// freeing its descriptors
+    pub fn pop_used(&mut self) -> Result<Option<(u16, u32)>, UnknownChain> {
+        let desc = unsafe { self.ring.add(self.next_used as usize) };
+        let flags = unsafe { (&raw const (*desc).flags).read_volatile() };
+        // the device marks used descriptors with both flags equal to its wrap counter
+        let available = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        if available != used || used != self.used_wrap {
+            return Ok(None);
+        }
+        // the id and length are written before the flags
+        fence(Ordering::SeqCst);
+        let (id, len) = unsafe {
+            (
+                (&raw const (*desc).id).read_volatile(),
+                (&raw const (*desc).len).read_volatile(),
+            )
+        };
+
+        let Some(Chain { descs, indirect }) =
+            self.chains.get_mut(id as usize).and_then(Option::take)
+        else {
+            return Err(UnknownChain(id as u32));
+        };
+        // the device is done with the indirect table as well
+        drop(indirect);
+        self.free_ids.push(id);
+        self.free_count += descs;
+        self.next_used += descs;
+        if self.next_used >= self.size {
+            self.next_used -= self.size;
+            self.used_wrap = !self.used_wrap;
+        }
+        if self.interrupts && self.event_idx {
+            self.write_driver_event(RING_EVENT_FLAGS_DESC);
+        }
+        Ok(Some((id, len)))
+    }
+
+    /// enables or suppresses the interrupts of the device when it uses chains, drivers polling
+    /// [pop_used] need none
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        self.interrupts = enabled;
+        let flags = match enabled {
+            false => RING_EVENT_FLAGS_DISABLE,
+            true if self.event_idx => RING_EVENT_FLAGS_DESC,
+            true => RING_EVENT_FLAGS_ENABLE,
+        };
+        self.write_driver_event(flags);
+    }
+
+    /// publishes [flags], asking with [RING_EVENT_FLAGS_DESC] for an interrupt when the next
+    /// descriptor to be used is
+    fn write_driver_event(&mut self, flags: u16) {
+        let off_wrap = self.next_used | ((self.used_wrap as u16) << RING_EVENT_WRAP);
+        unsafe {
+            (&raw mut (*self.driver_event).off_wrap).write_volatile(off_wrap);
+            fence(Ordering::SeqCst);
+            (&raw mut (*self.driver_event).flags).write_volatile(flags);
+        }
+    }
+}
+
+impl Drop for PackedQueue {
+    /// the device must have been reset, it could still write to the queue otherwise
+    fn drop(&mut self) {
+        unsafe { dealloc(self.memory, self.layout) };
+    }
+}
@@ -0,0 +1,391 @@
+use super::packed::PackedQueue;
+use super::{Transport, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED};
+use crate::driver::ProbeError;
+use crate::memory::PAGE_SIZE;
+use alloc::alloc::{Layout, alloc_zeroed, dealloc};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{Ordering, fence};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+/// End of the free descriptor list
+const NO_DESCRIPTOR: u16 = u16::MAX;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Error of a device that used a chain with the id [0] it was not given, after which the
+/// queue cannot be used anymore
+#[derive(Copy, Clone, Debug)]
+pub struct UnknownChain(pub u32);
+
+/// Memory the device reads or writes as part of a request
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    /// physical address
+    pub addr: usize,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+impl Buffer {
+    fn descriptor(&self, next: Option<u16>) -> Descriptor {
+        let mut flags = 0;
+        if self.device_writes {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        if next.is_some() {
+            flags |= VIRTQ_DESC_F_NEXT;
+        }
+        Descriptor {
+            addr: self.addr as u64,
+            len: self.len,
+            flags,
+            next: next.unwrap_or(0),
+        }
+    }
+}
+
+/// Split virtqueue, with a descriptor table, an available ring and a used ring
+///
+/// The three areas are allocated together with the used ring aligned as the transport requires,
+/// so the layout also suits legacy devices. They are only accessed through pointers as the
+/// device writes to them, the bookkeeping of the driver is kept out of them.
+pub struct SplitQueue {
+    index: u16,
+    size: u16,
+    memory: *mut u8,
+    layout: Layout,
+    descs: *mut Descriptor,
+    /// flags, index, ring of [size] entries and `used_event`
+    available: *mut u16,
+    /// flags and index, followed by [size] entries of id and length and `avail_event`
+    used: *mut u16,
+    /// first free descriptor, the free ones are chained through [Descriptor::next]
+    free_head: u16,
+    free_count: u16,
+    available_index: u16,
+    /// [available_index] when the device was last notified
+    notified_index: u16,
+    last_used_index: u16,
+    /// indirect descriptor tables, indexed by the descriptor pointing to them
+    indirect: Vec<Option<Box<[Descriptor]>>>,
+    /// whether the chain at each head is available or used but not popped yet
+    in_flight: Vec<bool>,
+    indirect_desc: bool,
+    event_idx: bool,
+    interrupts: bool,
+}
+
+// the areas are owned by the queue, the device accesses them through physical addresses only
+unsafe impl Send for SplitQueue {}
+
+impl SplitQueue {
+    /// sets up the queue [index] of the device with at most [max_size] entries, using the
+    /// indirect descriptors and event index of the negotiated [features]
+    ///
+    /// Interrupts are suppressed until [set_interrupts] enables them.
+    pub fn new(
+        transport: &dyn Transport,
+        index: u16,
+        max_size: u16,
+        features: u64,
+    ) -> Result<Self, ProbeError> {
+        let size = transport.max_queue_size(index).min(max_size);
+        if size == 0 {
+            return Err(ProbeError::Unsupported("missing queue"));
+        }
+        // split queues have a power of two size
+        let size = 1 << size.ilog2();
+
+        let n = size as usize;
+        let available_offset = size_of::<Descriptor>() * n;
+        let used_offset =
+            (available_offset + 2 * (3 + n)).next_multiple_of(transport.used_ring_align());
+        let layout = Layout::from_size_align(
+            used_offset + 6 + 8 * n,
+            PAGE_SIZE.max(transport.used_ring_align()),
+        )
+        .unwrap();
+        let memory = unsafe { alloc_zeroed(layout) };
+        if memory.is_null() {
+            return Err(ProbeError::Unsupported("out of memory"));
+        }
+
+        let mut queue = Self {
+            index,
+            size,
+            memory,
+            layout,
+            descs: memory as *mut Descriptor,
+            available: unsafe { memory.add(available_offset) } as *mut u16,
+            used: unsafe { memory.add(used_offset) } as *mut u16,
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            notified_index: 0,
+            last_used_index: 0,
+            indirect: (0..n).map(|_| None).collect(),
+            in_flight: vec![false; n],
+            indirect_desc: features & VIRTIO_F_INDIRECT_DESC != 0,
+            event_idx: features & VIRTIO_F_EVENT_IDX != 0,
+            interrupts: true,
+        };
+        for i in 0..size {
+            queue.desc(i).next = if i + 1 < size { i + 1 } else { NO_DESCRIPTOR };
+        }
+        queue.set_interrupts(false);
+
+        transport.setup_queue(
+            index,
+            size,
+            memory as usize,
+            queue.available as usize,
+            queue.used as usize,
+        );
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn desc(&mut self, index: u16) -> &mut Descriptor {
+        unsafe { &mut *self.descs.add(index as usize) }
+    }
+
+    /// returns whether a chain of [buffers] can be added now
+    pub fn can_add(&self, buffers: usize) -> bool {
+        let needed = if self.indirect_desc && buffers > 1 {
+            1
+        } else {
+            buffers
+        };
+        // chains, indirect or not, cannot be longer than the queue
+        buffers <= self.size as usize && needed <= self.free_count as usize
+    }
+
+    /// adds the chain of [buffers] to the available ring, returning its head or none if there
+    /// are not enough free descriptors
+    ///
+    /// The device only sees the chain once [notify] is called.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || !self.can_add(buffers.len()) {
+            return None;
+        }
+
+        let head = self.free_head;
+        if self.indirect_desc && buffers.len() > 1 {
+            let table: Box<[Descriptor]> = buffers
+                .iter()
+                .enumerate()
+                .map(|(i, buffer)| {
+                    buffer.descriptor((i + 1 < buffers.len()).then_some(i as u16 + 1))
+                })
+                .collect();
+            let desc = self.desc(head);
+            desc.addr = table.as_ptr() as u64;
+            desc.len = size_of_val(&*table) as u32;
+            desc.flags = VIRTQ_DESC_F_INDIRECT;
+            self.free_head = desc.next;
+            self.free_count -= 1;
+            self.indirect[head as usize] = Some(table);
+        } else {
+            let mut index = head;
+            for (i, buffer) in buffers.iter().enumerate() {
+                let next = self.desc(index).next;
+                let last = i + 1 == buffers.len();
+                *self.desc(index) = buffer.descriptor((!last).then_some(next));
+                if last {
+                    // the free list continues after the chain
+                    self.free_head = next;
+                }
+                index = next;
+            }
+            self.free_count -= buffers.len() as u16;
+        }
+
+        self.in_flight[head as usize] = true;
+        let slot = self.available_index % self.size;
+        unsafe { self.available.add(2 + slot as usize).write_volatile(head) };
+        self.available_index = self.available_index.wrapping_add(1);
+        // the device must see the descriptors and the ring entry before the new index
+        fence(Ordering::SeqCst);
+        unsafe { self.available.add(1).write_volatile(self.available_index) };
+        Some(head)
+    }
+
+    /// notifies the device of the chains added since the last notification, unless it asked
+    /// not to be
+    pub fn notify(&mut self, transport: &dyn Transport) {
+        fence(Ordering::SeqCst);
+        let (old, new) = (self.notified_index, self.available_index);
+        if old == new {
+            return;
+        }
+        self.notified_index = new;
+
+        let needed = if self.event_idx {
+            // notify if `avail_event` was passed by the chains added since the last notification
+            let event = unsafe { self.avail_event().read_volatile() };
+            new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+        } else {
+            (unsafe { self.used.read_volatile() } & VIRTQ_USED_F_NO_NOTIFY) == 0
+        };
+        if needed {
+            transport.notify(self.index);
+        }
+    }
+
+    /// returns the head of the next chain used by the device and the length it wrote, freeing
+    /// its descriptors
+    pub fn pop_used(&mut self) -> Result<Option<(u16, u32)>, UnknownChain> {
+        if unsafe { self.used.add(1).read_volatile() } == self.last_used_index {
+            return Ok(None);
+        }
+        // the entry is written before the index
+        fence(Ordering::SeqCst);
+        let slot = (self.last_used_index % self.size) as usize;
+        let entry = unsafe { (self.used as *mut u8).add(4 + 8 * slot) } as *mut u32;
+        let (id, len) = unsafe { (entry.read_volatile(), entry.add(1).read_volatile()) };
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+        if self.interrupts && self.event_idx {
+            unsafe { self.used_event().write_volatile(self.last_used_index) };
+        }
+
+        let head = u16::try_from(id)
+            .ok()
+            .filter(|&head| head < self.size && self.in_flight[head as usize])
+            .ok_or(UnknownChain(id))?;
+        self.free_chain(head);
+        Ok(Some((head, len)))
+    }
+
+    /// returns the descriptors of the chain at [head] to the free list
+    fn free_chain(&mut self, head: u16) {
+        self.in_flight[head as usize] = false;
+        self.indirect[head as usize] = None;
+        let mut index = head;
+        loop {
+            self.free_count += 1;
+            let free_head = self.free_head;
+            let desc = self.desc(index);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                desc.next = free_head;
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+    }
+
+    /// enables or suppresses the interrupts of the device when it uses chains, drivers polling
+    /// [pop_used] need none
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        self.interrupts = enabled;
+        if self.event_idx {
+            // an interrupt is only sent when the used index passes `used_event`
+            let event = if enabled {
+                self.last_used_index
+            } else {
+                self.last_used_index.wrapping_sub(1)
+            };
+            unsafe { self.used_event().write_volatile(event) };
+        } else {
+            let flags = if enabled {
+                0
+            } else {
+                VIRTQ_AVAIL_F_NO_INTERRUPT
+            };
+            unsafe { self.available.write_volatile(flags) };
+        }
+    }
+
+    fn used_event(&self) -> *mut u16 {
+        unsafe { self.available.add(2 + self.size as usize) }
+    }
+
+    fn avail_event(&self) -> *mut u16 {
+        unsafe { (self.used as *mut u8).add(4 + 8 * self.size as usize) as *mut u16 }
+    }
+}
+
+impl Drop for SplitQueue {
+    /// the device must have been reset, it could still write to the queue otherwise
+    fn drop(&mut self) {
+        unsafe { dealloc(self.memory, self.layout) };
+    }
+}
+
+/// Virtqueue in the layout negotiated with the device
+pub enum Virtqueue {
+    Split(SplitQueue),
+    Packed(PackedQueue),
+}
+
+impl Virtqueue {
+    /// sets up the queue [index] of the device with at most [max_size] entries, packed if
+    /// [VIRTIO_F_RING_PACKED] was negotiated
+    pub fn new(
+        transport: &dyn Transport,
+        index: u16,
+        max_size: u16,
+        features: u64,
+    ) -> Result<Self, ProbeError> {
+        if features & VIRTIO_F_RING_PACKED != 0 {
+            PackedQueue::new(transport, index, max_size, features).map(Virtqueue::Packed)
+        } else {
+            SplitQueue::new(transport, index, max_size, features).map(Virtqueue::Split)
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        match self {
+            Virtqueue::Split(queue) => queue.size(),
+            Virtqueue::Packed(queue) => queue.size(),
+        }
+    }
+
+    /// returns whether a chain of [buffers] can be added now
+    pub fn can_add(&self, buffers: usize) -> bool {
+        match self {
+            Virtqueue::Split(queue) => queue.can_add(buffers),
+            Virtqueue::Packed(queue) => queue.can_add(buffers),
+        }
+    }
+
+    /// adds the chain of [buffers], returning an id below [size] identifying it until it is
+    /// used, or none if there are not enough free descriptors
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        match self {
+            Virtqueue::Split(queue) => queue.add(buffers),
+            Virtqueue::Packed(queue) => queue.add(buffers),
+        }
+    }
+
+    pub fn notify(&mut self, transport: &dyn Transport) {
+        match self {
+            Virtqueue::Split(queue) => queue.notify(transport),
+            Virtqueue::Packed(queue) => queue.notify(transport),
+        }
+    }
+
+    /// returns the id of the next chain used by the device and the length it wrote
+    pub fn pop_used(&mut self) -> Result<Option<(u16, u32)>, UnknownChain> {
+        match self {
+            Virtqueue::Split(queue) => queue.pop_used(),
+            Virtqueue::Packed(queue) => queue.pop_used(),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use super::queue::{Buffer, Virtqueue};
+use super::{Transport, VIRTIO_F_RING_PACKED};
+use crate::driver::ProbeError;
+use crate::{println, random};
+use alloc::boxed::Box;
+
+/// Entries of the request queue, a single request is in flight
+const QUEUE_SIZE: u16 = 1;
+/// Bytes requested from the device, a whole key of the generator
+const SEED_SIZE: usize = 32;
+
+/// seeds [random] from the entropy source behind [transport]
+///
+/// The device is only used once, the generator stretches the seed.
+pub fn probe(transport: Box<dyn Transport>) -> Result<(), ProbeError> {
+    let features = super::negotiate(&*transport, VIRTIO_F_RING_PACKED)?;
+    let mut queue = Virtqueue::new(&*transport, 0, QUEUE_SIZE, features)?;
+    super::driver_ok(&*transport);
+
+    let mut seed = [0; SEED_SIZE];
+    let mut done = 0;
+    // the device may return fewer bytes than requested
+    while done < seed.len() {
+        queue
+            .add(&[Buffer {
+                addr: seed[done..].as_mut_ptr() as usize,
+                len: (seed.len() - done) as u32,
+                device_writes: true,
+            }])
+            .expect("virtio-rng: request queue is full");
+        queue.notify(&*transport);
+        let len = loop {
+            match queue.pop_used() {
+                Ok(Some((_, 0))) => {
+                    return Err(ProbeError::Unsupported("device returned no entropy"));
+                }
+                Ok(Some((_, len))) => break len as usize,
+                Ok(None) => core::hint::spin_loop(),
+                Err(_) => return Err(ProbeError::Unsupported("device used an unknown buffer")),
+            }
+        };
+        done += len.min(seed.len() - done);
+    }
+
+    random::add_entropy(&seed);
+    println!("virtio-rng: seeded the generator with {SEED_SIZE} bytes");
+    Ok(())
+}